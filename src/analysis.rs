@@ -0,0 +1,260 @@
+use crate::quirks::{Platform, Quirks};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Instructions looked at after an Fx55/Fx65 when checking if the program relies on I moving
+const LOAD_STORE_WINDOW: usize = 6;
+
+/// Length in bytes of the instruction starting with `opcode`, XO-CHIP's F000 NNNN takes 4
+pub fn instruction_length(opcode: u16) -> u16 {
+    if opcode == 0xF000 {
+        4
+    } else {
+        2
+    }
+}
+
+/// Whether `opcode` conditionally skips the next instruction
+pub fn is_skip(opcode: u16) -> bool {
+    match opcode >> 12 {
+        0x3 | 0x4 => true,
+        0x5 | 0x9 => opcode & 0xF == 0,
+        0xE => matches!(opcode & 0xFF, 0x9E | 0xA1),
+        _ => false,
+    }
+}
+
+pub fn read_opcode(memory: &[u8], address: usize) -> Option<u16> {
+    Some(((*memory.get(address)? as u16) << 8) | *memory.get(address + 1)? as u16)
+}
+
+/// Addresses of the jumps in the table that `Bnnn` with table address `nnn` jumps into, the
+/// consecutive `1nnn` instructions found there
+pub fn jump_table(memory: &[u8], table: u16, end: usize) -> Vec<u16> {
+    let mut entries = Vec::new();
+    let mut address = table as usize;
+    while address + 1 < end {
+        match read_opcode(memory, address) {
+            Some(opcode) if opcode >> 12 == 0x1 => entries.push(address as u16),
+            _ => break,
+        }
+        address += 2;
+    }
+    entries
+}
+
+/// Instructions reachable from `starts` by following jumps, calls, skips and jump tables,
+/// keyed by address
+pub fn reachable_code(memory: &[u8], starts: &[u16], end: usize) -> BTreeMap<u16, u16> {
+    let mut code = BTreeMap::new();
+    let mut pending = starts.to_vec();
+    while let Some(address) = pending.pop() {
+        if code.contains_key(&address) || address as usize + 1 >= end {
+            continue;
+        }
+        let opcode = match read_opcode(memory, address as usize) {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        code.insert(address, opcode);
+        // Nothing follows an instruction at the top of memory
+        let next = address.checked_add(instruction_length(opcode));
+        let nnn = opcode & 0x0FFF;
+        match opcode >> 12 {
+            0x0 if matches!(opcode, 0x00EE | 0x00FD) => {}
+            0x1 => pending.push(nnn),
+            0x2 => {
+                pending.push(nnn);
+                pending.extend(next);
+            }
+            0xB => {
+                pending.push(nnn);
+                pending.extend(jump_table(memory, nnn, end));
+            }
+            _ if is_skip(opcode) => {
+                if let Some(next) = next {
+                    pending.push(next);
+                    if let Some(skipped) = read_opcode(memory, next as usize) {
+                        pending.extend(next.checked_add(instruction_length(skipped)));
+                    }
+                }
+            }
+            _ => pending.extend(next),
+        }
+    }
+    code
+}
+
+fn schip_only(opcode: u16) -> bool {
+    match opcode >> 12 {
+        0x0 => matches!(opcode & 0xFFF0, 0x00C0) || (0x00FB..=0x00FF).contains(&opcode),
+        0xD => opcode & 0xF == 0,
+        0xF => matches!(opcode & 0xFF, 0x30 | 0x75 | 0x85),
+        _ => false,
+    }
+}
+
+fn xochip_only(opcode: u16) -> bool {
+    match opcode >> 12 {
+        0x0 => opcode & 0xFFF0 == 0x00D0,
+        0x5 => matches!(opcode & 0xF, 0x2 | 0x3),
+        0xF => opcode == 0xF000 || opcode == 0xF002 || matches!(opcode & 0xFF, 0x01 | 0x3A),
+        _ => false,
+    }
+}
+
+/// Platform and quirks guessed from the instructions a ROM uses
+#[derive(Debug, Clone, PartialEq)]
+pub struct Guess {
+    pub platform: Platform,
+    pub quirks: Quirks,
+    // 0 to 1, how sure the guess is
+    pub confidence: f32,
+    pub reasons: Vec<String>,
+}
+
+/// Looks at the reachable code of the program in `memory[start..end]` and proposes a quirk
+/// profile for it
+pub fn guess_profile(memory: &[u8], start: u16, end: usize) -> Guess {
+    let code = reachable_code(memory, &[start], end);
+    let mut reasons = Vec::new();
+    let opcodes: BTreeSet<u16> = code.values().copied().collect();
+
+    let xochip: Vec<u16> = opcodes
+        .iter()
+        .copied()
+        .filter(|o| xochip_only(*o))
+        .collect();
+    let schip: Vec<u16> = opcodes.iter().copied().filter(|o| schip_only(*o)).collect();
+    let list = |opcodes: &[u16]| {
+        opcodes
+            .iter()
+            .take(4)
+            .map(|o| format!("{:04X}", o))
+            .collect::<Vec<String>>()
+            .join(", ")
+    };
+    let (platform, mut confidence): (Platform, f32) = if !xochip.is_empty() {
+        reasons.push(format!("uses XO-CHIP instructions ({})", list(&xochip)));
+        (Platform::XoChip, if xochip.len() > 1 { 0.9 } else { 0.7 })
+    } else if !schip.is_empty() {
+        reasons.push(format!("uses SUPER-CHIP instructions ({})", list(&schip)));
+        (Platform::SuperChip, if schip.len() > 1 { 0.9 } else { 0.7 })
+    } else {
+        reasons.push("only uses CHIP-8 instructions".to_string());
+        (Platform::Chip8, 0.5)
+    };
+    let mut quirks = platform.quirks();
+
+    // Shifting only matters when the source and destination registers differ
+    let shifts: Vec<u16> = opcodes
+        .iter()
+        .copied()
+        .filter(|o| o >> 12 == 0x8 && matches!(o & 0xF, 0x6 | 0xE))
+        .collect();
+    if shifts.iter().any(|o| (o >> 8) & 0xF != (o >> 4) & 0xF) {
+        reasons.push(format!(
+            "shifts with x != y ({}), shifting quirk matters",
+            list(&shifts)
+        ));
+        confidence -= 0.1;
+    } else if !shifts.is_empty() {
+        reasons.push("all shifts have x == y, shifting quirk does not matter".to_string());
+        confidence += 0.05;
+    }
+
+    // Two loads/stores in a row without setting I in between only work if I moves
+    let instructions: Vec<u16> = code.values().copied().collect();
+    let load_store = |o: u16| o >> 12 == 0xF && matches!(o & 0xFF, 0x55 | 0x65);
+    let sets_i = |o: u16| o >> 12 == 0xA || (o >> 12 == 0xF && o & 0xFF == 0x1E);
+    let relies_on_increment = instructions.iter().enumerate().any(|(index, o)| {
+        load_store(*o)
+            && instructions[index + 1..]
+                .iter()
+                .take(LOAD_STORE_WINDOW)
+                .take_while(|next| !sets_i(**next))
+                .any(|next| load_store(*next))
+    });
+    if relies_on_increment {
+        reasons.push("consecutive Fx55/Fx65 rely on I being incremented".to_string());
+        if !quirks.memory {
+            quirks.memory = true;
+            confidence -= 0.1;
+        }
+    }
+
+    let jumps: Vec<u16> = opcodes.iter().copied().filter(|o| o >> 12 == 0xB).collect();
+    if !jumps.is_empty() {
+        reasons.push(format!(
+            "uses Bnnn ({}), jumping quirk matters",
+            list(&jumps)
+        ));
+        confidence -= 0.1;
+    }
+
+    Guess {
+        platform,
+        quirks,
+        confidence: confidence.clamp(0.0, 1.0),
+        reasons,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Memory with the opcodes at 0x200, shared with the other analysis tests
+    pub fn program(opcodes: &[u16]) -> Vec<u8> {
+        let mut memory = vec![0; 0x200];
+        for opcode in opcodes {
+            memory.extend_from_slice(&opcode.to_be_bytes());
+        }
+        memory
+    }
+
+    #[test]
+    fn test_reachable_code() {
+        // 200: skip, 202: jump to 208, 204: F000 NNNN, 208: call 20C, 20A: loop, 20C: ret
+        let memory = program(&[
+            0x3000, 0x1208, 0xF000, 0x00FF, 0x220C, 0x120A, 0x00EE, 0x00FF,
+        ]);
+        let code = reachable_code(&memory, &[0x200], memory.len());
+        let addresses: Vec<u16> = code.keys().copied().collect();
+        assert_eq!(addresses, vec![0x200, 0x202, 0x204, 0x208, 0x20A, 0x20C]);
+
+        // 200: jump0 206, 202: data, 204: return, 206: table of jumps to 204 and 200
+        let memory = program(&[0xB206, 0xFFFF, 0x00EE, 0x1204, 0x1200, 0xFFFF]);
+        assert_eq!(jump_table(&memory, 0x206, memory.len()), vec![0x206, 0x208]);
+        let code = reachable_code(&memory, &[0x200], memory.len());
+        let addresses: Vec<u16> = code.keys().copied().collect();
+        assert_eq!(addresses, vec![0x200, 0x204, 0x206, 0x208]);
+    }
+
+    #[test]
+    fn test_guess_profile() {
+        let chip8 = program(&[0x6001, 0x8016, 0xA300, 0xF165, 0xF155, 0x120A]);
+        let guess = guess_profile(&chip8, 0x200, chip8.len());
+        assert_eq!(guess.platform, Platform::Chip8);
+        assert!(guess.quirks.memory);
+        assert_eq!(guess.reasons.len(), 3);
+
+        // 00FF is data here, it is never reached
+        let data = program(&[0x1204, 0x00FF, 0x1204]);
+        assert_eq!(
+            guess_profile(&data, 0x200, data.len()).platform,
+            Platform::Chip8
+        );
+
+        let schip = program(&[0x00FF, 0xD120, 0xF165, 0xF155, 0x1208]);
+        let guess = guess_profile(&schip, 0x200, schip.len());
+        assert_eq!(guess.platform, Platform::SuperChip);
+        assert!(guess.quirks.memory);
+        assert!(guess.quirks.shifting);
+
+        let xochip = program(&[0xF000, 0x0300, 0x5122, 0x1206]);
+        assert_eq!(
+            guess_profile(&xochip, 0x200, xochip.len()).platform,
+            Platform::XoChip
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use crate::display::Palette;
+use crate::fonts::FontSet;
+use crate::octo::{self, Program};
+use crate::quirks::Platform;
+use crate::romdb::RomInfo;
+use serde_json::Value;
+use std::fs::File;
+
+/// Contents of an Octo cartridge: the Octo source and the settings it was saved with
+pub struct Cartridge {
+    pub info: RomInfo,
+    pub source: String,
+}
+
+impl Cartridge {
+    /// Compiles the embedded source, the symbol map shows lines of that source
+    pub fn program(&self) -> Result<Program, String> {
+        let mut program = octo::compile(&self.source).map_err(|e| e.to_string())?;
+        program.symbols.set_text(&self.source);
+        Ok(program)
+    }
+}
+
+/// Extracts the payload hidden in the pixels of a cartridge. Each pixel's colour index carries
+/// 2 bits in its lowest bits, most significant first, 4 pixels per byte. The payload is a 32 bit
+/// big endian length followed by that many bytes of UTF-8 JSON.
+pub fn decode_payload(pixels: &[u8]) -> Result<String, String> {
+    let bytes: Vec<u8> = pixels
+        .chunks_exact(4)
+        .map(|p| ((p[0] & 3) << 6) | ((p[1] & 3) << 4) | ((p[2] & 3) << 2) | (p[3] & 3))
+        .collect();
+    if bytes.len() < 4 {
+        return Err("Cartridge image is too small".to_string());
+    }
+    let size = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    let payload = bytes
+        .get(4..4 + size)
+        .ok_or("Cartridge payload is truncated, this is probably not an Octo cartridge")?;
+    String::from_utf8(payload.to_vec()).map_err(|_| "Cartridge payload is not text".to_string())
+}
+
+// Octo names the platforms by the largest program they can hold, 3232 bytes for CHIP-8 and 3583
+// for SCHIP. Anything bigger is XO-CHIP, which includes Octo's default of 3584.
+fn platform_for_size(max_size: u64) -> Platform {
+    match max_size {
+        0..=3232 => Platform::Chip8,
+        3233..=3583 => Platform::SuperChip,
+        _ => Platform::XoChip,
+    }
+}
+
+/// Reads the `{"options": {...}, "program": "..."}` payload written by Octo
+pub fn parse_payload(json: &str) -> Result<Cartridge, String> {
+    let json: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let source = json["program"]
+        .as_str()
+        .ok_or("Cartridge has no program")?
+        .to_string();
+    let options = &json["options"];
+    let platform = match options["maxSize"].as_u64() {
+        Some(max_size) => platform_for_size(max_size),
+        None => Platform::Chip8,
+    };
+    let mut info = RomInfo::unknown(platform);
+    info.title = "Octo cartridge".to_string();
+    let quirk = |name: &str| options[name].as_bool();
+    if let Some(on) = quirk("logicQuirks") {
+        info.quirks.vf_reset = on;
+    }
+    // Octo's load/store quirk is the one that leaves I unchanged
+    if let Some(on) = quirk("loadStoreQuirks") {
+        info.quirks.memory = !on;
+    }
+    if let Some(on) = quirk("shiftQuirks") {
+        info.quirks.shifting = on;
+    }
+    if let Some(on) = quirk("jumpQuirks") {
+        info.quirks.jumping = on;
+    }
+    if let Some(on) = quirk("clipQuirks") {
+        info.quirks.clipping = on;
+    }
+    if let Some(on) = quirk("vBlankQuirks") {
+        info.quirks.display_wait = on;
+    }
+    if let Some(tickrate) = options["tickrate"].as_u64() {
+        info.ipf = tickrate.clamp(1, 100_000) as u32;
+    }
+    if let Some(font) = options["fontStyle"].as_str().and_then(FontSet::parse) {
+        info.font = font;
+    }
+    let color = |name: &str| options[name].as_str().and_then(Palette::parse_color);
+    if let (Some(background), Some(foreground)) = (color("backgroundColor"), color("fillColor")) {
+        info.palette = Palette {
+            background,
+            foreground,
+        };
+    }
+    Ok(Cartridge { info, source })
+}
+
+pub fn load(path: &str) -> Result<Cartridge, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(file).map_err(|e| e.to_string())?;
+    let mut pixels = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+        pixels.extend_from_slice(&frame.buffer);
+    }
+    parse_payload(&decode_payload(&pixels)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    // Spreads `payload` over colour indices the way Octo does, on top of a label colour
+    fn encode(payload: &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        bytes
+            .iter()
+            .flat_map(|b| [b >> 6, b >> 4, b >> 2, *b].map(|bits| 0x10 | (bits & 3)))
+            .collect()
+    }
+
+    #[test]
+    fn test_payload() {
+        let payload = r##"{"options": {"tickrate": 20, "shiftQuirks": true,
+            "loadStoreQuirks": true, "maxSize": 3583, "backgroundColor": "#996600",
+            "fillColor": "#FFCC00", "fontStyle": "vip"}, "program": ": main\n  loop again"}"##;
+        let cartridge = parse_payload(&decode_payload(&encode(payload)).unwrap()).unwrap();
+        assert_eq!(cartridge.source, ": main\n  loop again");
+        assert_eq!(cartridge.info.platform, Platform::SuperChip);
+        assert_eq!(cartridge.info.ipf, 20);
+        assert!(cartridge.info.quirks.shifting);
+        assert!(!cartridge.info.quirks.memory);
+        assert_eq!(cartridge.info.palette.foreground, (0xFF, 0xCC, 0x00));
+        assert_eq!(cartridge.info.font, FontSet::Vip);
+
+        assert!(decode_payload(&[0xFF; 64]).is_err());
+        assert!(parse_payload(r#"{"options": {}}"#).is_err());
+    }
+
+    #[test]
+    fn test_load_gif() {
+        let pixels = encode(r#"{"options": {"tickrate": 30}, "program": ": main v0 := 5"}"#);
+        let path = std::env::temp_dir().join("chip8_cartridge_test.gif");
+        {
+            let palette: Vec<u8> = (0..=255).flat_map(|c| [c, c, c]).collect();
+            let file = File::create(&path).unwrap();
+            let mut encoder = gif::Encoder::new(file, 16, 16, &palette).unwrap();
+            // Split over two frames, padded with zero bits
+            let mut frame = vec![0x10; 512];
+            frame[..pixels.len()].copy_from_slice(&pixels);
+            for half in frame.chunks(256) {
+                let frame = gif::Frame::from_indexed_pixels(16, 16, half.to_vec(), None);
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        let cartridge = load(path.to_str().unwrap()).unwrap();
+        assert_eq!(cartridge.source, ": main v0 := 5");
+        assert_eq!(cartridge.info.platform, Platform::Chip8);
+
+        let mut cpu = CPU::default();
+        let (info, symbols) = cpu.load_rom(path.to_str().unwrap()).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(info.ipf, 30);
+        assert_eq!(cpu.memory().cart[0x200..0x202], [0x60, 0x05]);
+        assert_eq!(symbols.source.as_deref(), path.to_str());
+    }
+}
@@ -0,0 +1,386 @@
+/// What the 1802 is wired to: memory, the N0-N2 I/O lines and the EF1-EF4 flag inputs
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    /// OUT 1-7, `value` is the byte the CPU puts on the data bus
+    fn output(&mut self, port: u8, value: u8);
+    /// INP 1-7, returns the byte the device puts on the data bus
+    fn input(&mut self, port: u8) -> u8;
+    /// Whether the EF1-EF4 line `flag` is asserted
+    fn flag(&mut self, flag: u8) -> bool;
+}
+
+/// The RCA CDP1802 "COSMAC" CPU. Timing is counted in machine cycles of 8 clock pulses.
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    // Register used as program counter
+    pub p: u8,
+    // Register used as data pointer
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    // X and P saved by interrupts and MARK
+    pub t: u8,
+    // Interrupt enable
+    pub ie: bool,
+    pub q: bool,
+    // Stopped by IDL until an interrupt or DMA
+    pub idle: bool,
+}
+
+impl Cdp1802 {
+    /// State after a reset: P, X and R0 are 0, interrupts are enabled
+    pub fn default() -> Self {
+        Cdp1802 {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    fn fetch(&mut self, bus: &mut impl Bus) -> u8 {
+        let pc = self.p as usize;
+        let value = bus.read(self.r[pc]);
+        self.r[pc] = self.r[pc].wrapping_add(1);
+        value
+    }
+
+    fn rx(&self) -> u16 {
+        self.r[self.x as usize]
+    }
+
+    fn inc_x(&mut self) {
+        let x = self.x as usize;
+        self.r[x] = self.r[x].wrapping_add(1);
+    }
+
+    fn add(&mut self, a: u8, b: u8, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // a - b, DF is set when there was no borrow
+    fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+    // Condition tested by short branches 30-37, 4-7 are the EF1-EF4 lines
+    fn condition(&self, bus: &mut impl Bus, n: u8) -> bool {
+        match n {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            _ => bus.flag(n - 3),
+        }
+    }
+
+    /// Accepts an interrupt if they are enabled, returns the machine cycles it took
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = (self.x << 4) | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    /// A DMA output cycle: the byte at R0 goes to the device and R0 moves on
+    pub fn dma_out(&mut self, bus: &mut impl Bus) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    /// Executes one instruction, returns the machine cycles it took
+    pub fn step(&mut self, bus: &mut impl Bus) -> u32 {
+        if self.idle {
+            return 1;
+        }
+        let opcode = self.fetch(bus);
+        let n = opcode & 0xF;
+        let rn = n as usize;
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[rn]),
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            0x3 => {
+                // Short branch within the current page, 38-3F on the inverse condition
+                let taken = self.condition(bus, n & 0x7) != (n & 0x8 != 0);
+                let pc = self.p as usize;
+                if taken {
+                    let low = bus.read(self.r[pc]);
+                    self.r[pc] = (self.r[pc] & 0xFF00) | low as u16;
+                } else {
+                    self.r[pc] = self.r[pc].wrapping_add(1);
+                }
+            }
+            0x4 => {
+                self.d = bus.read(self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            }
+            0x5 => bus.write(self.r[rn], self.d),
+            0x6 if n == 0 => self.inc_x(),
+            0x6 if n < 8 => {
+                let value = bus.read(self.rx());
+                bus.output(n, value);
+                self.inc_x();
+            }
+            // 68 is an extended opcode on the 1804 and later, nothing on the 1802
+            0x6 if n == 8 => {}
+            0x6 => {
+                self.d = bus.input(n - 8);
+                bus.write(self.rx(), self.d);
+            }
+            0x7 => self.execute_7(bus, n),
+            0x8 => self.d = self.r[rn] as u8,
+            0x9 => self.d = (self.r[rn] >> 8) as u8,
+            0xA => self.r[rn] = (self.r[rn] & 0xFF00) | self.d as u16,
+            0xB => self.r[rn] = (self.r[rn] & 0x00FF) | ((self.d as u16) << 8),
+            0xC => {
+                self.long_branch(bus, n);
+                return 3;
+            }
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => self.execute_f(bus, n),
+        }
+        2
+    }
+
+    fn execute_7(&mut self, bus: &mut impl Bus, n: u8) {
+        match n {
+            0x0 | 0x1 => {
+                let value = bus.read(self.rx());
+                self.inc_x();
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = n == 0x0;
+            }
+            0x2 => {
+                self.d = bus.read(self.rx());
+                self.inc_x();
+            }
+            0x3 => {
+                bus.write(self.rx(), self.d);
+                let x = self.x as usize;
+                self.r[x] = self.r[x].wrapping_sub(1);
+            }
+            0x4 => {
+                let value = bus.read(self.rx());
+                self.add(value, self.d, self.df);
+            }
+            0x5 => {
+                let value = bus.read(self.rx());
+                self.subtract(value, self.d, !self.df);
+            }
+            0x6 => {
+                let carry = self.df;
+                self.df = self.d & 1 != 0;
+                self.d = (self.d >> 1) | ((carry as u8) << 7);
+            }
+            0x7 => {
+                let value = bus.read(self.rx());
+                self.subtract(self.d, value, !self.df);
+            }
+            0x8 => bus.write(self.rx(), self.t),
+            0x9 => {
+                self.t = (self.x << 4) | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            }
+            0xA => self.q = false,
+            0xB => self.q = true,
+            0xC => {
+                let value = self.fetch(bus);
+                self.add(value, self.d, self.df);
+            }
+            0xD => {
+                let value = self.fetch(bus);
+                self.subtract(value, self.d, !self.df);
+            }
+            0xE => {
+                let carry = self.df;
+                self.df = self.d & 0x80 != 0;
+                self.d = (self.d << 1) | carry as u8;
+            }
+            _ => {
+                let value = self.fetch(bus);
+                self.subtract(self.d, value, !self.df);
+            }
+        }
+    }
+
+    fn execute_f(&mut self, bus: &mut impl Bus, n: u8) {
+        // F0-F7 take their operand from M(R(X)), F8-FF from the next byte
+        let value = match n {
+            0x6 | 0xE => 0,
+            0x0..=0x7 => bus.read(self.rx()),
+            _ => self.fetch(bus),
+        };
+        match n & 0x7 {
+            0x0 => self.d = value,
+            0x1 => self.d |= value,
+            0x2 => self.d &= value,
+            0x3 => self.d ^= value,
+            0x4 => self.add(value, self.d, false),
+            0x5 => self.subtract(value, self.d, false),
+            0x6 if n == 0x6 => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            }
+            0x6 => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            }
+            _ => self.subtract(self.d, value, false),
+        }
+    }
+
+    fn long_branch(&mut self, bus: &mut impl Bus, n: u8) {
+        let pc = self.p as usize;
+        let condition = match n & 0x3 {
+            // LSIE (CC) tests interrupt enable, for the others it is "always"
+            0 => n != 0xC || self.ie,
+            1 => self.q,
+            2 => self.d == 0,
+            _ => self.df,
+        };
+        // C4-C7 and CC-CF skip the next two bytes, C4-C7 and C8-CB on the inverse
+        // condition, which makes C4 NOP and C8 an unconditional skip
+        let taken = condition != ((n & 0x4 != 0) != (n & 0x8 != 0));
+        let skip = n & 0x4 != 0;
+        if taken && !skip {
+            let high = bus.read(self.r[pc]);
+            let low = bus.read(self.r[pc].wrapping_add(1));
+            self.r[pc] = ((high as u16) << 8) | low as u16;
+        } else if taken || !skip {
+            self.r[pc] = self.r[pc].wrapping_add(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestBus {
+        memory: Vec<u8>,
+        outputs: Vec<(u8, u8)>,
+        ef: [bool; 4],
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.push((port, value));
+        }
+        fn input(&mut self, port: u8) -> u8 {
+            0x40 | port
+        }
+        fn flag(&mut self, flag: u8) -> bool {
+            self.ef[flag as usize - 1]
+        }
+    }
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, TestBus) {
+        let mut bus = TestBus {
+            memory: vec![0; 0x10000],
+            outputs: Vec::new(),
+            ef: [false, false, true, false],
+        };
+        bus.memory[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::default();
+        for _ in 0..steps {
+            cpu.step(&mut bus);
+        }
+        (cpu, bus)
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        // R3 = 0x0100, SEP 3, then at 0x100: D = 0xF0 + 0x20, R4.0 = D, D - 0x11 with borrow,
+        // shift left with carry
+        let mut program = vec![0xF8, 0x01, 0xB3, 0xF8, 0x00, 0xA3, 0xD3];
+        program.resize(0x100, 0);
+        program.extend([0xF8, 0xF0, 0xFC, 0x20, 0xA4, 0x7F, 0x11, 0x7E]);
+        let (cpu, _) = run(&program, 10);
+        assert_eq!(cpu.p, 3);
+        assert_eq!(cpu.r[4] & 0xFF, 0x10);
+        // Shifted left, with the cleared DF coming in and the top bit going out
+        assert_eq!(cpu.d, 0xFE);
+        assert!(cpu.df);
+        // The addition carried, so no borrow: 0x10 - 0x11 = 0xFF, which borrows
+        let (cpu, _) = run(&program, 9);
+        assert_eq!(cpu.d, 0xFF);
+        assert!(!cpu.df);
+    }
+
+    #[test]
+    fn test_branches() {
+        // BZ not taken, B3 taken to 0x08, LBNQ taken to 0x0010, LSKP over LDI 1, LDI 2
+        let mut program = vec![0xF8, 0x05, 0x32, 0x20, 0x36, 0x08, 0x00, 0x00];
+        program.extend([0xC9, 0x00, 0x10]);
+        program.resize(0x10, 0);
+        program.extend([0xC8, 0xF8, 0x01, 0xF8, 0x02]);
+        let (cpu, _) = run(&program, 6);
+        assert_eq!(cpu.d, 0x02);
+        assert_eq!(cpu.r[0], 0x15);
+        // The skip takes three machine cycles, a short branch two
+        let (mut cpu, mut bus) = run(&program, 0);
+        assert_eq!(cpu.step(&mut bus), 2);
+        cpu.r[0] = 0x10;
+        assert_eq!(cpu.step(&mut bus), 3);
+    }
+
+    #[test]
+    fn test_io_and_interrupts() {
+        // SEX 2, R2 = 0x80, STXD of 0x23, MARK, INC R2, OUT 4, INP 3
+        let program = [
+            0xE2, 0xF8, 0x80, 0xA2, 0xF8, 0x23, 0x73, 0x79, 0x12, 0x64, 0x6B,
+        ];
+        let (mut cpu, mut bus) = run(&program, 7);
+        assert_eq!(bus.memory[0x80], 0x23);
+        // MARK saved X=2 P=0 at 0x7F and set X to P, INC R2 points it back
+        assert_eq!(bus.memory[0x7F], 0x20);
+        assert_eq!(cpu.x, 0);
+
+        cpu.x = 2;
+        cpu.r[2] = 0x80;
+        cpu.step(&mut bus);
+        assert_eq!(bus.outputs, [(4, 0x23)]);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.d, 0x43);
+        assert_eq!(bus.memory[0x81], 0x43);
+
+        assert_eq!(cpu.interrupt(), 1);
+        assert_eq!((cpu.p, cpu.x, cpu.t, cpu.ie), (1, 2, 0x20, false));
+        assert_eq!(cpu.interrupt(), 0);
+        // RET restores X and P from memory and enables interrupts again
+        bus.memory[0x81] = 0x35;
+        bus.memory[0] = 0x70;
+        cpu.r[1] = 0;
+        cpu.step(&mut bus);
+        assert_eq!((cpu.x, cpu.p, cpu.ie, cpu.r[2]), (3, 5, true, 0x82));
+    }
+}
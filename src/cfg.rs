@@ -0,0 +1,382 @@
+use crate::analysis::{instruction_length, is_skip, jump_table, reachable_code, read_opcode};
+use crate::cpu::Decoded;
+use crate::disasm::instruction_source;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    // Taken when the skip condition holds
+    Skip,
+    Call,
+    // From a `Bnnn` to the table and the jumps in it
+    JumpTable,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Skip => "skip",
+            EdgeKind::Call => "call",
+            EdgeKind::JumpTable => "jump-table",
+        }
+    }
+
+    fn dot_style(&self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "",
+            EdgeKind::Jump => " [color=blue]",
+            EdgeKind::Skip => " [style=dashed, label=\"skip\"]",
+            EdgeKind::Call => " [style=dotted, label=\"call\"]",
+            EdgeKind::JumpTable => " [style=bold, label=\"table\"]",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Edge {
+    // Start addresses of the blocks
+    pub from: u16,
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+/// Instructions that always run one after the other, as (address, opcode)
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub instructions: Vec<(u16, u16)>,
+}
+
+impl Block {
+    pub fn start(&self) -> u16 {
+        self.instructions[0].0
+    }
+
+    // Past the last instruction, which is 0x10000 for a block at the top of memory
+    pub fn end(&self) -> usize {
+        let (address, opcode) = self.instructions[self.instructions.len() - 1];
+        address as usize + instruction_length(opcode) as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Subroutine {
+    pub entry: u16,
+    // Start addresses of the blocks reachable from the entry without following calls
+    pub blocks: BTreeSet<u16>,
+}
+
+// Where control can go after an instruction, and whether it ends its block
+fn successors(
+    memory: &[u8],
+    address: u16,
+    opcode: u16,
+    end: usize,
+) -> (Vec<(u16, EdgeKind)>, bool) {
+    let decoded = Decoded::new(opcode);
+    // Nothing follows an instruction at the top of memory
+    let next = address.checked_add(instruction_length(opcode));
+    let fallthrough = next.map(|next| (next, EdgeKind::Fallthrough));
+    match decoded.upper {
+        0x0 if matches!(opcode, 0x00EE | 0x00FD) => (vec![], true),
+        0x1 => (vec![(decoded.nnn, EdgeKind::Jump)], true),
+        0x2 => {
+            let mut targets = vec![(decoded.nnn, EdgeKind::Call)];
+            targets.extend(fallthrough);
+            (targets, false)
+        }
+        0xB => {
+            let mut targets = vec![(decoded.nnn, EdgeKind::JumpTable)];
+            for entry in jump_table(memory, decoded.nnn, end) {
+                targets.push((entry, EdgeKind::JumpTable));
+            }
+            (targets, true)
+        }
+        _ if is_skip(opcode) => {
+            let mut targets: Vec<(u16, EdgeKind)> = fallthrough.into_iter().collect();
+            if let Some(next) = next {
+                let skipped = read_opcode(memory, next as usize).map_or(2, instruction_length);
+                targets.extend(next.checked_add(skipped).map(|to| (to, EdgeKind::Skip)));
+            }
+            (targets, true)
+        }
+        _ => (fallthrough.into_iter().collect(), false),
+    }
+}
+
+/// Basic blocks and subroutines of the program in `memory[start..end]`
+pub struct ControlFlowGraph {
+    // Where the program is loaded and starts running
+    pub start: u16,
+    pub blocks: BTreeMap<u16, Block>,
+    pub edges: Vec<Edge>,
+    pub subroutines: Vec<Subroutine>,
+    // Byte ranges of the program that are never reached as code
+    pub unreachable: Vec<(usize, usize)>,
+}
+
+impl ControlFlowGraph {
+    pub fn new(memory: &[u8], start: u16, end: usize) -> Self {
+        let code = reachable_code(memory, &[start], end);
+
+        // Blocks start at the entry, at every target and after every instruction ending one
+        let mut leaders = BTreeSet::from([start]);
+        for (address, opcode) in &code {
+            let (targets, ends_block) = successors(memory, *address, *opcode, end);
+            for (target, kind) in targets {
+                if ends_block || kind != EdgeKind::Fallthrough {
+                    leaders.insert(target);
+                }
+            }
+        }
+        let mut blocks: BTreeMap<u16, Block> = BTreeMap::new();
+        let mut current: Option<Block> = None;
+        for (address, opcode) in &code {
+            if let Some(block) = current.take() {
+                if !leaders.contains(address) && block.end() == *address as usize {
+                    current = Some(block);
+                } else {
+                    blocks.insert(block.start(), block);
+                }
+            }
+            match &mut current {
+                Some(block) => block.instructions.push((*address, *opcode)),
+                None => {
+                    current = Some(Block {
+                        instructions: vec![(*address, *opcode)],
+                    })
+                }
+            }
+            let (_, ends_block) = successors(memory, *address, *opcode, end);
+            if ends_block {
+                let block = current.take().unwrap();
+                blocks.insert(block.start(), block);
+            }
+        }
+        if let Some(block) = current {
+            blocks.insert(block.start(), block);
+        }
+
+        let mut edges = Vec::new();
+        for block in blocks.values() {
+            let last = block.instructions.len() - 1;
+            for (index, (address, opcode)) in block.instructions.iter().enumerate() {
+                let (targets, _) = successors(memory, *address, *opcode, end);
+                for (target, kind) in targets {
+                    let inside = kind == EdgeKind::Fallthrough && index < last;
+                    if !inside && blocks.contains_key(&target) {
+                        edges.push(Edge {
+                            from: block.start(),
+                            to: target,
+                            kind,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut entries = BTreeSet::from([start]);
+        entries.extend(
+            edges
+                .iter()
+                .filter(|e| e.kind == EdgeKind::Call)
+                .map(|e| e.to),
+        );
+        let subroutines = entries
+            .into_iter()
+            .map(|entry| {
+                let mut reached = BTreeSet::new();
+                let mut pending = vec![entry];
+                while let Some(block) = pending.pop() {
+                    if reached.insert(block) {
+                        pending.extend(
+                            edges
+                                .iter()
+                                .filter(|e| e.from == block && e.kind != EdgeKind::Call)
+                                .map(|e| e.to),
+                        );
+                    }
+                }
+                Subroutine {
+                    entry,
+                    blocks: reached,
+                }
+            })
+            .collect();
+
+        let mut unreachable = Vec::new();
+        let mut cursor = start as usize;
+        for block in blocks.values() {
+            if block.start() as usize > cursor {
+                unreachable.push((cursor, block.start() as usize));
+            }
+            cursor = cursor.max(block.end());
+        }
+        if cursor < end {
+            unreachable.push((cursor, end));
+        }
+
+        ControlFlowGraph {
+            start,
+            blocks,
+            edges,
+            subroutines,
+            unreachable,
+        }
+    }
+
+    fn subroutine_name(&self, entry: u16) -> String {
+        if entry == self.start {
+            "main".to_string()
+        } else {
+            format!("sub_{:03x}", entry)
+        }
+    }
+
+    fn instruction_text(address: u16, opcode: u16, memory: &[u8]) -> String {
+        let long = read_opcode(memory, address as usize + 2);
+        let source = instruction_source(opcode, long, &|a| format!("{:#05x}", a));
+        source.unwrap_or_else(|| format!("{:#06x}", opcode))
+    }
+
+    /// Graphviz source, with a cluster per subroutine
+    pub fn to_dot(&self, memory: &[u8]) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "  node [shape=box, fontname=\"monospace\"];").unwrap();
+        // A block shared by several subroutines is drawn in the first one
+        let mut drawn = BTreeSet::new();
+        for subroutine in &self.subroutines {
+            writeln!(out, "  subgraph cluster_{:03x} {{", subroutine.entry).unwrap();
+            let name = self.subroutine_name(subroutine.entry);
+            writeln!(out, "    label=\"{}\";", name).unwrap();
+            for start in &subroutine.blocks {
+                if !drawn.insert(*start) {
+                    continue;
+                }
+                let mut label = String::new();
+                for (address, opcode) in &self.blocks[start].instructions {
+                    let text = ControlFlowGraph::instruction_text(*address, *opcode, memory);
+                    label += &format!("{:03x}: {:04x}  {}\\l", address, opcode, text);
+                }
+                writeln!(out, "    b{:03x} [label=\"{}\"];", start, label).unwrap();
+            }
+            writeln!(out, "  }}").unwrap();
+        }
+        for edge in &self.edges {
+            writeln!(
+                out,
+                "  b{:03x} -> b{:03x}{};",
+                edge.from,
+                edge.to,
+                edge.kind.dot_style()
+            )
+            .unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn to_json(&self, memory: &[u8]) -> Value {
+        let blocks: Vec<Value> = self
+            .blocks
+            .values()
+            .map(|block| {
+                let instructions: Vec<Value> = block
+                    .instructions
+                    .iter()
+                    .map(|(address, opcode)| {
+                        json!({
+                            "address": address,
+                            "opcode": format!("{:04x}", opcode),
+                            "text": ControlFlowGraph::instruction_text(*address, *opcode, memory),
+                        })
+                    })
+                    .collect();
+                json!({"start": block.start(), "end": block.end(), "instructions": instructions})
+            })
+            .collect();
+        let edges: Vec<Value> = self
+            .edges
+            .iter()
+            .map(|e| json!({"from": e.from, "to": e.to, "kind": e.kind.name()}))
+            .collect();
+        let subroutines: Vec<Value> = self
+            .subroutines
+            .iter()
+            .map(|s| {
+                json!({
+                    "name": self.subroutine_name(s.entry),
+                    "entry": s.entry,
+                    "blocks": s.blocks,
+                })
+            })
+            .collect();
+        let unreachable: Vec<Value> = self
+            .unreachable
+            .iter()
+            .map(|(start, end)| json!({"start": start, "end": end}))
+            .collect();
+        json!({
+            "blocks": blocks,
+            "edges": edges,
+            "subroutines": subroutines,
+            "unreachable": unreachable,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::tests::program;
+
+    #[test]
+    fn test_blocks_and_edges() {
+        // main: 200 call 20a, 202 skip, 204 jump 200, 206 loop on itself
+        // 208: never reached, sub_20a: 20a v0 += 1, 20c return
+        let memory = program(&[0x220A, 0x3001, 0x1200, 0x1206, 0xFFFF, 0x7001, 0x00EE]);
+        let cfg = ControlFlowGraph::new(&memory, 0x200, memory.len());
+        let starts: Vec<u16> = cfg.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0x200, 0x204, 0x206, 0x20A]);
+        assert_eq!(cfg.blocks[&0x200].end(), 0x204);
+
+        let edge = |from, to, kind| Edge { from, to, kind };
+        assert!(cfg.edges.contains(&edge(0x200, 0x20A, EdgeKind::Call)));
+        assert!(cfg
+            .edges
+            .contains(&edge(0x200, 0x204, EdgeKind::Fallthrough)));
+        assert!(cfg.edges.contains(&edge(0x200, 0x206, EdgeKind::Skip)));
+        assert!(cfg.edges.contains(&edge(0x204, 0x200, EdgeKind::Jump)));
+        assert!(cfg.edges.contains(&edge(0x206, 0x206, EdgeKind::Jump)));
+        assert_eq!(cfg.edges.len(), 5);
+
+        let entries: Vec<u16> = cfg.subroutines.iter().map(|s| s.entry).collect();
+        assert_eq!(entries, vec![0x200, 0x20A]);
+        assert_eq!(
+            cfg.subroutines[0].blocks,
+            BTreeSet::from([0x200, 0x204, 0x206])
+        );
+        assert_eq!(cfg.unreachable, vec![(0x208, 0x20A)]);
+
+        let dot = cfg.to_dot(&memory);
+        assert!(dot.contains("subgraph cluster_20a {"));
+        assert!(dot.contains("b200 -> b206 [style=dashed, label=\"skip\"];"));
+        assert!(dot.contains("20a: 7001  v0 += 0x01\\l"));
+        let json = cfg.to_json(&memory);
+        assert_eq!(json["subroutines"][1]["name"], "sub_20a");
+        assert_eq!(json["edges"].as_array().unwrap().len(), 5);
+
+        // A CHIP-8X program at 0x300 that loops on itself
+        let mut memory = vec![0; 0x300];
+        memory.extend_from_slice(&[0x13, 0x00]);
+        let cfg = ControlFlowGraph::new(&memory, 0x300, memory.len());
+        assert_eq!(cfg.unreachable, vec![]);
+        assert_eq!(cfg.to_json(&memory)["subroutines"][0]["name"], "main");
+    }
+}
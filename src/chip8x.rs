@@ -0,0 +1,143 @@
+use crate::history::{invalid, StateReader};
+use std::io;
+
+// CHIP-8X drives the VP-590 colour board, which colours the 64x32 display in zones 8 pixels
+// wide. The zones set by BXY0 are 4 pixels high, BXYN sets them one row at a time.
+const COLUMNS: usize = 8;
+const ROWS: usize = 32;
+const ZONE_WIDTH: usize = 8;
+const ZONE_HEIGHT: usize = 4;
+
+/// The 8 colours of the VP-590, in the order of their numbers
+pub const COLOURS: [(u8, u8, u8); 8] = [
+    (0x00, 0x00, 0x00), // black
+    (0xFF, 0x00, 0x00), // red
+    (0x00, 0x00, 0xFF), // blue
+    (0xFF, 0x00, 0xFF), // violet
+    (0x00, 0xFF, 0x00), // green
+    (0xFF, 0xFF, 0x00), // yellow
+    (0x00, 0xFF, 0xFF), // aqua
+    (0xFF, 0xFF, 0xFF), // white
+];
+// 02A0 steps the background through these
+const BACKGROUNDS: [usize; 4] = [2, 0, 4, 1];
+
+/// Colour attributes laid over the monochrome display: a background colour and the
+/// foreground colour of every zone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColourMap {
+    background: usize,
+    zones: [[u8; COLUMNS]; ROWS],
+}
+
+impl ColourMap {
+    /// Red on blue, how the VP-590 starts up
+    pub fn default() -> Self {
+        ColourMap {
+            background: 0,
+            zones: [[1; COLUMNS]; ROWS],
+        }
+    }
+
+    /// 02A0: blue, black, green, red and back to blue
+    pub fn cycle_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUNDS.len();
+    }
+
+    fn fill(&mut self, columns: u8, rows: std::ops::Range<usize>, colour: u8) {
+        let first = (columns & 0xF) as usize;
+        let last = first + (columns >> 4) as usize;
+        for row in self.zones.iter_mut().skip(rows.start).take(rows.len()) {
+            for zone in row.iter_mut().take(last + 1).skip(first) {
+                *zone = colour & 0x7;
+            }
+        }
+    }
+
+    /// BXY0: `columns` has the first zone column in its low nibble and the number of extra
+    /// columns in its high nibble, `rows` the same for zone rows of 4 pixels
+    pub fn fill_zones(&mut self, columns: u8, rows: u8, colour: u8) {
+        let first = (rows & 0xF) as usize * ZONE_HEIGHT;
+        let count = ((rows >> 4) as usize + 1) * ZONE_HEIGHT;
+        self.fill(columns, first..first + count, colour);
+    }
+
+    /// BXYN: `height` pixel rows from `row` in the zone column in the low nibble of `column`
+    pub fn fill_rows(&mut self, column: u8, row: u8, height: u8, colour: u8) {
+        let first = row as usize % ROWS;
+        self.fill(column & 0xF, first..first + height as usize, colour);
+    }
+
+    pub fn background(&self) -> (u8, u8, u8) {
+        COLOURS[BACKGROUNDS[self.background]]
+    }
+
+    /// Colour of a set pixel
+    pub fn foreground(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        COLOURS[self.zones[y % ROWS][(x / ZONE_WIDTH) % COLUMNS] as usize]
+    }
+
+    /// Appends the background and the zone colours to a saved state
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        out.push(self.background as u8);
+        for row in self.zones.iter() {
+            out.extend_from_slice(row);
+        }
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> io::Result<Self> {
+        let mut colours = ColourMap::default();
+        colours.background = reader.u8()? as usize;
+        if colours.background >= BACKGROUNDS.len() {
+            return Err(invalid("Saved state has an unknown background colour"));
+        }
+        for row in colours.zones.iter_mut() {
+            row.copy_from_slice(reader.bytes(COLUMNS)?);
+            if row.iter().any(|colour| *colour as usize >= COLOURS.len()) {
+                return Err(invalid("Saved state has an unknown zone colour"));
+            }
+        }
+        Ok(colours)
+    }
+}
+
+/// 5XY1: adds the nibbles of `a` and `b` separately, each wrapping at 8
+pub fn add_nibbles(a: u8, b: u8) -> u8 {
+    let high = ((a >> 4) + (b >> 4)) % 8;
+    let low = ((a & 0xF) + (b & 0xF)) % 8;
+    (high << 4) | low
+}
+
+/// Pitch of the VP-595 sound board for the value FxF8 wrote to its port
+pub fn tone_frequency(port: u8) -> f32 {
+    27535.0 / (port as f32 + 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colour_map() {
+        let mut colours = ColourMap::default();
+        assert_eq!(colours.background(), COLOURS[2]);
+        colours.cycle_background();
+        colours.cycle_background();
+        assert_eq!(colours.background(), COLOURS[4]);
+
+        // Two columns from 1 and a single zone row at 2, so pixel rows 8 to 11
+        colours.fill_zones(0x11, 0x02, 0x6);
+        assert_eq!(colours.foreground(8, 8), COLOURS[6]);
+        assert_eq!(colours.foreground(23, 11), COLOURS[6]);
+        assert_eq!(colours.foreground(24, 11), COLOURS[1]);
+        assert_eq!(colours.foreground(8, 12), COLOURS[1]);
+        assert_eq!(colours.foreground(7, 8), COLOURS[1]);
+
+        // Rows run off the bottom instead of wrapping
+        colours.fill_rows(0x07, 30, 5, 0xB);
+        assert_eq!(colours.foreground(63, 31), COLOURS[3]);
+        assert_eq!(colours.foreground(63, 0), COLOURS[1]);
+
+        assert_eq!(add_nibbles(0x35, 0x46), 0x73);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::cpu::CPU;
+use crate::debugger::{BreakKind, Debugger, WatchMode};
+use crate::expr::{parse_number, Expr};
+use crate::history::Snapshot;
+use crate::memview::{hex_dump, parse_edit};
+use crate::register::{Reg, RegName};
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+const HELP: &str = "\
+Commands:
+  break <addr> [if <expr>]              stop before executing <addr>, which can also be a
+                                        label, label+offset or file:line
+  watch <addr>[-<end>] [r|w|rw] [if <expr>]  stop after memory is read/written
+  watchreg <reg> [if <expr>]            stop after a register changes
+  cond <expr>                           stop when <expr> becomes true
+  delete <id> | enable <id> | disable <id>
+  list                                  list breakpoints and watchpoints
+  continue | step [n] | pause
+  back [n]                              step back n instructions
+  rcontinue                             run backwards to the previous break or watch hit
+  regs                                  print the registers
+  where                                 show the label and source line of pc
+  mem <addr> [len]                      hex dump of memory
+  poke <addr>=<byte> [byte...]          write memory
+  save <file> | load <file>             save or restore the machine state
+Expressions use v0-vf, i, pc, sp, dt, st, [addr], numbers and C operators";
+
+/// Debugger frontend reading commands from stdin
+pub struct Console {
+    receiver: Receiver<String>,
+}
+
+impl Console {
+    pub fn spawn() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                match line {
+                    Ok(line) => {
+                        if sender.send(line).is_err() {
+                            break;
+                        }
+                    }
+                    Err(_) => break,
+                }
+            }
+        });
+        Console { receiver }
+    }
+
+    /// Runs every command typed since the last poll, must be called from the UI thread
+    pub fn poll(&self, cpu: &mut CPU) {
+        while let Ok(line) = self.receiver.try_recv() {
+            match execute(cpu, &line) {
+                Ok(output) => {
+                    if !output.is_empty() {
+                        println!("{}", output);
+                    }
+                }
+                Err(e) => println!("error: {}", e),
+            }
+        }
+    }
+}
+
+pub fn format_registers(reg: &Reg) -> String {
+    let mut out = String::new();
+    for (x, value) in reg.v.iter().enumerate() {
+        out += &format!("v{:x}={:02x} ", x, value);
+        if x == 7 {
+            out += "\n";
+        }
+    }
+    out += &format!(
+        "\npc={:03x} i={:03x} sp={:x} dt={:02x} st={:02x}",
+        reg.pc, reg.i, reg.sp, reg.delay_timer, reg.sound_time
+    );
+    out
+}
+
+fn parse_address(text: &str) -> Result<usize, String> {
+    match parse_number(text) {
+        Some(address) if address >= 0 => Ok(address as usize),
+        _ => Err(format!("invalid address '{}'", text)),
+    }
+}
+
+// Address, label, label+offset or file:line
+fn resolve(cpu: &CPU, text: &str) -> Result<u16, String> {
+    match &cpu.debugger {
+        Some(debugger) => debugger.symbols.resolve(text),
+        None => {
+            let address = parse_address(text)?;
+            u16::try_from(address).map_err(|_| format!("invalid address '{}'", text))
+        }
+    }
+}
+
+fn parse_id(args: &[&str]) -> Result<usize, String> {
+    let text = args.first().ok_or("missing breakpoint id")?;
+    text.trim_start_matches('#')
+        .parse()
+        .map_err(|_| format!("invalid breakpoint id '{}'", text))
+}
+
+// Splits `<args> if <expr>` into the arguments and the parsed condition
+fn split_condition(rest: &str) -> Result<(Vec<&str>, Option<Expr>), String> {
+    let (args, condition) = match rest.find(" if ") {
+        Some(index) => (&rest[..index], Some(Expr::parse(&rest[index + 4..])?)),
+        None => match rest.strip_prefix("if ") {
+            Some(condition) => ("", Some(Expr::parse(condition)?)),
+            None => (rest, None),
+        },
+    };
+    Ok((args.split_whitespace().collect(), condition))
+}
+
+pub fn execute(cpu: &mut CPU, line: &str) -> Result<String, String> {
+    let line = line.trim();
+    let (command, rest) = match line.split_once(char::is_whitespace) {
+        Some((command, rest)) => (command, rest.trim()),
+        None => (line, ""),
+    };
+    if command.is_empty() {
+        return Ok(String::new());
+    }
+    if matches!(command, "r" | "regs") {
+        return Ok(format_registers(cpu.registers()));
+    }
+    if command == "where" {
+        let pc = cpu.registers().pc;
+        return Ok(match &cpu.debugger {
+            Some(debugger) => debugger.symbols.describe(pc),
+            None => format!("{:#05x}", pc),
+        });
+    }
+    if matches!(command, "h" | "help") {
+        return Ok(HELP.to_string());
+    }
+    if matches!(command, "bs" | "back") {
+        let count = match rest {
+            "" => 1,
+            count => count.parse().map_err(|_| "invalid step count")?,
+        };
+        let reg = cpu.registers().clone();
+        cpu.debugger
+            .get_or_insert_with(Debugger::default)
+            .pause(&reg);
+        cpu.step_back(count)?;
+        return Ok(format!(
+            "at cycle {}\n{}",
+            cpu.cycles(),
+            format_registers(cpu.registers())
+        ));
+    }
+    match command {
+        "m" | "mem" => {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let start = resolve(cpu, args.first().ok_or("missing address")?)? as usize;
+            let length = match args.get(1) {
+                Some(length) => parse_address(length)?,
+                None => 0x40,
+            };
+            let memory = cpu.memory();
+            let end = start.saturating_add(length).min(memory.cart.len());
+            return Ok(
+                hex_dump(&memory.cart, start, end, cpu.registers(), memory.cart_size)
+                    .trim_end()
+                    .to_string(),
+            );
+        }
+        "poke" => {
+            if !cpu.is_paused() {
+                return Err("pause before editing memory".to_string());
+            }
+            let edits = parse_edit(rest)?;
+            for (address, value) in &edits {
+                cpu.poke(*address, *value)?;
+            }
+            return Ok(format!("wrote {} byte(s)", edits.len()));
+        }
+        "save" => {
+            cpu.snapshot()
+                .save(rest)
+                .map_err(|e| format!("could not save {}: {}", rest, e))?;
+            return Ok(format!("saved state to {}", rest));
+        }
+        "load" => {
+            let snapshot =
+                Snapshot::load(rest).map_err(|e| format!("could not load {}: {}", rest, e))?;
+            cpu.load_state(&snapshot);
+            return Ok(format!("loaded state from {}", rest));
+        }
+        _ => {}
+    }
+    if matches!(command, "rc" | "rcontinue") {
+        cpu.reverse_continue()?;
+        return Ok(format!("at cycle {}", cpu.cycles()));
+    }
+    let reg = cpu.registers().clone();
+    let memory_size = cpu.memory().cart.len();
+    let debugger = cpu.debugger.get_or_insert_with(Debugger::default);
+    match command {
+        "b" | "break" => {
+            let (args, condition) = split_condition(rest)?;
+            let address = debugger
+                .symbols
+                .resolve(args.first().ok_or("missing address")?)?;
+            if address as usize >= memory_size {
+                return Err(format!("{:#05x} is past the end of memory", address));
+            }
+            let id = debugger.add_breakpoint(BreakKind::Pc(address), condition);
+            Ok(format!(
+                "breakpoint #{} at {}",
+                id,
+                debugger.symbols.describe(address)
+            ))
+        }
+        "w" | "watch" => {
+            let (args, condition) = split_condition(rest)?;
+            let range = args.first().ok_or("missing address")?;
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (parse_address(start)?, parse_address(end)?),
+                None => (parse_address(range)?, parse_address(range)?),
+            };
+            if end < start {
+                return Err("range end is before its start".to_string());
+            }
+            let mode = match args.get(1) {
+                Some(mode) => WatchMode::parse(mode).ok_or("mode must be r, w or rw")?,
+                None => WatchMode::Write,
+            };
+            let id = debugger.add_breakpoint(BreakKind::Memory { start, end, mode }, condition);
+            Ok(format!("watchpoint #{}", id))
+        }
+        "wr" | "watchreg" => {
+            let (args, condition) = split_condition(rest)?;
+            let name = args.first().ok_or("missing register")?;
+            let name = RegName::parse(name).ok_or(format!("unknown register '{}'", name))?;
+            let id = debugger.add_breakpoint(BreakKind::Register(name), condition);
+            Ok(format!("watchpoint #{} on {}", id, name.name()))
+        }
+        "cond" => {
+            let condition = Expr::parse(rest)?;
+            let id = debugger.add_breakpoint(BreakKind::Condition, Some(condition));
+            Ok(format!("condition #{}", id))
+        }
+        "d" | "delete" | "enable" | "disable" => {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let id = parse_id(&args)?;
+            let found = match command {
+                "enable" => debugger.set_enabled(id, true),
+                "disable" => debugger.set_enabled(id, false),
+                _ => debugger.remove_breakpoint(id),
+            };
+            if found {
+                Ok(String::new())
+            } else {
+                Err(format!("no breakpoint #{}", id))
+            }
+        }
+        "l" | "list" => Ok(debugger
+            .breakpoints()
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<String>>()
+            .join("\n")),
+        "c" | "continue" => {
+            debugger.resume();
+            Ok(String::new())
+        }
+        "s" | "step" => {
+            let count = match rest {
+                "" => 1,
+                count => count.parse().map_err(|_| "invalid step count")?,
+            };
+            debugger.step(count);
+            Ok(String::new())
+        }
+        "p" | "pause" => {
+            debugger.pause(&reg);
+            Ok(String::new())
+        }
+        _ => Err(format!("unknown command '{}', try 'help'", command)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbols::SymbolMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_split_condition() {
+        let (args, condition) = split_condition("0x300-0x310 rw if v3 == 1").unwrap();
+        assert_eq!(args, vec!["0x300-0x310", "rw"]);
+        assert_eq!(condition, Some(Expr::parse("v3 == 1").unwrap()));
+        let (args, condition) = split_condition("0x200").unwrap();
+        assert_eq!(args, vec!["0x200"]);
+        assert_eq!(condition, None);
+        assert!(split_condition("0x200 if v3 ==").is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut cpu = CPU::default();
+        assert!(execute(&mut cpu, "break 0x202 if v0 == 0").is_ok());
+        assert!(execute(&mut cpu, "watch 0x300-0x30f r").is_ok());
+        assert!(execute(&mut cpu, "watchreg vf").is_ok());
+        assert!(execute(&mut cpu, "watch 0x300 x").is_err());
+        // Past the end of the 4K of memory, and too big for an address
+        assert!(execute(&mut cpu, "break 0x1000").is_err());
+        assert!(execute(&mut cpu, "break 0x10200").is_err());
+        assert!(execute(&mut cpu, "frobnicate").is_err());
+        assert_eq!(cpu.debugger.as_ref().unwrap().breakpoints().len(), 3);
+        assert!(execute(&mut cpu, "delete 2").is_ok());
+        assert!(execute(&mut cpu, "delete 2").is_err());
+        assert!(execute(&mut cpu, "pause").is_ok());
+        assert!(cpu.is_paused());
+        assert!(execute(&mut cpu, "continue").is_ok());
+        assert!(!cpu.is_paused());
+        assert!(execute(&mut cpu, "poke 0x300=0x12 0x34").is_err());
+        assert!(execute(&mut cpu, "pause").is_ok());
+        assert!(execute(&mut cpu, "poke 0x300=0x12 0x34").is_ok());
+        assert_eq!(cpu.memory().cart[0x301], 0x34);
+        assert!(execute(&mut cpu, "mem 0x300 8").unwrap().contains("12 34"));
+        // A length running past the end of memory stops there
+        assert!(execute(&mut cpu, "mem 0xff0 0x7fffffffffffffff")
+            .unwrap()
+            .contains("ff0"));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut cpu = CPU::default();
+        let mut symbols = SymbolMap::default();
+        symbols.source = Some("game.8o".to_string());
+        symbols.add_label("main_loop", 0x204);
+        symbols.add_line(0x206, 9);
+        cpu.debugger.get_or_insert_with(Debugger::default).symbols = Rc::new(symbols);
+        assert_eq!(
+            execute(&mut cpu, "break main_loop+2"),
+            Ok("breakpoint #1 at 0x206 main_loop+0x2 game.8o:9".to_string())
+        );
+        assert!(execute(&mut cpu, "break game.8o:9").is_ok());
+        assert!(execute(&mut cpu, "break other_loop").is_err());
+        assert_eq!(execute(&mut cpu, "where"), Ok("0x200".to_string()));
+    }
+}
@@ -0,0 +1,328 @@
+use crate::analysis::instruction_length;
+use crate::cfg::ControlFlowGraph;
+use crate::ram::{Access, AccessKind};
+use crate::symbols::SymbolMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// Addresses a run has executed, read and written, to find the code it never reached
+pub struct Coverage {
+    // Times the instruction at each address was executed
+    executed: BTreeMap<u16, u64>,
+    read: BTreeSet<u16>,
+    written: BTreeSet<u16>,
+}
+
+fn percent(part: usize, total: usize) -> f64 {
+    if total > 0 {
+        part as f64 * 100.0 / total as f64
+    } else {
+        100.0
+    }
+}
+
+impl Coverage {
+    pub fn default() -> Self {
+        Coverage {
+            executed: BTreeMap::new(),
+            read: BTreeSet::new(),
+            written: BTreeSet::new(),
+        }
+    }
+
+    /// Called for every fetched instruction
+    pub fn record_fetch(&mut self, address: u16) {
+        *self.executed.entry(address).or_insert(0) += 1;
+    }
+
+    pub fn record_accesses(&mut self, accesses: &[Access]) {
+        for access in accesses {
+            match access.kind {
+                AccessKind::Read => self.read.insert(access.address as u16),
+                AccessKind::Write => self.written.insert(access.address as u16),
+            };
+        }
+    }
+
+    fn count(&self, address: u16) -> u64 {
+        self.executed.get(&address).copied().unwrap_or(0)
+    }
+
+    // Instructions found by following the program flow, plus any others that ran, like
+    // ones reached through computed jumps
+    fn instructions(graph: &ControlFlowGraph, executed: &BTreeMap<u16, u64>) -> BTreeSet<u16> {
+        let mut instructions: BTreeSet<u16> = graph
+            .blocks
+            .values()
+            .flat_map(|block| block.instructions.iter().map(|(address, _)| *address))
+            .collect();
+        instructions.extend(executed.keys().filter(|a| **a >= graph.start));
+        instructions
+    }
+
+    fn subroutine_name(graph: &ControlFlowGraph, entry: u16, symbols: &SymbolMap) -> String {
+        match symbols.symbolize(entry) {
+            Some(name) if !name.contains('+') => name,
+            _ if entry == graph.start => "main".to_string(),
+            _ => format!("sub_{:03x}", entry),
+        }
+    }
+
+    /// Per-subroutine coverage of the program in `memory[start..end]` and the ranges of
+    /// code that never ran
+    pub fn text_report(
+        &self,
+        memory: &[u8],
+        start: u16,
+        end: usize,
+        symbols: &SymbolMap,
+    ) -> String {
+        let graph = ControlFlowGraph::new(memory, start, end);
+        let instructions = Coverage::instructions(&graph, &self.executed);
+        let covered = instructions.iter().filter(|a| self.count(**a) > 0).count();
+        let mut out = String::new();
+        writeln!(out, "=== Coverage ===").unwrap();
+        writeln!(
+            out,
+            "{} of {} instructions executed ({:.1}%)",
+            covered,
+            instructions.len(),
+            percent(covered, instructions.len())
+        )
+        .unwrap();
+
+        // Program bytes that are not part of any instruction
+        let mut code_bytes = BTreeSet::new();
+        for address in &instructions {
+            let opcode = ((memory[*address as usize] as u16) << 8)
+                | memory.get(*address as usize + 1).copied().unwrap_or(0) as u16;
+            let address = *address as usize;
+            code_bytes.extend(address..address + instruction_length(opcode) as usize);
+        }
+        let data: Vec<u16> = (start as usize..end)
+            .filter(|a| !code_bytes.contains(a))
+            .map(|a| a as u16)
+            .collect();
+        let data_read = data.iter().filter(|a| self.read.contains(a)).count();
+        let data_written = data.iter().filter(|a| self.written.contains(a)).count();
+        writeln!(
+            out,
+            "{} of {} data bytes read ({:.1}%), {} written",
+            data_read,
+            data.len(),
+            percent(data_read, data.len()),
+            data_written
+        )
+        .unwrap();
+
+        writeln!(out, "\nSubroutines:").unwrap();
+        for subroutine in &graph.subroutines {
+            let addresses: Vec<u16> = subroutine
+                .blocks
+                .iter()
+                .flat_map(|start| graph.blocks[start].instructions.iter().map(|(a, _)| *a))
+                .collect();
+            let hit = addresses.iter().filter(|a| self.count(**a) > 0).count();
+            writeln!(
+                out,
+                "  {:#05x}  {:>6.1}%  {:>4}/{:<4}  {}",
+                subroutine.entry,
+                percent(hit, addresses.len()),
+                hit,
+                addresses.len(),
+                Coverage::subroutine_name(&graph, subroutine.entry, symbols)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nNever executed:").unwrap();
+        let mut range: Option<(u16, usize, usize)> = None;
+        let mut ranges = Vec::new();
+        for block in graph.blocks.values() {
+            for (address, opcode) in &block.instructions {
+                let next = *address as usize + instruction_length(*opcode) as usize;
+                range = match (range, self.count(*address) > 0) {
+                    (Some(r), true) => {
+                        ranges.push(r);
+                        None
+                    }
+                    (None, true) => None,
+                    (Some((start, end, count)), false) if end == *address as usize => {
+                        Some((start, next, count + 1))
+                    }
+                    (Some(r), false) => {
+                        ranges.push(r);
+                        Some((*address, next, 1))
+                    }
+                    (None, false) => Some((*address, next, 1)),
+                };
+            }
+        }
+        ranges.extend(range);
+        if ranges.is_empty() {
+            writeln!(out, "  nothing").unwrap();
+        }
+        for (start, end, count) in ranges {
+            write!(
+                out,
+                "  {:#05x}-{:#05x}  {:>4} instructions",
+                start,
+                end - 1,
+                count
+            )
+            .unwrap();
+            if let Some(location) = symbols.location(start) {
+                write!(out, "  {}", location).unwrap();
+            }
+            writeln!(out).unwrap();
+        }
+        out
+    }
+
+    /// LCOV tracefile of the run. Lines come from the symbol map when the program was compiled
+    /// from source, otherwise every instruction is a "line" numbered by its address in `rom`.
+    pub fn lcov(
+        &self,
+        memory: &[u8],
+        start: u16,
+        end: usize,
+        symbols: &SymbolMap,
+        rom: &str,
+    ) -> String {
+        let graph = ControlFlowGraph::new(memory, start, end);
+        let line = |address: u16| match &symbols.source {
+            Some(_) => symbols.line(address),
+            None => Some(address as usize),
+        };
+        let mut out = String::new();
+        writeln!(out, "TN:").unwrap();
+        writeln!(out, "SF:{}", symbols.source.as_deref().unwrap_or(rom)).unwrap();
+        // Subroutines that have a line to point at
+        let functions: Vec<(String, usize, u64)> = graph
+            .subroutines
+            .iter()
+            .filter_map(|subroutine| {
+                let name = Coverage::subroutine_name(&graph, subroutine.entry, symbols);
+                Some((name, line(subroutine.entry)?, self.count(subroutine.entry)))
+            })
+            .collect();
+        for (name, line, _) in &functions {
+            writeln!(out, "FN:{},{}", line, name).unwrap();
+        }
+        for (name, _, count) in &functions {
+            writeln!(out, "FNDA:{},{}", count, name).unwrap();
+        }
+        writeln!(out, "FNF:{}", functions.len()).unwrap();
+        let functions_hit = functions.iter().filter(|(_, _, count)| *count > 0).count();
+        writeln!(out, "FNH:{}", functions_hit).unwrap();
+
+        // A line with several instructions counts as often as its busiest one
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        for address in Coverage::instructions(&graph, &self.executed) {
+            if let Some(line) = line(address) {
+                let count = lines.entry(line).or_insert(0);
+                *count = (*count).max(self.count(address));
+            }
+        }
+        for (line, count) in &lines {
+            writeln!(out, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(out, "LF:{}", lines.len()).unwrap();
+        writeln!(out, "LH:{}", lines.values().filter(|c| **c > 0).count()).unwrap();
+        writeln!(out, "end_of_record").unwrap();
+        out
+    }
+
+    /// Executed addresses, one hex address per line, as read by `disasm --coverage`
+    pub fn address_log(&self) -> String {
+        self.executed
+            .keys()
+            .map(|address| format!("{:03x}\n", address))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn program() -> Vec<u8> {
+        // 200: if v0 == 1 then call 208, 204: jump 204, 206: data, 208: v1 := 0, return
+        let mut memory = vec![0; 0x200];
+        for opcode in [0x4001, 0x2208, 0x1204, 0xABCD, 0x6100, 0x00EE] {
+            memory.extend_from_slice(&u16::to_be_bytes(opcode));
+        }
+        memory
+    }
+
+    fn run(coverage: &mut Coverage) {
+        coverage.record_fetch(0x200);
+        coverage.record_fetch(0x204);
+        coverage.record_fetch(0x204);
+        coverage.record_accesses(&[Access {
+            address: 0x206,
+            kind: AccessKind::Read,
+            value: 0xAB,
+        }]);
+    }
+
+    #[test]
+    fn test_report() {
+        let memory = program();
+        let mut coverage = Coverage::default();
+        run(&mut coverage);
+        let mut symbols = SymbolMap::default();
+        symbols.add_label("draw", 0x208);
+        let report = coverage.text_report(&memory, 0x200, memory.len(), &symbols);
+        assert!(report.contains("2 of 5 instructions executed (40.0%)"));
+        assert!(report.contains("1 of 2 data bytes read (50.0%), 0 written"));
+        assert!(report.contains("  0x200    66.7%     2/3     main\n"));
+        assert!(report.contains("  0x208     0.0%     0/2     draw\n"));
+        assert!(report.contains("  0x202-0x203     1 instructions\n"));
+        assert!(report.contains("  0x208-0x20b     2 instructions  draw\n"));
+        assert_eq!(coverage.address_log(), "200\n204\n");
+    }
+
+    #[test]
+    fn test_top_of_memory() {
+        // fff8: skip, fffa: clear, fffc: F000 NNNN running into the end of 64K memory
+        let mut memory = vec![0; 0x10000];
+        memory[0xFFF8..].copy_from_slice(&[0x30, 0x00, 0x00, 0xE0, 0xF0, 0x00, 0x00, 0x00]);
+        let mut coverage = Coverage::default();
+        coverage.record_fetch(0xFFF8);
+        let graph = ControlFlowGraph::new(&memory, 0xFFF8, memory.len());
+        assert_eq!(graph.blocks[&0xFFFC].end(), 0x10000);
+        assert!(graph.unreachable.is_empty());
+        let report = coverage.text_report(&memory, 0xFFF8, memory.len(), &SymbolMap::default());
+        assert!(report.contains("1 of 3 instructions executed (33.3%)"));
+        assert!(report.contains("0 of 0 data bytes read"));
+        assert!(report.contains("  0xfffa-0xffff     2 instructions\n"));
+    }
+
+    #[test]
+    fn test_lcov() {
+        let memory = program();
+        let mut coverage = Coverage::default();
+        run(&mut coverage);
+        let lcov = coverage.lcov(
+            &memory,
+            0x200,
+            memory.len(),
+            &SymbolMap::default(),
+            "game.ch8",
+        );
+        assert!(lcov.starts_with("TN:\nSF:game.ch8\nFN:512,main\nFN:520,sub_208\n"));
+        assert!(lcov.contains("FNDA:1,main\nFNDA:0,sub_208\nFNF:2\nFNH:1\n"));
+        assert!(lcov.contains("DA:516,2\nDA:520,0\n"));
+        assert!(lcov.ends_with("LF:5\nLH:2\nend_of_record\n"));
+
+        let mut symbols = SymbolMap::default();
+        symbols.source = Some("game.8o".to_string());
+        symbols.add_line(0x200, 3);
+        symbols.add_line(0x202, 3);
+        symbols.add_line(0x204, 4);
+        let lcov = coverage.lcov(&memory, 0x200, memory.len(), &symbols, "game.ch8");
+        assert!(lcov.starts_with("TN:\nSF:game.8o\nFN:3,main\nFNDA:1,main\nFNF:1\n"));
+        assert!(lcov.contains("DA:3,1\nDA:4,2\nLF:2\nLH:2\n"));
+    }
+}
@@ -1,8 +1,26 @@
+use crate::cartridge;
+use crate::chip8x::{self, ColourMap};
+use crate::coverage::Coverage;
+use crate::debugger::{Debugger, StopReason};
 use crate::display::EmuDisplay;
-use crate::ram::RAM;
+use crate::flags::RplFlags;
+use crate::fonts::{FontSet, BIG_GLYPH, DEFAULT_FONT_ADDRESS, SMALL_GLYPH};
+use crate::heatmap::Heatmap;
+use crate::history::{History, Snapshot};
+use crate::keyboard::InputState;
+use crate::machine_code::{Dispatch, MachineCode, Routine};
+use crate::megachip::{BlendMode, MegaChip, Sound, SoundCommand};
+use crate::profiler::Profiler;
+use crate::quirks::{Platform, Quirks};
+use crate::ram::{Access, RAM};
 use crate::register::Reg;
+use crate::romdb::RomInfo;
+use crate::spriteview::row_pixels;
 use crate::stack::Stack;
-use rand;
+use crate::symbols::SymbolMap;
+use crate::trace::Trace;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::cmp;
 
 pub struct CPU {
@@ -11,18 +29,43 @@ pub struct CPU {
     stack: Stack,
     display: EmuDisplay,
     found_key: Option<u8>,
+    // Random numbers and input go through the CPU so that execution can be replayed exactly
+    rng: StdRng,
+    input: InputState,
+    // Instructions executed since power on
+    cycles: u64,
+    pub quirks: Quirks,
+    pub platform: Platform,
+    pub flags: RplFlags,
+    font: FontSet,
+    font_address: u16,
+    // Last value FxF8 sent to the CHIP-8X sound board
+    tone_port: u8,
+    // Set while MegaChip mode is on
+    mega: Option<MegaChip>,
+    // Set by DXYN with the display wait quirk, instructions stall until the next timer tick
+    waiting_for_frame: bool,
+    // Last sound started or stopped by MegaChip, until the audio output takes it
+    sound_command: Option<SoundCommand>,
+    pub profiler: Option<Profiler>,
+    pub debugger: Option<Debugger>,
+    pub history: Option<History>,
+    pub trace: Option<Trace>,
+    pub coverage: Option<Coverage>,
+    pub heatmap: Option<Heatmap>,
+    pub machine_code: MachineCode,
 }
 
 #[derive(Debug)]
-struct Decoded {
+pub struct Decoded {
     // For the u8 values only lower nibble is used
     // For u16 only lower 12 bits are used
-    x: u8,
-    y: u8,
-    n: u8,
-    nn: u8,
-    nnn: u16,
-    upper: u8,
+    pub x: u8,
+    pub y: u8,
+    pub n: u8,
+    pub nn: u8,
+    pub nnn: u16,
+    pub upper: u8,
 }
 // TODO Create a font set in memory
 impl Decoded {
@@ -50,58 +93,382 @@ impl CPU {
             stack: Stack::default(),
             display,
             found_key: None,
+            rng: StdRng::from_entropy(),
+            input: InputState::default(),
+            cycles: 0,
+            quirks: Platform::Chip8.quirks(),
+            platform: Platform::Chip8,
+            flags: RplFlags::default(),
+            font: Platform::Chip8.font(),
+            font_address: DEFAULT_FONT_ADDRESS,
+            tone_port: 0,
+            mega: None,
+            waiting_for_frame: false,
+            sound_command: None,
+            profiler: None,
+            debugger: None,
+            history: None,
+            trace: None,
+            coverage: None,
+            heatmap: None,
+            machine_code: MachineCode::default(),
         };
         cpu.reg.pc = 0x200;
-        cpu.memory.cart_size = 0x200;
-        cpu.setup_fonts();
+        cpu.set_font(cpu.font, cpu.font_address);
         cpu
     }
-    fn setup_fonts(&mut self) {
-        let fonts = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ];
-        for i in 0..fonts.len() {
-            self.memory.write(0x50 + i, fonts[i]).unwrap();
-        }
-    }
-
-    pub fn load_rom(&mut self, rom: &str) {
-        self.memory.load(rom).unwrap();
+    /// Puts `font` in memory at `address`, clearing the previous font
+    pub fn set_font(&mut self, font: FontSet, address: u16) {
+        let old = self.font_address as usize;
+        let old_size = self.font.bytes().len();
+        for byte in &mut self.memory.cart[old..old + old_size] {
+            *byte = 0;
+        }
+        for (i, byte) in font.bytes().iter().enumerate() {
+            self.memory.write(address as usize + i, *byte).unwrap();
+        }
+        self.font = font;
+        self.font_address = address;
+    }
+
+    /// Replaces memory with `size` bytes, keeping the font, and moves the program start
+    pub fn set_memory_layout(&mut self, size: usize, load_address: u16) {
+        self.memory = RAM::new(size, load_address as usize);
+        self.set_font(self.font, self.font_address);
+        self.reg.pc = load_address;
+    }
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), String> {
+        self.memory.load_bytes(program)
+    }
+    /// Loads a ROM file into the current memory layout. Octo cartridges (.gif) are compiled and
+    /// set up the memory and settings they were saved with, which are returned with their symbols
+    pub fn load_rom(&mut self, path: &str) -> Result<Option<(RomInfo, SymbolMap)>, String> {
+        if !path.to_ascii_lowercase().ends_with(".gif") {
+            self.memory.load(path).map_err(|e| e.to_string())?;
+            return Ok(None);
+        }
+        let cartridge = cartridge::load(path)?;
+        let mut program = cartridge.program()?;
+        program.symbols.source = Some(path.to_string());
+        let platform = cartridge.info.platform;
+        self.set_memory_layout(
+            platform.memory_size(program.rom.len()),
+            platform.load_address(),
+        );
+        self.load_program(&program.rom)?;
+        self.apply_rom_info(&cartridge.info);
+        Ok(Some((cartridge.info, program.symbols)))
+    }
+    /// Uses the quirks, key map and palette the ROM was made for
+    pub fn apply_rom_info(&mut self, info: &RomInfo) {
+        self.quirks = info.quirks;
+        self.platform = info.platform;
+        self.set_font(info.font, self.font_address);
+        *self.display.keymap.borrow_mut() = info.keymap;
+        *self.display.palette.borrow_mut() = info.palette;
+        *self.display.colours.borrow_mut() = match info.platform {
+            Platform::Chip8X => Some(ColourMap::default()),
+            _ => None,
+        };
+        self.display.set_resolution(info.platform.resolution());
+        self.machine_code.set_platform(info.platform);
+        // MegaChip programs start in CHIP-8 mode
+        self.mega = None;
+        *self.display.mega_frame.borrow_mut() = None;
     }
     pub fn fetch(&mut self) -> u16 {
         //println!("PC: {:x}, Cycle: {}", self.reg.pc, self.cycle_count);
-        let opcode: u8 = match self.memory.read(self.reg.pc as usize) {
+        match self.memory.fetch(self.reg.pc as usize) {
             Ok(opcode) => opcode,
-            Err(e) => panic!("Error fetching upper byte: {}", e),
+            Err(e) => panic!("Error fetching opcode: {}", e),
+        }
+    }
+    pub fn registers(&self) -> &Reg {
+        &self.reg
+    }
+    pub fn set_pc(&mut self, pc: u16) {
+        self.reg.pc = pc;
+    }
+    pub fn memory(&self) -> &RAM {
+        &self.memory
+    }
+    pub fn set_paused(&mut self, paused: bool) {
+        let debugger = self.debugger.get_or_insert_with(Debugger::default);
+        if paused {
+            debugger.pause(&self.reg);
+        } else {
+            debugger.resume();
+        }
+    }
+    pub fn is_paused(&self) -> bool {
+        match &self.debugger {
+            Some(debugger) => debugger.is_paused(),
+            None => false,
+        }
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    // True while re-executing instructions that are already in the recorded history
+    fn is_replaying(&self) -> bool {
+        match &self.history {
+            Some(history) => self.cycles < history.end(),
+            None => false,
+        }
+    }
+    pub fn run(&mut self) {
+        // Replays tick the timers themselves, so they never wait for a live frame
+        if self.waiting_for_frame && !self.is_replaying() {
+            return;
+        }
+        self.memory.trace_accesses =
+            self.debugger.is_some() || self.coverage.is_some() || self.heatmap.is_some();
+        if let Some(debugger) = &mut self.debugger {
+            if !debugger.before_step(&self.reg, &self.memory.cart) {
+                return;
+            }
+        }
+        let before = self.debugger.as_ref().map(|_| self.reg.clone());
+
+        if self.is_replaying() {
+            self.replay_step();
+        } else {
+            self.input = self.display.input_state();
+            if self.history.is_some() {
+                self.record_history();
+            }
+            let opcode = self.fetch();
+            if let Some(profiler) = &mut self.profiler {
+                profiler.record_instruction(self.reg.pc, opcode);
+            }
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record_fetch(self.reg.pc);
+            }
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record_fetch(self.reg.pc, opcode);
+            }
+            if let Some(trace) = &mut self.trace {
+                if let Err(e) = trace.record(self.cycles, self.reg.pc, opcode) {
+                    eprintln!("Could not write trace, stopping it: {}", e);
+                    self.trace = None;
+                }
+            }
+            self.execute(opcode);
+            self.cycles += 1;
+            if let Some(history) = &mut self.history {
+                history.set_end(self.cycles);
+            }
+        }
+
+        if self.memory.trace_accesses {
+            let accesses = self.memory.take_accesses();
+            if let Some(coverage) = &mut self.coverage {
+                coverage.record_accesses(&accesses);
+            }
+            if let Some(heatmap) = &mut self.heatmap {
+                heatmap.record_accesses(&accesses);
+            }
+            if let (Some(debugger), Some(before)) = (&mut self.debugger, before) {
+                debugger.after_step(&before, &self.reg, &self.memory.cart, &accesses);
+            }
+        }
+    }
+
+    fn record_history(&mut self) {
+        let snapshot = match &self.history {
+            Some(history) if history.needs_checkpoint(self.cycles) => Some(self.snapshot()),
+            _ => None,
         };
-        let mut opcode: u16 = (opcode as u16) << 8;
-        let lower = match self.memory.read(self.reg.pc as usize + 1) {
-            Ok(lower) => lower,
-            Err(e) => panic!("Error fetching lower byte: {}", e),
+        if let Some(history) = &mut self.history {
+            if let Some(snapshot) = snapshot {
+                history.add_checkpoint(snapshot);
+            }
+            history.record_input(self.cycles, self.input);
+        }
+    }
+
+    // Executes the next instruction with the input and timer ticks recorded in the history
+    fn replay_step(&mut self) {
+        let history = self.history.as_ref().unwrap();
+        self.input = history.input_at(self.cycles);
+        let opcode = self.fetch();
+        self.execute(opcode);
+        self.cycles += 1;
+        let ticks = self.history.as_ref().unwrap().ticks_at(self.cycles);
+        for _ in 0..ticks {
+            self.tick_timers();
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            reg: self.reg.clone(),
+            memory: self.memory.cart.clone(),
+            cart_size: self.memory.cart_size,
+            stack: self.stack.clone(),
+            pixels: self.display.pixel_mat.borrow().clone(),
+            found_key: self.found_key,
+            rng: self.rng.clone(),
+            input: self.input,
+            cycles: self.cycles,
+            mega: self.mega.clone(),
+            colours: *self.display.colours.borrow(),
+            tone_port: self.tone_port,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.reg = snapshot.reg.clone();
+        self.memory.cart = snapshot.memory.clone();
+        self.memory.cart_size = snapshot.cart_size;
+        self.stack = snapshot.stack.clone();
+        *self.display.pixel_mat.borrow_mut() = snapshot.pixels.clone();
+        self.found_key = snapshot.found_key;
+        self.rng = snapshot.rng.clone();
+        self.input = snapshot.input;
+        self.cycles = snapshot.cycles;
+        self.mega = snapshot.mega.clone();
+        *self.display.mega_frame.borrow_mut() = self.mega.as_ref().map(|m| m.frame().to_vec());
+        *self.display.colours.borrow_mut() = snapshot.colours;
+        self.tone_port = snapshot.tone_port;
+        self.waiting_for_frame = false;
+    }
+
+    /// Moves execution back (or forward, within the recorded history) to the given cycle by
+    /// restoring the closest checkpoint and re-executing from there
+    pub fn rewind_to(&mut self, target: u64) -> Result<(), &'static str> {
+        let history = self
+            .history
+            .as_ref()
+            .ok_or("History is not being recorded")?;
+        if target > history.end() {
+            return Err("Cannot go past the end of the recorded history");
+        }
+        let checkpoint = history
+            .checkpoint_before(target)
+            .ok_or("Target is older than the recorded history")?
+            .clone();
+        self.restore(&checkpoint);
+        // Re-executed instructions were already profiled the first time around
+        let profiler = self.profiler.take();
+        while self.cycles < target {
+            self.replay_step();
+        }
+        self.profiler = profiler;
+        self.memory.take_accesses();
+        Ok(())
+    }
+
+    pub fn step_back(&mut self, count: u64) -> Result<(), &'static str> {
+        let start = match &self.history {
+            Some(history) => history.start().unwrap_or(0),
+            None => return Err("History is not being recorded"),
         };
-        opcode = opcode | (lower as u16);
-        opcode
+        if self.cycles <= start {
+            return Err("Already at the start of the recorded history");
+        }
+        self.rewind_to(cmp::max(self.cycles.saturating_sub(count), start))
     }
-    pub fn run(&mut self) {
+
+    /// Changes a byte of memory by hand, the recorded future is dropped as it no longer applies
+    pub fn poke(&mut self, address: usize, value: u8) -> Result<(), &'static str> {
+        if address >= self.memory.cart.len() {
+            return Err("Address out of range");
+        }
+        self.memory.cart[address] = value;
+        let cycles = self.cycles;
+        if let Some(history) = &mut self.history {
+            history.truncate(cycles);
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole machine state with a saved one, starting a new recorded history
+    pub fn load_state(&mut self, snapshot: &Snapshot) {
+        self.restore(snapshot);
+        if self.history.is_some() {
+            self.history = Some(History::default());
+        }
+    }
+
+    /// Runs backwards until the most recent breakpoint or watchpoint hit before the current
+    /// position and stops there, or at the start of the history if nothing triggers
+    pub fn reverse_continue(&mut self) -> Result<StopReason, &'static str> {
+        if self.debugger.is_none() {
+            return Err("No debugger attached");
+        }
+        let checkpoints = match &self.history {
+            Some(history) => history.checkpoint_cycles(),
+            None => return Err("History is not being recorded"),
+        };
+        let start = self.cycles;
+        let mut segment_end = start;
+        self.memory.trace_accesses = true;
+        let profiler = self.profiler.take();
+        let mut found = None;
+        for checkpoint in checkpoints.iter().rev().filter(|c| **c < start) {
+            self.rewind_to(*checkpoint)?;
+            // The scan must not count hits or move condition edges, so it only peeks
+            let mut conditions = self
+                .debugger
+                .as_ref()
+                .unwrap()
+                .conditions(&self.reg, &self.memory.cart);
+            let mut last_hit = None;
+            while self.cycles < segment_end {
+                let debugger = self.debugger.as_ref().unwrap();
+                if let Some(reason) = debugger.peek_before(&self.reg, &self.memory.cart) {
+                    last_hit = Some((self.cycles, reason));
+                }
+                let before = self.reg.clone();
+                self.replay_step();
+                let accesses: Vec<Access> = self.memory.take_accesses();
+                let debugger = self.debugger.as_ref().unwrap();
+                let hit = debugger.peek_after(
+                    &before,
+                    &self.reg,
+                    &self.memory.cart,
+                    &accesses,
+                    &mut conditions,
+                );
+                if let Some(reason) = hit {
+                    if self.cycles < start {
+                        last_hit = Some((self.cycles, reason));
+                    }
+                }
+            }
+            if last_hit.is_some() {
+                found = last_hit;
+                break;
+            }
+            segment_end = *checkpoint;
+        }
+        self.profiler = profiler;
+        let reason = match found {
+            Some((cycles, reason)) => {
+                self.rewind_to(cycles)?;
+                reason
+            }
+            None => {
+                if let Some(oldest) = checkpoints.first() {
+                    self.rewind_to(*oldest)?;
+                }
+                StopReason::HistoryStart
+            }
+        };
+        let debugger = self.debugger.as_mut().unwrap();
+        debugger.count_hit(&reason);
+        debugger.reset_conditions(&self.reg, &self.memory.cart);
+        debugger.stop(reason.clone(), &self.reg);
+        Ok(reason)
+    }
+
+    fn execute(&mut self, opcode: u16) {
         // decode for chip-8
-        // I have not implemented 0nnn instruction which was used on old chip-8 interpreters
-        let opcode = self.fetch();
         self.reg.pc += 2;
+        if self.platform == Platform::MegaChip && opcode & 0xF000 == 0 && self.megachip_op(opcode) {
+            return;
+        }
         if opcode == 0x00E0 {
             self.clear_screen();
         } else if opcode == 0x00EE {
@@ -110,16 +477,28 @@ impl CPU {
             let decoded = Decoded::new(opcode);
             // //println!("{:?}", decoded);
             match decoded.upper {
+                // SCHIP scrolling and resolution changes are not machine code
+                0x0 if matches!(opcode, 0x00C0..=0x00CF | 0x00FB..=0x00FF) => {
+                    self.halt(StopReason::Unsupported {
+                        address: self.reg.pc - 2,
+                        opcode,
+                    })
+                }
+                0x0 => self.call_machine_code(decoded),
                 0x1 => self.jump_to_address(decoded),
                 0x2 => self.call_subroutine(decoded),
                 0x3 => self.skip_next_instruction_if_equal(decoded),
                 0x4 => self.skip_next_instruction_if_not_equal(decoded),
+                0x5 if self.platform == Platform::Chip8X && decoded.n == 1 => {
+                    self.add_nibbles(decoded)
+                }
                 0x5 => self.skip_next_instruction_if_equal_register(decoded),
                 0x6 => self.set_register(decoded),
                 0x7 => self.add_to_register(decoded),
                 0x8 => self.apply_op(decoded),
                 0x9 => self.skip_next_instruction_if_not_equal_register(decoded),
                 0xA => self.set_memory_addr(decoded),
+                0xB if self.platform == Platform::Chip8X => self.set_colour(decoded),
                 0xB => self.jump_to_address_with_offset(decoded),
                 0xC => self.rnd_and(decoded),
                 0xD => self.disp_sprite(decoded),
@@ -140,12 +519,18 @@ impl CPU {
         self.reg.sp += 1;
         self.stack.push(self.reg.pc).unwrap();
         self.reg.pc = decoded.nnn;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter_subroutine(decoded.nnn);
+        }
     }
 
     fn return_from_subroutine(&mut self) {
         //println!("RET");
         self.reg.pc = self.stack.pop().unwrap();
         self.reg.sp -= 1;
+        if let Some(profiler) = &mut self.profiler {
+            profiler.leave_subroutine();
+        }
     }
 
     fn skip_next_instruction_if_equal(&mut self, decoded: Decoded) {
@@ -222,24 +607,27 @@ impl CPU {
         //println!("OR V{:x} V{:x}", decoded.x, decoded.y);
         self.reg.v[decoded.x as usize] =
             self.reg.v[decoded.x as usize] | self.reg.v[decoded.y as usize];
-        // quirk
-        self.reg.v[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.reg.v[0xF] = 0;
+        }
     }
 
     fn and_registers(&mut self, decoded: Decoded) {
         //println!("AND V{:x} V{:x}", decoded.x, decoded.y);
         self.reg.v[decoded.x as usize] =
             self.reg.v[decoded.x as usize] & self.reg.v[decoded.y as usize];
-        // quirk
-        self.reg.v[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.reg.v[0xF] = 0;
+        }
     }
 
     fn xor_registers(&mut self, decoded: Decoded) {
         //println!("XOR V{:x} V{:x}", decoded.x, decoded.y);
         self.reg.v[decoded.x as usize] =
             self.reg.v[decoded.x as usize] ^ self.reg.v[decoded.y as usize];
-        // quirk
-        self.reg.v[0xF] = 0;
+        if self.quirks.vf_reset {
+            self.reg.v[0xF] = 0;
+        }
     }
 
     fn add_registers(&mut self, decoded: Decoded) {
@@ -269,8 +657,9 @@ impl CPU {
 
     fn shift_registers_right(&mut self, decoded: Decoded) {
         //println!("SHR V{:x} V{:x}", decoded.x, decoded.y);
-        // quirk
-        self.reg.v[decoded.x as usize] = self.reg.v[decoded.y as usize];
+        if !self.quirks.shifting {
+            self.reg.v[decoded.x as usize] = self.reg.v[decoded.y as usize];
+        }
         let a = self.reg.v[decoded.x as usize];
         self.reg.v[decoded.x as usize] = self.reg.v[decoded.x as usize] >> 1;
         if a & 0x1 == 1 {
@@ -282,8 +671,9 @@ impl CPU {
 
     fn shift_registers_left(&mut self, decoded: Decoded) {
         //println!("SHL V{:x}", decoded.x);
-        // quirk
-        self.reg.v[decoded.x as usize] = self.reg.v[decoded.y as usize];
+        if !self.quirks.shifting {
+            self.reg.v[decoded.x as usize] = self.reg.v[decoded.y as usize];
+        }
         let a = self.reg.v[decoded.x as usize];
         self.reg.v[decoded.x as usize] = self.reg.v[decoded.x as usize] << 1;
         if a & 0x80 == 0x80 {
@@ -308,17 +698,22 @@ impl CPU {
 
     fn set_memory_addr(&mut self, decoded: Decoded) {
         //println!("SET I {:x}", decoded.nnn);
-        self.reg.i = decoded.nnn;
+        self.reg.i = decoded.nnn as u32;
     }
 
     fn jump_to_address_with_offset(&mut self, decoded: Decoded) {
         //println!("JP V{:x} {:x}", 0, decoded.nnn);
-        self.reg.pc = decoded.nnn + (self.reg.v[0] as u16);
+        let offset = if self.quirks.jumping {
+            self.reg.v[decoded.x as usize]
+        } else {
+            self.reg.v[0]
+        };
+        self.reg.pc = decoded.nnn + (offset as u16);
     }
 
     fn rnd_and(&mut self, decoded: Decoded) {
         //println!("RND V{:x} {:x}", decoded.x, decoded.nn);
-        self.reg.v[decoded.x as usize] = rand::random::<u8>() & decoded.nn;
+        self.reg.v[decoded.x as usize] = self.rng.gen::<u8>() & decoded.nn;
     }
 
     fn misc_op(&mut self, decoded: Decoded) {
@@ -329,13 +724,53 @@ impl CPU {
             0x18 => self.ld_sound_timer_register(decoded),
             0x1E => self.add_i_register(decoded),
             0x29 => self.ld_font_char(decoded),
+            0x30 => self.ld_big_font_char(decoded),
             0x33 => self.ld_bcd_register(decoded),
             0x55 => self.sv_registers_to_mem(decoded),
             0x65 => self.ld_registers_from_mem(decoded),
+            0x75 => self.sv_registers_to_flags(decoded),
+            0x85 => self.ld_registers_from_flags(decoded),
+            0xF8 if self.platform == Platform::Chip8X => {
+                self.tone_port = self.reg.v[decoded.x as usize]
+            }
+            // Nothing is connected to the CHIP-8X input port, so it reads as 0
+            0xFB if self.platform == Platform::Chip8X => self.reg.v[decoded.x as usize] = 0,
             _ => panic!("Unknown misc op {:x}", decoded.nn),
         }
     }
 
+    fn add_nibbles(&mut self, decoded: Decoded) {
+        let (x, y) = (decoded.x as usize, decoded.y as usize);
+        self.reg.v[x] = chip8x::add_nibbles(self.reg.v[x], self.reg.v[y]);
+    }
+
+    // BXY0 colours zones of 8x4 pixels, BXYN N rows of one zone column. The position is in VX
+    // and VX+1, the colour in VY.
+    fn set_colour(&mut self, decoded: Decoded) {
+        let columns = self.reg.v[decoded.x as usize];
+        let rows = self.reg.v[(decoded.x as usize + 1) % 16];
+        let colour = self.reg.v[decoded.y as usize];
+        if let Some(colours) = self.display.colours.borrow_mut().as_mut() {
+            match decoded.n {
+                0 => colours.fill_zones(columns, rows, colour),
+                n => colours.fill_rows(columns, rows, n, colour),
+            }
+        }
+    }
+
+    // Registers past the platform's number of flags are ignored
+    fn sv_registers_to_flags(&mut self, decoded: Decoded) {
+        let count = (decoded.x as usize + 1).min(self.platform.flag_registers());
+        if let Err(e) = self.flags.store(&self.reg.v[..count]) {
+            eprintln!("Could not save flags: {}", e);
+        }
+    }
+
+    fn ld_registers_from_flags(&mut self, decoded: Decoded) {
+        let count = (decoded.x as usize + 1).min(self.platform.flag_registers());
+        self.reg.v[..count].copy_from_slice(&self.flags.values()[..count]);
+    }
+
     fn ld_delay_timer(&mut self, decoded: Decoded) {
         //println!("LD V{:x} DT", decoded.x);
         self.reg.v[decoded.x as usize] = self.reg.delay_timer;
@@ -353,7 +788,7 @@ impl CPU {
 
     fn add_i_register(&mut self, decoded: Decoded) {
         //println!("ADD I V{:x}", decoded.x);
-        self.reg.i += self.reg.v[decoded.x as usize] as u16;
+        self.reg.i += self.reg.v[decoded.x as usize] as u32;
     }
 
     fn ld_bcd_register(&mut self, decoded: Decoded) {
@@ -377,8 +812,9 @@ impl CPU {
                 .write(self.reg.i as usize + i as usize, self.reg.v[i as usize])
                 .unwrap();
         }
-        // quirk
-        self.reg.i += decoded.x as u16 + 1;
+        if self.quirks.memory {
+            self.reg.i += decoded.x as u32 + 1;
+        }
     }
 
     fn ld_registers_from_mem(&mut self, decoded: Decoded) {
@@ -386,47 +822,145 @@ impl CPU {
         for i in 0..decoded.x + 1 {
             self.reg.v[i as usize] = self.memory.read(self.reg.i as usize + i as usize).unwrap();
         }
-        // quirk
-        self.reg.i += decoded.x as u16 + 1;
+        if self.quirks.memory {
+            self.reg.i += decoded.x as u32 + 1;
+        }
     }
 
-    fn clear_screen(&mut self) {
-        //println!("CLS");
-        {
-            let mut mat = self.display.pixel_mat.borrow_mut();
-            for i in 0..32 {
-                for j in 0..64 {
-                    mat[i][j] = false;
+    // 0nnn ran 1802 machine code on the original interpreters
+    fn call_machine_code(&mut self, decoded: Decoded) {
+        match self.machine_code.dispatch(decoded.nnn) {
+            Dispatch::Run(routine) => self.run_routine(routine),
+            Dispatch::Skip => {}
+            Dispatch::Halt => self.halt(StopReason::MachineCode {
+                address: decoded.nnn,
+            }),
+        }
+    }
+
+    // Stops on an instruction that cannot be executed
+    fn halt(&mut self, reason: StopReason) {
+        // Stays on the instruction, so the debugger shows where the program stopped
+        self.reg.pc -= 2;
+        if self.debugger.is_none() {
+            eprintln!("Stopped: {}", reason);
+        }
+        let debugger = self.debugger.get_or_insert_with(Debugger::default);
+        debugger.stop(reason, &self.reg);
+    }
+
+    // MegaChip's additions to the 0nnn space, all but 0011 only in MegaChip mode. Returns
+    // whether the opcode was one of them.
+    fn megachip_op(&mut self, opcode: u16) -> bool {
+        match opcode {
+            0x0010 => {
+                self.mega = None;
+                *self.display.mega_frame.borrow_mut() = None;
+                return true;
+            }
+            0x0011 => {
+                let mega = MegaChip::default();
+                *self.display.mega_frame.borrow_mut() = Some(mega.frame().to_vec());
+                self.mega = Some(mega);
+                return true;
+            }
+            _ => {}
+        }
+        let mega = match self.mega.as_mut() {
+            Some(mega) => mega,
+            None => return false,
+        };
+        let decoded = Decoded::new(opcode);
+        let (n, nn) = (decoded.n, decoded.nn);
+        match decoded.nnn >> 8 {
+            0x0 => match nn {
+                0xB0..=0xBF => mega.scroll(0, -(n as isize)),
+                0xC0..=0xCF => mega.scroll(0, n as isize),
+                0xFB => mega.scroll(4, 0),
+                0xFC => mega.scroll(-4, 0),
+                _ => return false,
+            },
+            // 01nn nnnn: I gets 24 bits, the low 16 from the next word
+            0x1 => {
+                let low = self.memory.fetch(self.reg.pc as usize).unwrap_or(0);
+                self.reg.i = (nn as u32) << 16 | low as u32;
+                self.reg.pc += 2;
+            }
+            0x2 => mega.load_palette(&self.memory.cart, self.reg.i as usize, nn),
+            0x3 => mega.sprite_width = if nn == 0 { 256 } else { nn as usize },
+            0x4 => mega.sprite_height = if nn == 0 { 256 } else { nn as usize },
+            0x5 => mega.set_screen_alpha(nn),
+            // 060n plays the sound at I, looping when n is 0
+            0x6 => {
+                let sound = Sound::parse(&self.memory.cart, self.reg.i as usize, n == 0);
+                if let Some(sound) = sound {
+                    self.sound_command = Some(SoundCommand::Play(sound));
+                }
+            }
+            0x7 if nn == 0 => self.sound_command = Some(SoundCommand::Stop),
+            0x8 => mega.blend = BlendMode::from_code(n).unwrap_or(BlendMode::Normal),
+            0x9 => mega.collision_colour = nn,
+            _ => return false,
+        }
+        true
+    }
+
+    fn run_routine(&mut self, routine: Routine) {
+        match routine {
+            Routine::Clear => self.clear_screen(),
+            Routine::CycleBackground => {
+                if let Some(colours) = self.display.colours.borrow_mut().as_mut() {
+                    colours.cycle_background();
                 }
             }
         }
     }
 
+    fn clear_screen(&mut self) {
+        //println!("CLS");
+        // MegaChip shows what was drawn since the last 00E0
+        if let Some(mega) = &mut self.mega {
+            mega.present();
+            *self.display.mega_frame.borrow_mut() = Some(mega.frame().to_vec());
+            return;
+        }
+        for row in self.display.pixel_mat.borrow_mut().iter_mut() {
+            row.fill(false);
+        }
+    }
+
     fn disp_sprite(&mut self, decoded: Decoded) {
         //println!("DRW V{:x} V{:x} {}", decoded.x, decoded.y, decoded.n);
+        self.waiting_for_frame = self.quirks.display_wait;
+        if let Some(mega) = &mut self.mega {
+            let x = self.reg.v[decoded.x as usize] as usize;
+            let y = self.reg.v[decoded.y as usize] as usize;
+            let collision = mega.draw_sprite(&self.memory.cart, self.reg.i as usize, x, y);
+            self.reg.v[0xF] = collision as u8;
+            return;
+        }
         let mut collision = false;
-        let start_x = self.reg.v[decoded.x as usize] % 64;
-        let start_y = self.reg.v[decoded.y as usize] % 32;
         {
             let mut mat = self.display.pixel_mat.borrow_mut();
-            for y in start_y..cmp::min(start_y + decoded.n, 32) {
-                let mut mask = self
-                    .memory
-                    .read(self.reg.i as usize + y as usize - start_y as usize)
-                    .unwrap();
-                let mut rev_mask = 0;
-                for _ in 0..8 {
-                    rev_mask = (rev_mask << 1) | (mask & 0x1);
-                    mask = mask >> 1;
+            let (width, height) = (mat[0].len(), mat.len());
+            let start_x = self.reg.v[decoded.x as usize] as usize % width;
+            let start_y = self.reg.v[decoded.y as usize] as usize % height;
+            for row in 0..decoded.n as usize {
+                let y = start_y + row;
+                if y >= height && self.quirks.clipping {
+                    break;
                 }
-                mask = rev_mask;
-                for x in start_x..cmp::min(start_x + 8, 64) {
-                    let pixel = mat[y as usize][x as usize];
-                    if pixel && ((mask & 0x1) != 0) {
+                let byte = self.memory.read(self.reg.i as usize + row).unwrap();
+                for (col, sprite_pixel) in row_pixels(byte).into_iter().enumerate() {
+                    let x = start_x + col;
+                    if x >= width && self.quirks.clipping {
+                        break;
+                    }
+                    let pixel = &mut mat[y % height][x % width];
+                    if *pixel && sprite_pixel {
                         collision = true;
                     }
-                    mat[y as usize][x as usize] = pixel ^ ((mask & 0x1) != 0);
-                    mask = mask >> 1;
+                    *pixel ^= sprite_pixel;
                 }
             }
         }
@@ -435,12 +969,21 @@ impl CPU {
         } else {
             self.reg.v[0xF] = 0;
         }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record_draw();
+        }
         //self.display.redraw();
     }
 
     fn ld_font_char(&mut self, decoded: Decoded) {
         //println!("LD F V{:x}", decoded.x);
-        self.reg.i = self.reg.v[decoded.x as usize] as u16 * 5 + 0x50;
+        self.reg.i =
+            (self.reg.v[decoded.x as usize] as u16 * SMALL_GLYPH + self.font_address) as u32;
+    }
+
+    fn ld_big_font_char(&mut self, decoded: Decoded) {
+        let big_font = self.font_address + self.font.small().len() as u16;
+        self.reg.i = (self.reg.v[decoded.x as usize] as u16 * BIG_GLYPH + big_font) as u32;
     }
 
     fn ld_register_key(&mut self, decoded: Decoded) {
@@ -448,7 +991,7 @@ impl CPU {
         match self.found_key {
             // TODO make sure its the same key
             Some(_) => {
-                if let Some(k) = *self.display.last_key_up.borrow() {
+                if let Some(k) = self.input.last_key_up {
                     self.reg.v[decoded.x as usize] = k;
                     self.found_key = None;
                 } else {
@@ -456,7 +999,7 @@ impl CPU {
                 }
             }
             None => {
-                if let Some(k) = *self.display.last_key_down.borrow() {
+                if let Some(k) = self.input.last_key_down {
                     self.found_key = Some(k);
                 }
                 self.reg.pc -= 2;
@@ -468,24 +1011,48 @@ impl CPU {
         match decoded.nn {
             0x9E => self.skip_next_instruction_if_key_pressed(decoded),
             0xA1 => self.skip_next_instruction_if_key_not_pressed(decoded),
+            0xF2 if self.platform == Platform::Chip8X => self.skip_if_second_key(decoded, true),
+            0xF5 if self.platform == Platform::Chip8X => self.skip_if_second_key(decoded, false),
             _ => panic!("Unknown skip cond {:x}", decoded.nn),
         }
     }
 
+    // ExF2 and ExF5 test the second keypad of CHIP-8X
+    fn skip_if_second_key(&mut self, decoded: Decoded, pressed: bool) {
+        let key = self.reg.v[decoded.x as usize] & 0xF;
+        if self.input.second[key as usize] == pressed {
+            self.reg.pc += 2;
+        }
+    }
+
     fn skip_next_instruction_if_key_pressed(&mut self, decoded: Decoded) {
         //println!("SKP V{:x}", decoded.x);
-        if self.display.keys_pressed.borrow()[self.reg.v[decoded.x as usize] as usize] {
+        if self.input.pressed[self.reg.v[decoded.x as usize] as usize] {
             self.reg.pc += 2;
         }
     }
 
     fn skip_next_instruction_if_key_not_pressed(&mut self, decoded: Decoded) {
         //println!("SKNP V{:x}", decoded.x);
-        if !self.display.keys_pressed.borrow()[self.reg.v[decoded.x as usize] as usize] {
+        if !self.input.pressed[self.reg.v[decoded.x as usize] as usize] {
             self.reg.pc += 2;
         }
     }
     pub fn update_timers(&mut self) {
+        // Ticks that happened during the recorded history are replayed from the log
+        if self.is_replaying() {
+            return;
+        }
+        if let Some(history) = &mut self.history {
+            history.record_tick(self.cycles);
+        }
+        self.tick_timers();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.end_frame();
+        }
+    }
+    fn tick_timers(&mut self) {
+        self.waiting_for_frame = false;
         if self.reg.delay_timer > 0 {
             self.reg.delay_timer -= 1;
         }
@@ -493,13 +1060,32 @@ impl CPU {
             self.reg.sound_time -= 1;
         }
     }
+    /// Whether a draw is stalling the CPU until the next frame, the rest of the frame's
+    /// instructions can be skipped
+    pub fn waiting_for_frame(&self) -> bool {
+        self.waiting_for_frame
+    }
     pub fn should_beep(&self) -> bool {
         self.reg.sound_time > 0
     }
+    /// Takes the MegaChip sound to start or stop, if there is a new one
+    pub fn take_sound_command(&mut self) -> Option<SoundCommand> {
+        self.sound_command.take()
+    }
+    /// Pitch of the beep, CHIP-8X sets it with FxF8
+    pub fn tone_frequency(&self) -> f32 {
+        match self.platform {
+            Platform::Chip8X => chip8x::tone_frequency(self.tone_port),
+            _ => 440.0,
+        }
+    }
 }
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::debugger::{BreakKind, WatchMode};
+    use crate::machine_code::MachineCodeMode;
+    use crate::megachip;
     #[test]
     fn test_fetch() {
         let mut cpu = CPU::default();
@@ -520,6 +1106,132 @@ mod tests {
         assert_eq!(decoded.upper, 0x1);
     }
 
+    #[test]
+    fn test_profiler_hooks() {
+        let mut cpu = CPU::default();
+        cpu.profiler = Some(Profiler::default());
+        // CALL 0x300, then RET from 0x300
+        cpu.memory.cart[0x200] = 0x23;
+        cpu.memory.cart[0x201] = 0x00;
+        cpu.memory.cart[0x300] = 0x00;
+        cpu.memory.cart[0x301] = 0xEE;
+        cpu.run();
+        cpu.run();
+        let report = cpu.profiler.as_ref().unwrap().json_report();
+        assert_eq!(report["instructions"], 2);
+        assert_eq!(report["subroutines"][0]["address"], 0x300);
+        assert_eq!(report["subroutines"][0]["calls"], 1);
+        assert_eq!(cpu.reg.pc, 0x202);
+    }
+
+    #[test]
+    fn test_debugger_watchpoint() {
+        let mut cpu = CPU::default();
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(
+            BreakKind::Memory {
+                start: 0x301,
+                end: 0x301,
+                mode: WatchMode::Write,
+            },
+            None,
+        );
+        cpu.debugger = Some(debugger);
+        // LD I, 0x300; LD V0, 123; LD B, V0
+        let program = [0xA3, 0x00, 0x60, 0x7B, 0xF0, 0x33];
+        cpu.memory.cart[0x200..0x206].copy_from_slice(&program);
+        for _ in 0..4 {
+            cpu.run();
+        }
+        // Stopped right after the BCD instruction wrote the tens digit
+        assert!(cpu.is_paused());
+        assert_eq!(cpu.reg.pc, 0x206);
+        assert_eq!(cpu.memory.cart[0x301], 2);
+    }
+
+    #[test]
+    fn test_display_wait() {
+        let mut cpu = CPU::default();
+        assert!(!cpu.quirks.display_wait);
+        cpu.quirks.display_wait = true;
+        // DRW V0, V0, 1; ADD V1, 1
+        let program = [0xD0, 0x01, 0x71, 0x01];
+        cpu.memory.cart[0x200..0x204].copy_from_slice(&program);
+        cpu.run();
+        assert!(cpu.waiting_for_frame());
+        cpu.run();
+        assert_eq!(cpu.reg.pc, 0x202);
+        cpu.update_timers();
+        cpu.run();
+        assert_eq!(cpu.reg.v[0x1], 1);
+
+        cpu.quirks.display_wait = false;
+        cpu.reg.pc = 0x200;
+        cpu.run();
+        assert!(!cpu.waiting_for_frame());
+        cpu.run();
+        assert_eq!(cpu.reg.v[0x1], 2);
+    }
+
+    fn load_rnd_loop(cpu: &mut CPU) {
+        // RND V0, 0xFF; LD DT, V0; ADD V1, 1; JP 0x200
+        let program = [0xC0, 0xFF, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x00];
+        cpu.memory.cart[0x200..0x208].copy_from_slice(&program);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut cpu = CPU::default();
+        cpu.history = Some(History::default());
+        load_rnd_loop(&mut cpu);
+        let mut states = Vec::new();
+        for cycle in 0..2500 {
+            if cycle % 3 == 0 {
+                cpu.update_timers();
+            }
+            states.push(cpu.reg.clone());
+            cpu.run();
+        }
+        for target in [2400, 1500, 999, 1000, 1, 2499] {
+            cpu.rewind_to(target).unwrap();
+            assert_eq!(cpu.cycles, target);
+            assert_eq!(cpu.reg, states[target as usize]);
+        }
+        // Running forward again replays the recorded history
+        cpu.rewind_to(10).unwrap();
+        cpu.update_timers();
+        cpu.run();
+        assert_eq!(cpu.reg, states[11]);
+        assert!(cpu.rewind_to(2501).is_err());
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut cpu = CPU::default();
+        cpu.history = Some(History::default());
+        cpu.debugger = Some(Debugger::default());
+        load_rnd_loop(&mut cpu);
+        for _ in 0..40 {
+            cpu.run();
+        }
+        cpu.step_back(3).unwrap();
+        assert_eq!(cpu.cycles, 37);
+        assert_eq!(cpu.reg.pc, 0x202);
+
+        let debugger = cpu.debugger.as_mut().unwrap();
+        let id = debugger.add_breakpoint(BreakKind::Pc(0x204), None);
+        let reason = cpu.reverse_continue().unwrap();
+        assert_eq!(reason, StopReason::Breakpoint { id, pc: 0x204 });
+        assert_eq!(cpu.cycles, 34);
+        assert!(cpu.is_paused());
+        // Only the stop counts, not the hits passed over while searching backwards
+        assert_eq!(cpu.debugger.as_ref().unwrap().breakpoints()[0].hits, 1);
+
+        cpu.debugger.as_mut().unwrap().remove_breakpoint(id);
+        assert_eq!(cpu.reverse_continue().unwrap(), StopReason::HistoryStart);
+        assert_eq!(cpu.cycles, 0);
+    }
+
     #[test]
     fn test_jump_to_address() {
         let mut cpu = CPU::default();
@@ -702,4 +1414,254 @@ mod tests {
             assert_eq!(mat[1][0], false);
         }
     }
+
+    #[test]
+    fn test_quirks() {
+        let mut cpu = CPU::default();
+        cpu.quirks = Platform::SuperChip.quirks();
+        cpu.reg.v[0x1] = 0b0000_0011;
+        cpu.reg.v[0x2] = 0b1000_0000;
+        cpu.reg.v[0xF] = 7;
+        cpu.apply_op(Decoded::new(0x8121)); // OR, no VF reset
+        assert_eq!(cpu.reg.v[0xF], 7);
+        cpu.apply_op(Decoded::new(0x8126)); // SHR shifts V1 in place
+        assert_eq!(cpu.reg.v[0x1], 0b0100_0001);
+        assert_eq!(cpu.reg.v[0xF], 1);
+
+        cpu.reg.i = 0x300;
+        cpu.sv_registers_to_mem(Decoded::new(0xF255));
+        assert_eq!(cpu.reg.i, 0x300);
+
+        cpu.reg.v[0x0] = 0x10;
+        cpu.reg.v[0x3] = 0x20;
+        cpu.jump_to_address_with_offset(Decoded::new(0xB340));
+        assert_eq!(cpu.reg.pc, 0x360);
+
+        // Without clipping the sprite wraps to the other side
+        cpu.quirks.clipping = false;
+        cpu.memory.cart[0x300] = 0b1100_0000;
+        cpu.memory.cart[0x301] = 0b1100_0000;
+        cpu.reg.i = 0x300;
+        cpu.reg.v[0x0] = 63;
+        cpu.reg.v[0x1] = 31;
+        cpu.disp_sprite(Decoded::new(0xD012));
+        let mat = cpu.display.pixel_mat.borrow();
+        assert!(mat[31][63] && mat[31][0] && mat[0][63] && mat[0][0]);
+    }
+
+    #[test]
+    fn test_rpl_flags() {
+        let mut cpu = CPU::default();
+        cpu.platform = Platform::SuperChip;
+        for x in 0..16 {
+            cpu.reg.v[x] = x as u8 + 1;
+        }
+        // SUPER-CHIP has 8 flags, the rest of the registers are not saved
+        cpu.misc_op(Decoded::new(0xF975));
+        cpu.reg.v = [0; 16];
+        cpu.misc_op(Decoded::new(0xF985));
+        assert_eq!(cpu.reg.v[..9], [1, 2, 3, 4, 5, 6, 7, 8, 0]);
+
+        cpu.platform = Platform::XoChip;
+        cpu.reg.v[0xF] = 0x42;
+        cpu.misc_op(Decoded::new(0xFF75));
+        cpu.reg.v = [0; 16];
+        cpu.misc_op(Decoded::new(0xFF85));
+        assert_eq!(cpu.reg.v[0xF], 0x42);
+    }
+
+    #[test]
+    fn test_machine_code() {
+        let mut cpu = CPU::default();
+        cpu.display.pixel_mat.borrow_mut()[0][0] = true;
+        cpu.machine_code.register(0x230, Routine::Clear);
+        cpu.execute(0x0230);
+        assert!(!cpu.display.pixel_mat.borrow()[0][0]);
+        assert_eq!(cpu.reg.pc, 0x202);
+
+        // Unknown routines stop on the call
+        cpu.execute(0x0123);
+        assert_eq!(cpu.reg.pc, 0x202);
+        assert!(cpu.is_paused());
+
+        cpu.machine_code.mode = MachineCodeMode::Ignore;
+        cpu.execute(0x0123);
+        assert_eq!(cpu.reg.pc, 0x204);
+
+        // SCHIP instructions are not taken for machine code, even when that is ignored
+        cpu.set_paused(false);
+        cpu.execute(0x00FF);
+        assert_eq!(cpu.reg.pc, 0x204);
+        assert!(cpu.is_paused());
+        let reason = StopReason::Unsupported {
+            address: 0x204,
+            opcode: 0x00FF,
+        };
+        assert_eq!(reason.to_string(), "unsupported instruction 00FF at 0x204");
+    }
+
+    #[test]
+    fn test_chip8x() {
+        let mut cpu = CPU::default();
+        let mut info = RomInfo::unknown(Platform::Chip8X);
+        info.font = FontSet::Vip;
+        cpu.apply_rom_info(&info);
+        cpu.reg.v[0x1] = 0x10;
+        cpu.reg.v[0x2] = 0x01;
+        cpu.reg.v[0x3] = 0x4;
+        cpu.execute(0xB130);
+        cpu.execute(0x02A0);
+        {
+            let colours = cpu.display.colours.borrow();
+            let colours = colours.as_ref().unwrap();
+            assert_eq!(colours.foreground(15, 4), chip8x::COLOURS[4]);
+            assert_eq!(colours.foreground(16, 4), chip8x::COLOURS[1]);
+            assert_eq!(colours.background(), chip8x::COLOURS[0]);
+        }
+
+        cpu.execute(0x5121);
+        assert_eq!(cpu.reg.v[0x1], 0x11);
+        cpu.input.second[0x4] = true;
+        cpu.execute(0xE3F2);
+        assert_eq!(cpu.reg.pc, 0x20A);
+        cpu.execute(0xE3F5);
+        assert_eq!(cpu.reg.pc, 0x20C);
+        cpu.execute(0xF3F8);
+        assert_eq!(cpu.tone_frequency(), 5507.0);
+
+        // Saved states keep the colours and the tone
+        let path = std::env::temp_dir().join("chip8_chip8x_test.c8s");
+        let path = path.to_str().unwrap();
+        cpu.snapshot().save(path).unwrap();
+        let snapshot = Snapshot::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        cpu.apply_rom_info(&info);
+        cpu.execute(0xF2F8);
+        cpu.restore(&snapshot);
+        assert_eq!(cpu.tone_frequency(), 5507.0);
+        let colours = cpu.display.colours.borrow();
+        let colours = colours.as_ref().unwrap();
+        assert_eq!(colours.foreground(15, 4), chip8x::COLOURS[4]);
+        assert_eq!(colours.background(), chip8x::COLOURS[0]);
+    }
+
+    #[test]
+    fn test_resolutions() {
+        let mut cpu = CPU::default();
+        cpu.apply_rom_info(&RomInfo::unknown(Platform::Eti660));
+        cpu.memory.cart[0x300] = 0xFF;
+        cpu.reg.i = 0x300;
+        // Row 40 is on screen on the ETI-660, which wraps at 48 instead of 32
+        cpu.reg.v[0x0] = 60;
+        cpu.reg.v[0x1] = 40 + 48;
+        cpu.execute(0xD011);
+        {
+            let mat = cpu.display.pixel_mat.borrow();
+            assert_eq!(mat.len(), 48);
+            assert!(mat[40][60..64].iter().all(|p| *p));
+            assert!(!mat[40][0]);
+        }
+
+        cpu.apply_rom_info(&RomInfo::unknown(Platform::TwoPage));
+        cpu.reg.v[0x1] = 63;
+        cpu.execute(0xD011);
+        assert!(cpu.display.pixel_mat.borrow()[63][63]);
+        cpu.execute(0x0230);
+        assert!(cpu.display.pixel_mat.borrow().iter().flatten().all(|p| !*p));
+        assert_eq!(cpu.reg.pc, 0x206);
+    }
+
+    #[test]
+    fn test_megachip() {
+        let mut cpu = CPU::default();
+        cpu.apply_rom_info(&RomInfo::unknown(Platform::MegaChip));
+        // Not in MegaChip mode yet, so 0300 is a machine-code call
+        cpu.machine_code.mode = MachineCodeMode::Ignore;
+        cpu.execute(0x0301);
+        assert!(cpu.mega.is_none());
+        cpu.execute(0x0011);
+        assert!(cpu.display.mega_frame.borrow().is_some());
+
+        // Palette of one opaque green at 0x300, a 1x1 sprite of it at 0x304
+        cpu.memory.cart[0x300..0x305].copy_from_slice(&[0xFF, 0x00, 0xFF, 0x00, 0x01]);
+        cpu.memory.cart[0x206..0x208].copy_from_slice(&[0x03, 0x00]);
+        cpu.reg.pc = 0x204;
+        cpu.execute(0x0100);
+        assert_eq!(cpu.reg.i, 0x300);
+        assert_eq!(cpu.reg.pc, 0x208);
+        cpu.execute(0x0201);
+        cpu.execute(0x0301);
+        cpu.execute(0x0401);
+        cpu.execute(0x0901);
+        cpu.reg.i = 0x304;
+        cpu.reg.v[0x1] = 200;
+        cpu.reg.v[0x2] = 100;
+        cpu.execute(0xD120);
+        assert_eq!(cpu.reg.v[0xF], 0);
+        cpu.execute(0xD120);
+        assert_eq!(cpu.reg.v[0xF], 1);
+        cpu.execute(0x00FB);
+        cpu.execute(0x00E0);
+        {
+            let frame = cpu.display.mega_frame.borrow();
+            let pixel = (100 * megachip::WIDTH + 204) * 3;
+            assert_eq!(
+                frame.as_ref().unwrap()[pixel..pixel + 3],
+                [0x00, 0xFF, 0x00]
+            );
+        }
+
+        // A sound of two samples at 0x310, played once
+        cpu.memory.cart[0x310..0x318].copy_from_slice(&[0x1F, 0x40, 0, 0, 2, 0, 0x80, 0x90]);
+        cpu.reg.i = 0x310;
+        cpu.execute(0x0601);
+        match cpu.take_sound_command() {
+            Some(SoundCommand::Play(sound)) => assert!(!sound.looping),
+            command => panic!("Expected a sound, got {:?}", command),
+        }
+        cpu.execute(0x0700);
+        assert_eq!(cpu.take_sound_command(), Some(SoundCommand::Stop));
+        assert_eq!(cpu.take_sound_command(), None);
+
+        // Saved states keep MegaChip mode with its frame, palette and sprite settings
+        let path = std::env::temp_dir().join("chip8_megachip_test.c8s");
+        let path = path.to_str().unwrap();
+        cpu.snapshot().save(path).unwrap();
+        let snapshot = Snapshot::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        cpu.execute(0x0010);
+        assert!(cpu.display.mega_frame.borrow().is_none());
+        cpu.restore(&snapshot);
+        {
+            let frame = cpu.display.mega_frame.borrow();
+            let pixel = (100 * megachip::WIDTH + 204) * 3;
+            assert_eq!(
+                frame.as_ref().unwrap()[pixel..pixel + 3],
+                [0x00, 0xFF, 0x00]
+            );
+        }
+        cpu.reg.i = 0x304;
+        cpu.execute(0xD120);
+        cpu.execute(0xD120);
+        assert_eq!(cpu.reg.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_font_sets() {
+        let mut cpu = CPU::default();
+        assert_eq!(cpu.memory.cart[0x50..0x55], [0xF0, 0x90, 0x90, 0x90, 0xF0]);
+        cpu.set_font(FontSet::Vip, 0x100);
+        assert_eq!(cpu.memory.cart[0x50..0x55], [0; 5]);
+
+        cpu.reg.v[0x3] = 0x1;
+        cpu.misc_op(Decoded::new(0xF329));
+        assert_eq!(cpu.reg.i, 0x105);
+        assert_eq!(
+            cpu.memory.cart[0x105..0x10A],
+            [0x60, 0x20, 0x20, 0x20, 0x70]
+        );
+        cpu.misc_op(Decoded::new(0xF330));
+        assert_eq!(cpu.reg.i, 0x100 + 80 + 10);
+        assert_eq!(cpu.memory.cart[cpu.reg.i as usize], 0x18);
+    }
 }
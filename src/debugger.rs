@@ -0,0 +1,558 @@
+use crate::expr::Expr;
+use crate::ram::{Access, AccessKind};
+use crate::register::{Reg, RegName};
+use crate::symbols::SymbolMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchMode {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl WatchMode {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "r" | "read" => Some(WatchMode::Read),
+            "w" | "write" => Some(WatchMode::Write),
+            "rw" | "access" => Some(WatchMode::ReadWrite),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, kind: AccessKind) -> bool {
+        match self {
+            WatchMode::Read => kind == AccessKind::Read,
+            WatchMode::Write => kind == AccessKind::Write,
+            WatchMode::ReadWrite => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakKind {
+    // Stops before the instruction at this address is executed
+    Pc(u16),
+    // Stops after an instruction touched memory in start..=end
+    Memory {
+        start: usize,
+        end: usize,
+        mode: WatchMode,
+    },
+    // Stops after an instruction changed the register
+    Register(RegName),
+    // Stops when the breakpoint condition becomes true
+    Condition,
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub kind: BreakKind,
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+    pub hits: u64,
+    // Last value of the condition, so `BreakKind::Condition` only fires on a false -> true edge
+    was_true: bool,
+}
+
+impl Breakpoint {
+    fn condition_holds(&self, reg: &Reg, memory: &[u8]) -> bool {
+        match &self.condition {
+            Some(condition) => condition.is_true(reg, memory),
+            None => true,
+        }
+    }
+
+    // Whether the last instruction triggered the breakpoint, given the value of the condition
+    // before it. Also returns the value of the condition now.
+    fn hit_after(
+        &self,
+        before: &Reg,
+        reg: &Reg,
+        memory: &[u8],
+        accesses: &[Access],
+        was_true: bool,
+    ) -> (Option<StopReason>, bool) {
+        match self.kind {
+            BreakKind::Pc(_) => (None, was_true),
+            BreakKind::Memory { start, end, mode } => {
+                let hit = accesses
+                    .iter()
+                    .find(|a| a.address >= start && a.address <= end && mode.matches(a.kind))
+                    .filter(|_| self.condition_holds(reg, memory))
+                    .map(|access| StopReason::Watchpoint {
+                        id: self.id,
+                        access: *access,
+                    });
+                (hit, was_true)
+            }
+            BreakKind::Register(name) => {
+                let (old, new) = (name.read(before), name.read(reg));
+                if old != new && self.condition_holds(reg, memory) {
+                    let hit = StopReason::RegisterChanged {
+                        id: self.id,
+                        reg: name,
+                        old,
+                        new,
+                    };
+                    (Some(hit), was_true)
+                } else {
+                    (None, was_true)
+                }
+            }
+            BreakKind::Condition => {
+                let is_true = self.condition_holds(reg, memory);
+                if is_true && !was_true {
+                    (Some(StopReason::Condition { id: self.id }), is_true)
+                } else {
+                    (None, is_true)
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} ", self.id)?;
+        match &self.kind {
+            BreakKind::Pc(address) => write!(f, "break {:#05x}", address)?,
+            BreakKind::Memory { start, end, mode } => {
+                write!(f, "watch {:#05x}", start)?;
+                if end != start {
+                    write!(f, "-{:#05x}", end)?;
+                }
+                write!(f, " {:?}", mode)?;
+            }
+            BreakKind::Register(reg) => write!(f, "watch {}", reg.name())?,
+            BreakKind::Condition => write!(f, "condition")?,
+        }
+        if let Some(condition) = &self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        write!(f, ", {} hits", self.hits)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Breakpoint {
+        id: usize,
+        pc: u16,
+    },
+    Watchpoint {
+        id: usize,
+        access: Access,
+    },
+    RegisterChanged {
+        id: usize,
+        reg: RegName,
+        old: u32,
+        new: u32,
+    },
+    Condition {
+        id: usize,
+    },
+    Step,
+    Pause,
+    HistoryStart,
+    // A 0nnn call that cannot be handled without an 1802
+    MachineCode {
+        address: u16,
+    },
+    // An instruction of another platform that is not emulated, like SCHIP scrolling
+    Unsupported {
+        address: u16,
+        opcode: u16,
+    },
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::Breakpoint { id, pc } => write!(f, "breakpoint #{} at {:#05x}", id, pc),
+            StopReason::Watchpoint { id, access } => write!(
+                f,
+                "watchpoint #{}: {:?} of {:#04x} at {:#05x}",
+                id, access.kind, access.value, access.address
+            ),
+            StopReason::RegisterChanged { id, reg, old, new } => write!(
+                f,
+                "watchpoint #{}: {} changed {:#x} -> {:#x}",
+                id,
+                reg.name(),
+                old,
+                new
+            ),
+            StopReason::Condition { id } => write!(f, "condition #{} became true", id),
+            StopReason::Step => write!(f, "step"),
+            StopReason::Pause => write!(f, "paused"),
+            StopReason::HistoryStart => write!(f, "reached the start of the recorded history"),
+            StopReason::MachineCode { address } => {
+                write!(f, "call to unsupported machine code at {:#05x}", address)
+            }
+            StopReason::Unsupported { address, opcode } => {
+                write!(
+                    f,
+                    "unsupported instruction {:04X} at {:#05x}",
+                    opcode, address
+                )
+            }
+        }
+    }
+}
+
+type StopListener = Box<dyn FnMut(&StopReason, &Reg)>;
+
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    paused: bool,
+    // Skip the PC breakpoint check once after resuming, otherwise we would stop at the same place
+    resuming: bool,
+    steps_remaining: Option<u64>,
+    listeners: Vec<StopListener>,
+    // Used to show and accept labels and source lines instead of addresses
+    pub symbols: Rc<SymbolMap>,
+}
+
+impl Debugger {
+    pub fn default() -> Self {
+        Debugger {
+            breakpoints: Vec::new(),
+            next_id: 1,
+            paused: false,
+            resuming: false,
+            steps_remaining: None,
+            listeners: Vec::new(),
+            symbols: Rc::new(SymbolMap::default()),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, kind: BreakKind, condition: Option<Expr>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint {
+            id,
+            kind,
+            condition,
+            enabled: true,
+            hits: 0,
+            was_true: false,
+        });
+        id
+    }
+
+    pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|b| b.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn set_enabled(&mut self, id: usize, enabled: bool) -> bool {
+        match self.breakpoints.iter_mut().find(|b| b.id == id) {
+            Some(breakpoint) => {
+                breakpoint.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Registers a frontend callback that is notified every time execution stops
+    pub fn on_stop<F: FnMut(&StopReason, &Reg) + 'static>(&mut self, listener: F) {
+        self.listeners.push(Box::new(listener));
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self, reg: &Reg) {
+        if !self.paused {
+            self.stop(StopReason::Pause, reg);
+        }
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        self.resuming = true;
+        self.steps_remaining = None;
+    }
+
+    pub fn step(&mut self, count: u64) {
+        self.resume();
+        self.steps_remaining = Some(count.max(1));
+    }
+
+    pub fn stop(&mut self, reason: StopReason, reg: &Reg) {
+        self.paused = true;
+        self.steps_remaining = None;
+        for listener in self.listeners.iter_mut() {
+            listener(&reason, reg);
+        }
+    }
+
+    /// Called before an instruction executes, returns false if it must not run
+    pub fn before_step(&mut self, reg: &Reg, memory: &[u8]) -> bool {
+        if self.paused {
+            return false;
+        }
+        if self.resuming {
+            self.resuming = false;
+            return true;
+        }
+        if let Some(reason) = self.check_before(reg, memory) {
+            self.stop(reason, reg);
+            return false;
+        }
+        true
+    }
+
+    /// Returns the PC breakpoint that triggers before the next instruction, without stopping
+    pub fn check_before(&mut self, reg: &Reg, memory: &[u8]) -> Option<StopReason> {
+        let reason = self.peek_before(reg, memory)?;
+        self.count_hit(&reason);
+        Some(reason)
+    }
+
+    /// Like `check_before`, but leaves the hit counts alone
+    pub fn peek_before(&self, reg: &Reg, memory: &[u8]) -> Option<StopReason> {
+        let breakpoint = self.breakpoints.iter().find(|b| {
+            b.enabled && b.kind == BreakKind::Pc(reg.pc) && b.condition_holds(reg, memory)
+        })?;
+        Some(StopReason::Breakpoint {
+            id: breakpoint.id,
+            pc: reg.pc,
+        })
+    }
+
+    /// Counts a hit of the breakpoint that caused the stop
+    pub fn count_hit(&mut self, reason: &StopReason) {
+        let id = match reason {
+            StopReason::Breakpoint { id, .. }
+            | StopReason::Watchpoint { id, .. }
+            | StopReason::RegisterChanged { id, .. }
+            | StopReason::Condition { id } => *id,
+            _ => return,
+        };
+        if let Some(breakpoint) = self.breakpoints.iter_mut().find(|b| b.id == id) {
+            breakpoint.hits += 1;
+        }
+    }
+
+    /// Called after an instruction executed with the registers from before it and the memory
+    /// accesses it made
+    pub fn after_step(&mut self, before: &Reg, reg: &Reg, memory: &[u8], accesses: &[Access]) {
+        if let Some(reason) = self.check_after(before, reg, memory, accesses) {
+            self.stop(reason, reg);
+            return;
+        }
+        if let Some(steps) = self.steps_remaining {
+            if steps <= 1 {
+                self.stop(StopReason::Step, reg);
+            } else {
+                self.steps_remaining = Some(steps - 1);
+            }
+        }
+    }
+
+    /// Returns the watchpoint or condition triggered by the last instruction, without stopping
+    pub fn check_after(
+        &mut self,
+        before: &Reg,
+        reg: &Reg,
+        memory: &[u8],
+        accesses: &[Access],
+    ) -> Option<StopReason> {
+        let mut reason = None;
+        for breakpoint in self.breakpoints.iter_mut().filter(|b| b.enabled) {
+            let (hit, is_true) =
+                breakpoint.hit_after(before, reg, memory, accesses, breakpoint.was_true);
+            breakpoint.was_true = is_true;
+            if let Some(hit) = hit {
+                breakpoint.hits += 1;
+                // Keep evaluating so every edge-triggered condition stays up to date
+                if reason.is_none() {
+                    reason = Some(hit);
+                }
+            }
+        }
+        reason
+    }
+
+    /// Value of every breakpoint condition in the given state, to start tracking the edges of
+    /// `BreakKind::Condition` breakpoints with `peek_after`
+    pub fn conditions(&self, reg: &Reg, memory: &[u8]) -> Vec<bool> {
+        self.breakpoints
+            .iter()
+            .map(|b| b.condition_holds(reg, memory))
+            .collect()
+    }
+
+    /// Like `check_after`, but leaves the hit counts alone and tracks condition edges in
+    /// `conditions` instead of the breakpoints
+    pub fn peek_after(
+        &self,
+        before: &Reg,
+        reg: &Reg,
+        memory: &[u8],
+        accesses: &[Access],
+        conditions: &mut [bool],
+    ) -> Option<StopReason> {
+        let mut reason = None;
+        for (breakpoint, was_true) in self.breakpoints.iter().zip(conditions.iter_mut()) {
+            if !breakpoint.enabled {
+                continue;
+            }
+            let (hit, is_true) = breakpoint.hit_after(before, reg, memory, accesses, *was_true);
+            *was_true = is_true;
+            if reason.is_none() {
+                reason = hit;
+            }
+        }
+        reason
+    }
+
+    /// Takes the condition edges from the given state, after execution jumped to it
+    pub fn reset_conditions(&mut self, reg: &Reg, memory: &[u8]) {
+        for breakpoint in self.breakpoints.iter_mut() {
+            breakpoint.was_true = breakpoint.condition_holds(reg, memory);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_pc_breakpoint() {
+        let mut debugger = Debugger::default();
+        let stops = Rc::new(RefCell::new(Vec::new()));
+        let stops_clone = stops.clone();
+        debugger.on_stop(move |reason, _| stops_clone.borrow_mut().push(reason.clone()));
+        let id = debugger.add_breakpoint(BreakKind::Pc(0x204), None);
+
+        let mut reg = Reg {
+            pc: 0x202,
+            ..Default::default()
+        };
+        assert!(debugger.before_step(&reg, &[]));
+        reg.pc = 0x204;
+        assert!(!debugger.before_step(&reg, &[]));
+        assert!(debugger.is_paused());
+        assert!(!debugger.before_step(&reg, &[]));
+        assert_eq!(
+            *stops.borrow(),
+            vec![StopReason::Breakpoint { id, pc: 0x204 }]
+        );
+
+        // Resuming executes the instruction under the breakpoint
+        debugger.resume();
+        assert!(debugger.before_step(&reg, &[]));
+        assert!(!debugger.before_step(&reg, &[]));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger = Debugger::default();
+        let condition = Expr::parse("v3 == 0x10 && i > 0x300").unwrap();
+        debugger.add_breakpoint(BreakKind::Pc(0x200), Some(condition));
+        let mut reg = Reg {
+            pc: 0x200,
+            i: 0x400,
+            ..Default::default()
+        };
+        assert!(debugger.before_step(&reg, &[]));
+        reg.v[3] = 0x10;
+        assert!(!debugger.before_step(&reg, &[]));
+    }
+
+    #[test]
+    fn test_memory_watchpoint() {
+        let mut debugger = Debugger::default();
+        let mode = WatchMode::Write;
+        let id = debugger.add_breakpoint(
+            BreakKind::Memory {
+                start: 0x300,
+                end: 0x30F,
+                mode,
+            },
+            None,
+        );
+        let reg = Reg::default();
+        let read = Access {
+            address: 0x305,
+            kind: AccessKind::Read,
+            value: 1,
+        };
+        debugger.after_step(&reg, &reg, &[], &[read]);
+        assert!(!debugger.is_paused());
+
+        let write = Access {
+            address: 0x30F,
+            kind: AccessKind::Write,
+            value: 7,
+        };
+        let stops = Rc::new(RefCell::new(Vec::new()));
+        let stops_clone = stops.clone();
+        debugger.on_stop(move |reason, _| stops_clone.borrow_mut().push(reason.clone()));
+        debugger.after_step(&reg, &reg, &[], &[read, write]);
+        assert!(debugger.is_paused());
+        assert_eq!(
+            *stops.borrow(),
+            vec![StopReason::Watchpoint { id, access: write }]
+        );
+    }
+
+    #[test]
+    fn test_register_watchpoint_and_condition() {
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(BreakKind::Register(RegName::V(2)), None);
+        let condition = Expr::parse("i == 5").unwrap();
+        debugger.add_breakpoint(BreakKind::Condition, Some(condition));
+
+        let before = Reg::default();
+        let mut after = Reg::default();
+        after.v[1] = 1;
+        debugger.after_step(&before, &after, &[], &[]);
+        assert!(!debugger.is_paused());
+        after.v[2] = 1;
+        debugger.after_step(&before, &after, &[], &[]);
+        assert!(debugger.is_paused());
+
+        debugger.resume();
+        after.i = 5;
+        debugger.after_step(&after, &after, &[], &[]);
+        assert!(debugger.is_paused());
+        // Conditions only fire again after becoming false first
+        debugger.resume();
+        debugger.after_step(&after, &after, &[], &[]);
+        assert!(!debugger.is_paused());
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = Debugger::default();
+        let reg = Reg::default();
+        debugger.step(2);
+        assert!(debugger.before_step(&reg, &[]));
+        debugger.after_step(&reg, &reg, &[], &[]);
+        assert!(!debugger.is_paused());
+        debugger.after_step(&reg, &reg, &[], &[]);
+        assert!(debugger.is_paused());
+    }
+}
@@ -0,0 +1,469 @@
+use crate::analysis::{instruction_length, is_skip, read_opcode};
+use crate::cfg::ControlFlowGraph;
+use crate::cpu::Decoded;
+use std::collections::BTreeSet;
+
+const INDENT: &str = "    ";
+
+fn register(n: u16) -> String {
+    format!("v{:x}", n & 0xF)
+}
+
+// Registers v0 to vx as a list, the way Fx55 and Fx65 use them
+fn registers(x: u16) -> String {
+    match x {
+        0 => "v0".to_string(),
+        x => format!("v0..{}", register(x)),
+    }
+}
+
+fn function_name(address: u16, start: u16) -> String {
+    if address == start {
+        "main".to_string()
+    } else {
+        format!("sub_{:03x}", address)
+    }
+}
+
+/// The condition under which a skip instruction skips, and its negation
+fn skip_condition(opcode: u16) -> (String, String) {
+    let d = Decoded::new(opcode);
+    let (vx, vy) = (register(d.x as u16), register(d.y as u16));
+    match (d.upper, d.nn) {
+        (0x3, nn) => (
+            format!("{} == {:#04x}", vx, nn),
+            format!("{} != {:#04x}", vx, nn),
+        ),
+        (0x4, nn) => (
+            format!("{} != {:#04x}", vx, nn),
+            format!("{} == {:#04x}", vx, nn),
+        ),
+        (0x5, _) => (format!("{} == {}", vx, vy), format!("{} != {}", vx, vy)),
+        (0x9, _) => (format!("{} != {}", vx, vy), format!("{} == {}", vx, vy)),
+        (0xE, 0x9E) => (
+            format!("key_pressed({})", vx),
+            format!("!key_pressed({})", vx),
+        ),
+        _ => (
+            format!("!key_pressed({})", vx),
+            format!("key_pressed({})", vx),
+        ),
+    }
+}
+
+// What the index register is known to point at
+#[derive(Clone, Copy, PartialEq)]
+enum Index {
+    Unknown,
+    Address(u16),
+    Font(u16),
+    BigFont(u16),
+}
+
+impl Index {
+    fn sprite(&self) -> String {
+        match self {
+            Index::Unknown => "i".to_string(),
+            Index::Address(address) => format!("sprite@{:#05x}", address),
+            Index::Font(x) => format!("font({})", register(*x)),
+            Index::BigFont(x) => format!("big_font({})", register(*x)),
+        }
+    }
+
+    fn memory(&self) -> String {
+        match self {
+            Index::Address(address) => format!("memory[{:#05x}]", address),
+            _ => "memory[i]".to_string(),
+        }
+    }
+}
+
+struct Line {
+    indent: usize,
+    // Address of the instruction the line comes from, labels are placed before it
+    address: Option<u16>,
+    text: String,
+}
+
+// Where `break` and `continue` jump to in the innermost loop
+#[derive(Clone, Copy)]
+struct Scope {
+    break_to: Option<u16>,
+    continue_to: Option<u16>,
+}
+
+/// Structured pseudocode of a ROM, one function per subroutine. Skip and jump pairs are
+/// lifted into `if`/`else`, backward jumps into loops, and sprite, font and BCD sequences
+/// into calls like `draw(v0, v1, sprite@0x3a0, 5)`.
+pub struct Decompiler<'a> {
+    memory: &'a [u8],
+    graph: ControlFlowGraph,
+    // Targets of the jumps that could not be structured
+    gotos: BTreeSet<u16>,
+    index: Index,
+}
+
+impl<'a> Decompiler<'a> {
+    pub fn new(memory: &'a [u8], start: u16, end: usize) -> Self {
+        Decompiler {
+            memory,
+            graph: ControlFlowGraph::new(memory, start, end),
+            gotos: BTreeSet::new(),
+            index: Index::Unknown,
+        }
+    }
+
+    pub fn pseudocode(&mut self) -> String {
+        let mut out = String::new();
+        let functions: Vec<(u16, Vec<(u16, u16)>)> = self
+            .graph
+            .subroutines
+            .iter()
+            .map(|subroutine| {
+                let mut code: Vec<(u16, u16)> = subroutine
+                    .blocks
+                    .iter()
+                    .flat_map(|start| self.graph.blocks[start].instructions.clone())
+                    .collect();
+                code.sort();
+                (subroutine.entry, code)
+            })
+            .collect();
+        for (entry, code) in functions {
+            self.gotos.clear();
+            let mut lines = Vec::new();
+            // Jumps only reach 12 bit addresses, so an end capped at 0xFFFF never misses one
+            let end = code.last().map_or(entry, |(address, opcode)| {
+                address.saturating_add(instruction_length(*opcode))
+            });
+            let scope = Scope {
+                break_to: None,
+                continue_to: None,
+            };
+            self.structure(&code, end, scope, 1, &mut lines);
+
+            out += &format!("fn {}() {{\n", function_name(entry, self.graph.start));
+            let mut labelled = BTreeSet::new();
+            for line in &lines {
+                if let Some(address) = line.address {
+                    if self.gotos.contains(&address) && labelled.insert(address) {
+                        out +=
+                            &format!("{}label_{:03x}:\n", INDENT.repeat(line.indent - 1), address);
+                    }
+                }
+                out += &format!("{}{}\n", INDENT.repeat(line.indent), line.text);
+            }
+            out += "}\n\n";
+        }
+        out.pop();
+        out
+    }
+
+    // Index of the instruction at `address` in `code`, or `code.len()` for `end`
+    fn position(code: &[(u16, u16)], end: u16, address: u16) -> Option<usize> {
+        if address == end {
+            return Some(code.len());
+        }
+        code.binary_search_by_key(&address, |(a, _)| *a).ok()
+    }
+
+    fn jump_target(opcode: u16) -> Option<u16> {
+        match opcode >> 12 {
+            0x1 => Some(opcode & 0x0FFF),
+            _ => None,
+        }
+    }
+
+    // Lifts `code`, a run of instructions followed by `end`, into `lines`
+    fn structure(
+        &mut self,
+        code: &[(u16, u16)],
+        end: u16,
+        scope: Scope,
+        indent: usize,
+        lines: &mut Vec<Line>,
+    ) {
+        let next_address = |k: usize| code.get(k).map_or(end, |(a, _)| *a);
+        let mut i = 0;
+        while i < code.len() {
+            let (address, opcode) = code[i];
+            if self.graph.blocks.contains_key(&address) {
+                self.index = Index::Unknown;
+            }
+            let line = |indent: usize, text: String| Line {
+                indent,
+                address: Some(address),
+                text,
+            };
+
+            // The last backward jump to here closes a loop
+            let back_jump = (i..code.len())
+                .rev()
+                .find(|&j| Decompiler::jump_target(code[j].1) == Some(address));
+            if let (Some(j), true) = (back_jump, scope.continue_to != Some(address)) {
+                let after = next_address(j + 1);
+                let inner = Scope {
+                    break_to: Some(after),
+                    continue_to: Some(address),
+                };
+                let exit = code
+                    .get(i + 1)
+                    .and_then(|(_, o)| Decompiler::jump_target(*o));
+                if is_skip(opcode) && j > i + 1 && exit == Some(after) {
+                    let (condition, _) = skip_condition(opcode);
+                    lines.push(line(indent, format!("while ({}) {{", condition)));
+                    self.structure(&code[i + 2..j], code[j].0, inner, indent + 1, lines);
+                } else if j > i && is_skip(code[j - 1].1) {
+                    let (_, negation) = skip_condition(code[j - 1].1);
+                    lines.push(line(indent, "do {".to_string()));
+                    self.structure(&code[i..j - 1], code[j - 1].0, inner, indent + 1, lines);
+                    lines.push(line(indent, format!("}} while ({});", negation)));
+                    i = j + 1;
+                    continue;
+                } else {
+                    lines.push(line(indent, "loop {".to_string()));
+                    self.structure(&code[i..j], code[j].0, inner, indent + 1, lines);
+                }
+                lines.push(line(indent, "}".to_string()));
+                i = j + 1;
+                continue;
+            }
+
+            if is_skip(opcode) && i + 1 < code.len() {
+                let (condition, negation) = skip_condition(opcode);
+                let (jump_address, jump) = code[i + 1];
+                let target = Decompiler::jump_target(jump)
+                    .filter(|target| *target > jump_address)
+                    .and_then(|target| {
+                        Decompiler::position(code, end, target).map(|k| (target, k))
+                    });
+                if let Some((target, k)) = target {
+                    // A jump at the end of the body over what follows makes that an else
+                    let otherwise = code[i + 2..k]
+                        .last()
+                        .and_then(|(_, o)| Decompiler::jump_target(*o))
+                        .filter(|t| *t > target)
+                        .and_then(|t| Decompiler::position(code, end, t).map(|k2| (t, k2)));
+                    lines.push(line(indent, format!("if ({}) {{", condition)));
+                    match otherwise {
+                        Some((after, k2)) if k > i + 2 => {
+                            self.structure(
+                                &code[i + 2..k - 1],
+                                code[k - 1].0,
+                                scope,
+                                indent + 1,
+                                lines,
+                            );
+                            lines.push(line(indent, "} else {".to_string()));
+                            self.structure(&code[k..k2], after, scope, indent + 1, lines);
+                            i = k2;
+                        }
+                        _ => {
+                            self.structure(&code[i + 2..k], target, scope, indent + 1, lines);
+                            i = k;
+                        }
+                    }
+                    lines.push(line(indent, "}".to_string()));
+                    continue;
+                }
+                lines.push(line(indent, format!("if ({}) {{", negation)));
+                let text = self.statement(code, i + 1, scope);
+                lines.push(Line {
+                    indent: indent + 1,
+                    address: Some(jump_address),
+                    text,
+                });
+                lines.push(line(indent, "}".to_string()));
+                i += 2;
+                continue;
+            }
+
+            // bcd followed by a load of its three digits
+            let digits = code.get(i + 1).filter(|(a, o)| {
+                opcode & 0xF0FF == 0xF033 && *o == 0xF265 && !self.graph.blocks.contains_key(a)
+            });
+            if digits.is_some() {
+                let vx = register(opcode >> 8);
+                lines.push(line(indent, format!("v0, v1, v2 = bcd({});", vx)));
+                self.index = Index::Unknown;
+                i += 2;
+                continue;
+            }
+
+            let text = self.statement(code, i, scope);
+            lines.push(line(indent, text));
+            i += 1;
+        }
+    }
+
+    fn statement(&mut self, code: &[(u16, u16)], i: usize, scope: Scope) -> String {
+        let (address, opcode) = code[i];
+        let d = Decoded::new(opcode);
+        let (vx, vy) = (register(d.x as u16), register(d.y as u16));
+        let (x, n, nn, nnn) = (d.x as u16, d.n, d.nn, d.nnn);
+        match d.upper {
+            0x0 => match opcode {
+                0x00E0 => "clear();".to_string(),
+                0x00EE => "return;".to_string(),
+                0x00FB => "scroll_right();".to_string(),
+                0x00FC => "scroll_left();".to_string(),
+                0x00FD => "exit();".to_string(),
+                0x00FE => "lores();".to_string(),
+                0x00FF => "hires();".to_string(),
+                _ if opcode & 0xFFF0 == 0x00C0 => format!("scroll_down({});", n),
+                _ if opcode & 0xFFF0 == 0x00D0 => format!("scroll_up({});", n),
+                _ => format!("machine_code({:#05x});", nnn),
+            },
+            0x1 if Some(nnn) == scope.break_to => "break;".to_string(),
+            0x1 if Some(nnn) == scope.continue_to => "continue;".to_string(),
+            0x1 => {
+                self.gotos.insert(nnn);
+                format!("goto label_{:03x};", nnn)
+            }
+            0x2 => format!("{}();", function_name(nnn, self.graph.start)),
+            0x5 if n == 2 => format!("{} = {}..{};", self.index.memory(), vx, vy),
+            0x5 if n == 3 => format!("{}..{} = {};", vx, vy, self.index.memory()),
+            0x6 => format!("{} = {:#04x};", vx, nn),
+            0x7 => format!("{} += {:#04x};", vx, nn),
+            0x8 => match n {
+                0x0 => format!("{} = {};", vx, vy),
+                0x1 => format!("{} |= {};", vx, vy),
+                0x2 => format!("{} &= {};", vx, vy),
+                0x3 => format!("{} ^= {};", vx, vy),
+                0x4 => format!("{} += {};  // vf = carry", vx, vy),
+                0x5 => format!("{} -= {};  // vf = no borrow", vx, vy),
+                0x6 => format!("{} = {} >> 1;", vx, vy),
+                0x7 => format!("{} = {} - {};  // vf = no borrow", vx, vy, vx),
+                0xE => format!("{} = {} << 1;", vx, vy),
+                _ => format!("/* {:04x} */", opcode),
+            },
+            0xA => {
+                self.index = Index::Address(nnn);
+                format!("i = {:#05x};", nnn)
+            }
+            0xB => format!("goto *({:#05x} + v0);", nnn),
+            0xC => format!("{} = random() & {:#04x};", vx, nn),
+            0xD => format!("draw({}, {}, {}, {});", vx, vy, self.index.sprite(), n),
+            0xF => match nn {
+                0x00 if opcode == 0xF000 => match read_opcode(self.memory, address as usize + 2) {
+                    Some(long) => {
+                        self.index = Index::Address(long);
+                        format!("i = {:#06x};", long)
+                    }
+                    None => format!("/* {:04x} */", opcode),
+                },
+                0x01 => format!("plane({});", x),
+                0x02 if opcode == 0xF002 => "audio(i);".to_string(),
+                0x07 => format!("{} = delay_timer;", vx),
+                0x0A => format!("{} = wait_key();", vx),
+                0x15 => format!("delay_timer = {};", vx),
+                0x18 => format!("sound_timer = {};", vx),
+                0x1E => {
+                    self.index = Index::Unknown;
+                    format!("i += {};", vx)
+                }
+                0x29 => {
+                    self.index = Index::Font(x);
+                    format!("i = font({});", vx)
+                }
+                0x30 => {
+                    self.index = Index::BigFont(x);
+                    format!("i = big_font({});", vx)
+                }
+                0x33 => format!("{} = bcd({});", self.index.memory(), vx),
+                0x3A => format!("pitch = {};", vx),
+                0x55 => {
+                    let text = format!("{} = {};", self.index.memory(), registers(x));
+                    self.index = Index::Unknown;
+                    text
+                }
+                0x65 => {
+                    let text = format!("{} = {};", registers(x), self.index.memory());
+                    self.index = Index::Unknown;
+                    text
+                }
+                0x75 => format!("flags = {};", registers(x)),
+                0x85 => format!("{} = flags;", registers(x)),
+                _ => format!("/* {:04x} */", opcode),
+            },
+            _ => format!("/* {:04x} */", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octo;
+
+    #[test]
+    fn test_structured() {
+        let source = "
+            : main
+              clear
+              v0 := 0
+              loop
+                i := glyph
+                sprite v0 v1 5
+                if v0 key begin v1 := 1 else v1 := 2 end
+                if v2 == 3 then v2 += 1
+                i := digits
+                bcd v3
+                load v2
+                i := hex v0
+                sprite v1 v2 5
+                step
+                v0 += 1
+                if v0 != 10 then
+              again
+              loop again
+            : step
+              loop
+                v4 += 1
+                if v4 == 8 then return
+              again
+            : glyph 0xF0 0x90
+            : digits 0 0 0
+        ";
+        let program = octo::compile(source).unwrap();
+        let mut memory = vec![0; 0x200];
+        memory.extend_from_slice(&program.rom);
+        let pseudocode = Decompiler::new(&memory, 0x200, memory.len()).pseudocode();
+        let expected = "\
+fn main() {
+    clear();
+    v0 = 0x00;
+    do {
+        i = 0x232;
+        draw(v0, v1, sprite@0x232, 5);
+        if (key_pressed(v0)) {
+            v1 = 0x01;
+        } else {
+            v1 = 0x02;
+        }
+        if (v2 == 0x03) {
+            v2 += 0x01;
+        }
+        i = 0x234;
+        v0, v1, v2 = bcd(v3);
+        i = font(v0);
+        draw(v1, v2, font(v0), 5);
+        sub_22a();
+        v0 += 0x01;
+    } while (v0 != 0x0a);
+    loop {
+    }
+}
+
+fn sub_22a() {
+    loop {
+        v4 += 0x01;
+        if (v4 == 0x08) {
+            return;
+        }
+    }
+}
+";
+        assert_eq!(pseudocode, expected);
+    }
+}
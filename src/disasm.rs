@@ -0,0 +1,364 @@
+use crate::analysis::{instruction_length, reachable_code};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Data bytes printed per line
+const DATA_ROW: usize = 8;
+
+// How an address is used, from the most to the least telling name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Main,
+    Subroutine,
+    Jump,
+    Data,
+}
+
+fn register(n: u16) -> String {
+    format!("v{:x}", n & 0xF)
+}
+
+/// Octo source of one instruction, `name` gives the label or number to use for an address.
+/// Returns None for opcodes Octo has no syntax for.
+pub fn instruction_source(
+    opcode: u16,
+    long: Option<u16>,
+    name: &dyn Fn(u16) -> String,
+) -> Option<String> {
+    let vx = register(opcode >> 8);
+    let vy = register(opcode >> 4);
+    let n = opcode & 0xF;
+    let nn = opcode & 0xFF;
+    let nnn = opcode & 0x0FFF;
+    let source = match opcode >> 12 {
+        0x0 => match opcode {
+            0x00E0 => "clear".to_string(),
+            0x00EE => "return".to_string(),
+            0x00FB => "scroll-right".to_string(),
+            0x00FC => "scroll-left".to_string(),
+            0x00FD => "exit".to_string(),
+            0x00FE => "lores".to_string(),
+            0x00FF => "hires".to_string(),
+            _ if opcode & 0xFFF0 == 0x00C0 => format!("scroll-down {}", n),
+            _ if opcode & 0xFFF0 == 0x00D0 => format!("scroll-up {}", n),
+            _ => format!("native {}", name(nnn)),
+        },
+        0x1 => format!("jump {}", name(nnn)),
+        0x2 => {
+            let target = name(nnn);
+            if target.starts_with("0x") {
+                format!(":call {}", target)
+            } else {
+                target
+            }
+        }
+        0x3 => format!("if {} != {:#04x} then", vx, nn),
+        0x4 => format!("if {} == {:#04x} then", vx, nn),
+        0x5 => match n {
+            0x0 => format!("if {} != {} then", vx, vy),
+            0x2 => format!("save {} - {}", vx, vy),
+            0x3 => format!("load {} - {}", vx, vy),
+            _ => return None,
+        },
+        0x6 => format!("{} := {:#04x}", vx, nn),
+        0x7 => format!("{} += {:#04x}", vx, nn),
+        0x8 => {
+            let op = match n {
+                0x0 => ":=",
+                0x1 => "|=",
+                0x2 => "&=",
+                0x3 => "^=",
+                0x4 => "+=",
+                0x5 => "-=",
+                0x6 => ">>=",
+                0x7 => "=-",
+                0xE => "<<=",
+                _ => return None,
+            };
+            format!("{} {} {}", vx, op, vy)
+        }
+        0x9 if n == 0 => format!("if {} == {} then", vx, vy),
+        0xA => format!("i := {}", name(nnn)),
+        0xB => format!("jump0 {}", name(nnn)),
+        0xC => format!("{} := random {:#04x}", vx, nn),
+        0xD => format!("sprite {} {} {}", vx, vy, n),
+        0xE => match nn {
+            0x9E => format!("if {} -key then", vx),
+            0xA1 => format!("if {} key then", vx),
+            _ => return None,
+        },
+        0xF => match nn {
+            0x00 if opcode == 0xF000 => format!("i := long {}", name(long?)),
+            0x01 => format!("plane {}", (opcode >> 8) & 0xF),
+            0x02 if opcode == 0xF002 => "audio".to_string(),
+            0x07 => format!("{} := delay", vx),
+            0x0A => format!("{} := key", vx),
+            0x15 => format!("delay := {}", vx),
+            0x18 => format!("buzzer := {}", vx),
+            0x1E => format!("i += {}", vx),
+            0x29 => format!("i := hex {}", vx),
+            0x30 => format!("i := bighex {}", vx),
+            0x33 => format!("bcd {}", vx),
+            0x3A => format!("pitch := {}", vx),
+            0x55 => format!("save {}", vx),
+            0x65 => format!("load {}", vx),
+            0x75 => format!("saveflags {}", vx),
+            0x85 => format!("loadflags {}", vx),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(source)
+}
+
+/// Addresses in a coverage log, one hex address per line, or in a trace log written with
+/// `--trace`, where the address is the second column
+pub fn parse_address_log(text: &str) -> Vec<u16> {
+    let hex = |text: &str| u16::from_str_radix(text.trim_start_matches("0x"), 16).ok();
+    text.lines()
+        .filter_map(|line| {
+            let columns: Vec<&str> = line.split('#').next()?.split_whitespace().collect();
+            let is_trace = columns.len() >= 3 && columns[2].len() == 4 && hex(columns[2]).is_some();
+            hex(columns.get(if is_trace { 1 } else { 0 })?)
+        })
+        .collect()
+}
+
+/// Flow-following disassembly of the program in `memory[start..end]`
+pub struct Disassembly {
+    memory: Vec<u8>,
+    start: usize,
+    end: usize,
+    // Instructions that are printed as code, overlapping ones are left out
+    code: BTreeMap<u16, u16>,
+    labels: BTreeMap<u16, (LabelKind, String)>,
+    // Table address -> address of the `Bnnn` using it
+    jump_tables: BTreeMap<u16, u16>,
+}
+
+impl Disassembly {
+    /// `executed` are addresses known to run, from a coverage log, that are used as extra
+    /// starting points for code that static analysis cannot find, e.g. behind `Bnnn`
+    pub fn new(memory: &[u8], start: u16, end: usize, executed: &[u16]) -> Self {
+        let program = start as usize..end;
+        let mut starts = vec![start];
+        starts.extend(
+            executed
+                .iter()
+                .filter(|a| program.contains(&(**a as usize))),
+        );
+        let mut code = BTreeMap::new();
+        let mut cursor = start as usize;
+        for (address, opcode) in reachable_code(memory, &starts, end) {
+            let length = instruction_length(opcode) as usize;
+            if address as usize >= cursor && address as usize + length <= end {
+                code.insert(address, opcode);
+                cursor = address as usize + length;
+            }
+        }
+        let mut disassembly = Disassembly {
+            memory: memory.to_vec(),
+            start: start as usize,
+            end,
+            code,
+            labels: BTreeMap::new(),
+            jump_tables: BTreeMap::new(),
+        };
+        disassembly.add_label(start, LabelKind::Main);
+        let code: Vec<(u16, u16)> = disassembly.code.iter().map(|(a, o)| (*a, *o)).collect();
+        for (address, opcode) in code {
+            let nnn = opcode & 0x0FFF;
+            match opcode >> 12 {
+                0x1 => disassembly.add_label(nnn, LabelKind::Jump),
+                0x2 => disassembly.add_label(nnn, LabelKind::Subroutine),
+                0xA => disassembly.add_label(nnn, LabelKind::Data),
+                0xB => {
+                    disassembly.add_label(nnn, LabelKind::Jump);
+                    disassembly.jump_tables.insert(nnn, address);
+                }
+                0xF if opcode == 0xF000 => {
+                    let target = disassembly.read_word(address as usize + 2);
+                    disassembly.add_label(target, LabelKind::Data);
+                }
+                _ => {}
+            }
+        }
+        disassembly
+    }
+
+    fn read_word(&self, address: usize) -> u16 {
+        ((self.memory[address] as u16) << 8) | self.memory[address + 1] as u16
+    }
+
+    fn is_code(&self, address: u16) -> bool {
+        self.code.contains_key(&address)
+    }
+
+    // Whether `address` is in the middle of a printed instruction, where no label can go
+    fn inside_instruction(&self, address: u16) -> bool {
+        match self.code.range(..address).next_back() {
+            Some((start, opcode)) => {
+                (address as usize) < *start as usize + instruction_length(*opcode) as usize
+            }
+            None => false,
+        }
+    }
+
+    fn add_label(&mut self, address: u16, kind: LabelKind) {
+        if !(self.start..self.end).contains(&(address as usize)) || self.inside_instruction(address)
+        {
+            return;
+        }
+        let kind = match kind {
+            LabelKind::Data if self.is_code(address) => LabelKind::Jump,
+            kind => kind,
+        };
+        let name = match kind {
+            LabelKind::Main => "main".to_string(),
+            LabelKind::Subroutine => format!("sub_{:03x}", address),
+            LabelKind::Jump => format!("label_{:03x}", address),
+            LabelKind::Data => format!("data_{:03x}", address),
+        };
+        match self.labels.get(&address) {
+            Some((existing, _)) if *existing <= kind => {}
+            _ => {
+                self.labels.insert(address, (kind, name));
+            }
+        }
+    }
+
+    fn name(&self, address: u16) -> String {
+        match self.labels.get(&address) {
+            Some((_, name)) => name.clone(),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    pub fn code_bytes(&self) -> usize {
+        self.code
+            .values()
+            .map(|opcode| instruction_length(*opcode) as usize)
+            .sum()
+    }
+
+    /// Octo source that compiles back to the same ROM
+    pub fn to_source(&self) -> String {
+        let mut out = String::new();
+        let size = self.end - self.start;
+        let code = self.code_bytes();
+        writeln!(
+            out,
+            "# {} bytes of code, {} bytes of data",
+            code,
+            size - code
+        )
+        .unwrap();
+        // Octo assembles to 0x200, the addresses below are where the program really runs
+        if self.start != 0x200 {
+            writeln!(out, "# loaded at {:#05x}", self.start).unwrap();
+        }
+        let name = |address: u16| self.name(address);
+        let mut address = self.start;
+        while address < self.end {
+            let address16 = address as u16;
+            if let Some((_, label)) = self.labels.get(&address16) {
+                writeln!(out, "\n: {}", label).unwrap();
+            }
+            if let Some(user) = self.jump_tables.get(&address16) {
+                writeln!(out, "  # jump table of jump0 at {:#05x}", user).unwrap();
+            }
+            if let Some(opcode) = self.code.get(&address16) {
+                let length = instruction_length(*opcode) as usize;
+                let long = (length == 4).then(|| self.read_word(address + 2));
+                let mut source = instruction_source(*opcode, long, &name);
+                // A skip needs an instruction after it to compile
+                if source.as_deref().is_some_and(|s| s.ends_with("then")) && address + 2 >= self.end
+                {
+                    source = None;
+                }
+                let bytes = &self.memory[address..address + length];
+                let source = source.unwrap_or_else(|| {
+                    bytes
+                        .iter()
+                        .map(|b| format!("{:#04x}", b))
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                });
+                let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                writeln!(out, "  {:<28}# {:03x}: {}", source, address, hex).unwrap();
+                address += length;
+            } else {
+                // Data runs until the next row, label or instruction
+                let mut row_end = address + 1;
+                while row_end < self.end
+                    && row_end - address < DATA_ROW
+                    && !self.is_code(row_end as u16)
+                    && !self.labels.contains_key(&(row_end as u16))
+                {
+                    row_end += 1;
+                }
+                let bytes: Vec<String> = self.memory[address..row_end]
+                    .iter()
+                    .map(|b| format!("{:#04x}", b))
+                    .collect();
+                writeln!(out, "  {:<40}# {:03x}", bytes.join(" "), address).unwrap();
+                address = row_end;
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::octo;
+
+    fn memory(rom: &[u8]) -> Vec<u8> {
+        let mut memory = vec![0; 0x200];
+        memory.extend_from_slice(rom);
+        memory
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rom = octo::compile(
+            ": main
+               i := glyph v0 := 8
+               loop
+                 sprite v0 v1 5 v0 += 8
+                 if v0 == 64 then draw-done
+                 if v2 key begin v1 := random 31 end
+                 i := long glyph save v1 - v2 v3 >>= v4 0x5A 0x01
+               again
+             : draw-done v0 := 0 jump0 table
+             : table jump draw-done jump main
+             : glyph 0xF0 0x90 0xF0 0x90 0xF0",
+        )
+        .unwrap()
+        .rom;
+        let memory = memory(&rom);
+        let disassembly = Disassembly::new(&memory, 0x200, memory.len(), &[]);
+        let source = disassembly.to_source();
+        assert!(source.contains("jump table of jump0"));
+        assert!(source.contains("\n: data_"));
+        assert_eq!(octo::compile(&source).unwrap().rom, rom, "{}", source);
+    }
+
+    #[test]
+    fn test_coverage() {
+        // 200: jump through v0 to a table the analysis cannot see, 206: return
+        let memory = memory(&[0xB2, 0x04, 0xFF, 0xFF, 0x00, 0xEE, 0x00, 0xEE]);
+        let disassembly = Disassembly::new(&memory, 0x200, memory.len(), &[]);
+        assert!(!disassembly.is_code(0x206));
+        let executed = parse_address_log("# coverage\n204\n0x206\n         7  206  00ee  sub\n");
+        assert_eq!(executed, vec![0x204, 0x206, 0x206]);
+        let disassembly = Disassembly::new(&memory, 0x200, memory.len(), &executed);
+        assert!(disassembly.is_code(0x206));
+        assert!(!disassembly.is_code(0x202));
+        assert_eq!(
+            octo::compile(&disassembly.to_source()).unwrap().rom,
+            memory[0x200..]
+        );
+    }
+}
@@ -1,17 +1,54 @@
-use crate::keyboard::map_modern_to_chip8;
+use crate::chip8x::ColourMap;
+use crate::keyboard::{map_modern_to_second_keypad, InputState, KeyMap};
+use crate::megachip;
 use fltk::{prelude::*, *};
 use std::cell::RefCell;
+use std::cmp;
 use std::rc::Rc;
 
 // TODO Need to figure out a way to only redraw the display a maximum of 60 times per second
 // TODO Need to only execute ~700 instructions per second
 
+/// Colours of unset and set pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub background: (u8, u8, u8),
+    pub foreground: (u8, u8, u8),
+}
+
+impl Palette {
+    pub fn default() -> Self {
+        Palette {
+            background: (0, 0, 0),
+            foreground: (0xFF, 0xFF, 0xFF),
+        }
+    }
+
+    /// Parses a `#rrggbb` colour
+    pub fn parse_color(text: &str) -> Option<(u8, u8, u8)> {
+        let hex = text.strip_prefix('#').unwrap_or(text);
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+        Some((channel(0)?, channel(2)?, channel(4)?))
+    }
+}
+
 pub struct EmuDisplay {
     pub inner: widget::Widget,
-    pub pixel_mat: Rc<RefCell<[[bool; 64]; 32]>>,
+    // Rows of pixels, sized for the platform's resolution
+    pub pixel_mat: Rc<RefCell<Vec<Vec<bool>>>>,
     pub keys_pressed: Rc<RefCell<[bool; 16]>>,
+    pub second_keys_pressed: Rc<RefCell<[bool; 16]>>,
     pub last_key_down: Rc<RefCell<Option<u8>>>,
     pub last_key_up: Rc<RefCell<Option<u8>>>,
+    pub palette: Rc<RefCell<Palette>>,
+    pub keymap: Rc<RefCell<KeyMap>>,
+    // CHIP-8X colours, which replace the palette when set
+    pub colours: Rc<RefCell<Option<ColourMap>>>,
+    // RGB frame of MegaChip mode, shown instead of the pixels when set
+    pub mega_frame: Rc<RefCell<Option<Vec<u8>>>>,
 }
 
 impl EmuDisplay {
@@ -22,36 +59,61 @@ impl EmuDisplay {
             .center_of_parent();
         inner.set_frame(enums::FrameType::NoBox);
 
-        let pixel_mat = [[false; 64]; 32];
+        let pixel_mat = vec![vec![false; 64]; 32];
         let pixel_mat = Rc::from(RefCell::from(pixel_mat));
         let draw_mat = pixel_mat.clone();
         let keys_pressed = Rc::new(RefCell::new([false; 16]));
         let handle_keys_pressed = keys_pressed.clone();
+        let second_keys_pressed = Rc::new(RefCell::new([false; 16]));
+        let handle_second_keys_pressed = second_keys_pressed.clone();
         let last_key_down = Rc::new(RefCell::new(None));
         let last_key_up = Rc::new(RefCell::new(None));
         let last_key_down_clone = last_key_down.clone();
         let last_key_up_clone = last_key_up.clone();
+        let palette = Rc::new(RefCell::new(Palette::default()));
+        let draw_palette = palette.clone();
+        let keymap = Rc::new(RefCell::new(KeyMap::default()));
+        let handle_keymap = keymap.clone();
+        let colours = Rc::new(RefCell::new(None));
+        let draw_colours = colours.clone();
+        let mega_frame: Rc<RefCell<Option<Vec<u8>>>> = Rc::new(RefCell::new(None));
+        let draw_frame = mega_frame.clone();
         inner.draw(move |i| {
+            if let Some(frame) = draw_frame.borrow().as_ref() {
+                draw_mega_frame(i, frame);
+                return;
+            }
             let mat = draw_mat.borrow();
-            for row in 0..32 {
-                for col in 0..64 {
-                    if mat[row as usize][col as usize] {
-                        draw::draw_rect_fill(
-                            i.x() + col * 10,
-                            i.y() + row * 10,
-                            10,
-                            10,
-                            enums::Color::White,
-                        );
-                    } else {
-                        draw::draw_rect_fill(
-                            i.x() + col * 10,
-                            i.y() + row * 10,
-                            10,
-                            10,
-                            enums::Color::Black,
-                        );
+            let palette = draw_palette.borrow();
+            let colours: Option<ColourMap> = *draw_colours.borrow();
+            let (r, g, b) = palette.foreground;
+            let mut foreground = enums::Color::from_rgb(r, g, b);
+            let (r, g, b) = match &colours {
+                Some(colours) => colours.background(),
+                None => palette.background,
+            };
+            let background = enums::Color::from_rgb(r, g, b);
+            // Square pixels as big as fit, centred
+            let (width, height) = (mat[0].len() as i32, mat.len() as i32);
+            let size = cmp::min(i.w() / width, i.h() / height);
+            let left = i.x() + (i.w() - width * size) / 2;
+            let top = i.y() + (i.h() - height * size) / 2;
+            if size * width < i.w() || size * height < i.h() {
+                draw::draw_rect_fill(i.x(), i.y(), i.w(), i.h(), enums::Color::Black);
+            }
+            for (row, pixels) in mat.iter().enumerate() {
+                for (col, pixel) in pixels.iter().enumerate() {
+                    if let Some(colours) = &colours {
+                        let (r, g, b) = colours.foreground(col, row);
+                        foreground = enums::Color::from_rgb(r, g, b);
                     }
+                    draw::draw_rect_fill(
+                        left + col as i32 * size,
+                        top + row as i32 * size,
+                        size,
+                        size,
+                        if *pixel { foreground } else { background },
+                    );
                 }
             }
         });
@@ -60,13 +122,15 @@ impl EmuDisplay {
                 let key = app::event_key();
                 let key_char = key.to_char();
                 let chip8_key = match key_char {
-                    Some(x) => map_modern_to_chip8(x),
+                    Some(x) => handle_keymap.borrow().chip8_key_for(x),
                     _ => None,
                 };
                 if let Some(k) = chip8_key {
                     handle_keys_pressed.borrow_mut()[k as usize] = true;
                     *last_key_down_clone.borrow_mut() = Some(k);
                     println!("Key pressed: {:x}", k);
+                } else if let Some(k) = key_char.and_then(map_modern_to_second_keypad) {
+                    handle_second_keys_pressed.borrow_mut()[k as usize] = true;
                 }
                 *last_key_up_clone.borrow_mut() = None;
                 true
@@ -75,13 +139,15 @@ impl EmuDisplay {
                 let key = app::event_key();
                 let key_char = key.to_char();
                 let chip8_key = match key_char {
-                    Some(x) => map_modern_to_chip8(x),
+                    Some(x) => handle_keymap.borrow().chip8_key_for(x),
                     _ => None,
                 };
                 if let Some(k) = chip8_key {
                     handle_keys_pressed.borrow_mut()[k as usize] = false;
                     println!("Key released: {:x}", k);
                     *last_key_up_clone.borrow_mut() = Some(k);
+                } else if let Some(k) = key_char.and_then(map_modern_to_second_keypad) {
+                    handle_second_keys_pressed.borrow_mut()[k as usize] = false;
                 }
                 *last_key_down_clone.borrow_mut() = None;
                 true
@@ -89,13 +155,15 @@ impl EmuDisplay {
             enums::Event::Shortcut => {
                 let key_char = app::event_key().to_char();
                 let chip8_key = match key_char {
-                    Some(x) => map_modern_to_chip8(x),
+                    Some(x) => handle_keymap.borrow().chip8_key_for(x),
                     _ => None,
                 };
                 if let Some(k) = chip8_key {
                     handle_keys_pressed.borrow_mut()[k as usize] = true;
                     println!("Key pressed: {:x}", k);
                     *last_key_down_clone.borrow_mut() = Some(k);
+                } else if let Some(k) = key_char.and_then(map_modern_to_second_keypad) {
+                    handle_second_keys_pressed.borrow_mut()[k as usize] = true;
                 }
                 *last_key_up_clone.borrow_mut() = None;
                 true
@@ -110,11 +178,62 @@ impl EmuDisplay {
             inner,
             pixel_mat,
             keys_pressed,
+            second_keys_pressed,
             last_key_down,
             last_key_up,
+            palette,
+            keymap,
+            colours,
+            mega_frame,
+        }
+    }
+
+    /// Replaces the pixels with a blank display of `width` by `height`
+    pub fn set_resolution(&self, (width, height): (usize, usize)) {
+        *self.pixel_mat.borrow_mut() = vec![vec![false; width]; height];
+    }
+
+    pub fn input_state(&self) -> InputState {
+        InputState {
+            pressed: *self.keys_pressed.borrow(),
+            second: *self.second_keys_pressed.borrow(),
+            last_key_down: *self.last_key_down.borrow(),
+            last_key_up: *self.last_key_up.borrow(),
         }
     }
 }
 
 // Extend widget::Widget via the member `inner` and add other initializers and constructors
 widget_extends!(EmuDisplay, widget::Widget, inner);
+
+// Scales the 256x192 MegaChip frame to fit the widget, keeping its shape
+fn draw_mega_frame(widget: &widget::Widget, frame: &[u8]) {
+    let (width, height) = (megachip::WIDTH as i32, megachip::HEIGHT as i32);
+    let scale = f64::min(
+        widget.w() as f64 / width as f64,
+        widget.h() as f64 / height as f64,
+    );
+    let left = widget.x() + (widget.w() - (width as f64 * scale) as i32) / 2;
+    let top = widget.y() + (widget.h() - (height as f64 * scale) as i32) / 2;
+    draw::draw_rect_fill(
+        widget.x(),
+        widget.y(),
+        widget.w(),
+        widget.h(),
+        enums::Color::Black,
+    );
+    let edge = |n: i32| (n as f64 * scale) as i32;
+    for (row, line) in frame.chunks(width as usize * 3).enumerate() {
+        let row = row as i32;
+        for (col, rgb) in line.chunks(3).enumerate() {
+            let col = col as i32;
+            draw::draw_rect_fill(
+                left + edge(col),
+                top + edge(row),
+                edge(col + 1) - edge(col),
+                edge(row + 1) - edge(row),
+                enums::Color::from_rgb(rgb[0], rgb[1], rgb[2]),
+            );
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::register::{Reg, RegName};
+use std::fmt;
+
+// Small expression language used by debugger conditions, e.g. `v3 == 0x10 && i > 0x300`.
+// Values are registers (v0-vf, i, pc, sp, dt, st), numbers (decimal, 0x hex, 0b binary)
+// and memory bytes written as `[address]`. Non-zero results are treated as true.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(RegName),
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+}
+
+// Longest operators first so that e.g. `<=` is not read as `<` followed by `=`
+const OPERATORS: [&str; 21] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/",
+    "%", "!", "~", "=",
+];
+
+/// Parses a number in decimal, `0x` hexadecimal or `0b` binary notation
+pub fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim().replace('_', "");
+    if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('#'))
+    {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            let word: String = chars[start..pos].iter().collect();
+            if c.is_ascii_digit() {
+                let value =
+                    parse_number(&word).ok_or_else(|| format!("invalid number '{}'", word))?;
+                tokens.push(Token::Number(value));
+            } else {
+                tokens.push(Token::Ident(word));
+            }
+        } else {
+            match c {
+                '(' => tokens.push(Token::LParen),
+                ')' => tokens.push(Token::RParen),
+                '[' => tokens.push(Token::LBracket),
+                ']' => tokens.push(Token::RBracket),
+                _ => {
+                    let rest: String = chars[pos..].iter().take(2).collect();
+                    let op = OPERATORS
+                        .iter()
+                        .find(|op| rest.starts_with(*op))
+                        .ok_or_else(|| format!("unexpected character '{}'", c))?;
+                    // A single `=` is accepted as a synonym for `==`
+                    tokens.push(Token::Op(if *op == "=" { "==" } else { op }));
+                    pos += op.len();
+                    continue;
+                }
+            }
+            pos += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+fn binary_op(op: &str) -> Option<(BinaryOp, u8)> {
+    // Binding power, higher binds tighter (same ordering as C)
+    match op {
+        "||" => Some((BinaryOp::Or, 1)),
+        "&&" => Some((BinaryOp::And, 2)),
+        "|" => Some((BinaryOp::BitOr, 3)),
+        "^" => Some((BinaryOp::BitXor, 4)),
+        "&" => Some((BinaryOp::BitAnd, 5)),
+        "==" => Some((BinaryOp::Eq, 6)),
+        "!=" => Some((BinaryOp::Ne, 6)),
+        "<" => Some((BinaryOp::Lt, 7)),
+        "<=" => Some((BinaryOp::Le, 7)),
+        ">" => Some((BinaryOp::Gt, 7)),
+        ">=" => Some((BinaryOp::Ge, 7)),
+        "<<" => Some((BinaryOp::Shl, 8)),
+        ">>" => Some((BinaryOp::Shr, 8)),
+        "+" => Some((BinaryOp::Add, 9)),
+        "-" => Some((BinaryOp::Sub, 9)),
+        "*" => Some((BinaryOp::Mul, 10)),
+        "/" => Some((BinaryOp::Div, 10)),
+        "%" => Some((BinaryOp::Rem, 10)),
+        _ => None,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), String> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            Some(token) => Err(format!("expected {:?}, found {:?}", expected, token)),
+            None => Err(format!("expected {:?} at end of expression", expected)),
+        }
+    }
+
+    fn expression(&mut self, min_power: u8) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let (op, power) = match binary_op(op) {
+                Some(binary) => binary,
+                None => return Err(format!("unexpected operator '{}'", op)),
+            };
+            if power < min_power {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.expression(power + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.next() {
+            Some(Token::Op("!")) => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+            Some(Token::Op("-")) => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+            Some(Token::Op("~")) => Ok(Expr::Unary(UnaryOp::BitNot, Box::new(self.unary()?))),
+            Some(Token::Number(n)) => Ok(Expr::Number(n)),
+            Some(Token::Ident(name)) => RegName::parse(&name)
+                .map(Expr::Register)
+                .ok_or_else(|| format!("unknown register '{}'", name)),
+            Some(Token::LParen) => {
+                let inner = self.expression(0)?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            Some(Token::LBracket) => {
+                let address = self.expression(0)?;
+                self.expect(Token::RBracket)?;
+                Ok(Expr::Memory(Box::new(address)))
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+        };
+        let expr = parser.expression(0)?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?} after expression", token)),
+        }
+    }
+
+    pub fn eval(&self, reg: &Reg, memory: &[u8]) -> i64 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::Register(name) => name.read(reg) as i64,
+            Expr::Memory(address) => {
+                let address = address.eval(reg, memory);
+                if address >= 0 && (address as usize) < memory.len() {
+                    memory[address as usize] as i64
+                } else {
+                    0
+                }
+            }
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(reg, memory);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Neg => value.wrapping_neg(),
+                    UnaryOp::BitNot => !value,
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let a = lhs.eval(reg, memory);
+                // Short-circuit the logical operators
+                match op {
+                    BinaryOp::Or if a != 0 => return 1,
+                    BinaryOp::And if a == 0 => return 0,
+                    _ => {}
+                }
+                let b = rhs.eval(reg, memory);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (b != 0) as i64,
+                    BinaryOp::BitOr => a | b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::Eq => (a == b) as i64,
+                    BinaryOp::Ne => (a != b) as i64,
+                    BinaryOp::Lt => (a < b) as i64,
+                    BinaryOp::Le => (a <= b) as i64,
+                    BinaryOp::Gt => (a > b) as i64,
+                    BinaryOp::Ge => (a >= b) as i64,
+                    BinaryOp::Shl => a.wrapping_shl(b as u32),
+                    BinaryOp::Shr => a.wrapping_shr(b as u32),
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Sub => a.wrapping_sub(b),
+                    BinaryOp::Mul => a.wrapping_mul(b),
+                    BinaryOp::Div => a.checked_div(b).unwrap_or(0),
+                    BinaryOp::Rem => a.checked_rem(b).unwrap_or(0),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, reg: &Reg, memory: &[u8]) -> bool {
+        self.eval(reg, memory) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Number(n) => write!(f, "{:#x}", n),
+            Expr::Register(name) => write!(f, "{}", name.name()),
+            Expr::Memory(address) => write!(f, "[{}]", address),
+            Expr::Unary(op, operand) => {
+                let op = match op {
+                    UnaryOp::Not => "!",
+                    UnaryOp::Neg => "-",
+                    UnaryOp::BitNot => "~",
+                };
+                write!(f, "{}{}", op, operand)
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let op = match op {
+                    BinaryOp::Or => "||",
+                    BinaryOp::And => "&&",
+                    BinaryOp::BitOr => "|",
+                    BinaryOp::BitXor => "^",
+                    BinaryOp::BitAnd => "&",
+                    BinaryOp::Eq => "==",
+                    BinaryOp::Ne => "!=",
+                    BinaryOp::Lt => "<",
+                    BinaryOp::Le => "<=",
+                    BinaryOp::Gt => ">",
+                    BinaryOp::Ge => ">=",
+                    BinaryOp::Shl => "<<",
+                    BinaryOp::Shr => ">>",
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::Rem => "%",
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("42"), Some(42));
+        assert_eq!(parse_number("0x2A"), Some(42));
+        assert_eq!(parse_number("0b101010"), Some(42));
+        assert_eq!(parse_number("#2a"), Some(42));
+        assert_eq!(parse_number("zz"), None);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let reg = Reg::default();
+        assert_eq!(Expr::parse("1 + 2 * 3").unwrap().eval(&reg, &[]), 7);
+        assert_eq!(Expr::parse("(1 + 2) * 3").unwrap().eval(&reg, &[]), 9);
+        assert_eq!(
+            Expr::parse("1 + 1 == 2 && 3 > 2").unwrap().eval(&reg, &[]),
+            1
+        );
+        assert_eq!(
+            Expr::parse("0x10 | 0x01 == 0x11").unwrap().eval(&reg, &[]),
+            0x10
+        );
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut reg = Reg::default();
+        reg.v[3] = 0x10;
+        reg.i = 0x301;
+        let memory = [0, 0xAB];
+        let expr = Expr::parse("v3 == 0x10 && i > 0x300").unwrap();
+        assert!(expr.is_true(&reg, &memory));
+        reg.v[3] = 0x11;
+        assert!(!expr.is_true(&reg, &memory));
+        assert_eq!(Expr::parse("[1]").unwrap().eval(&reg, &memory), 0xAB);
+        assert_eq!(Expr::parse("[i]").unwrap().eval(&reg, &memory), 0);
+        assert!(Expr::parse("VF = 0").unwrap().is_true(&reg, &memory));
+    }
+
+    #[test]
+    fn test_display() {
+        let expr = Expr::parse("v3 == 16 && !([i] > 0x300)").unwrap();
+        assert_eq!(expr.to_string(), "((v3 == 0x10) && !([i] > 0x300))");
+        assert_eq!(Expr::parse(&expr.to_string()).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(Expr::parse("v3 ==").is_err());
+        assert!(Expr::parse("vg == 1").is_err());
+        assert!(Expr::parse("(1 + 2").is_err());
+        assert!(Expr::parse("1 2").is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+// XO-CHIP has 16 flags, SUPER-CHIP only uses the first 8
+const FLAG_COUNT: usize = 16;
+
+/// The HP48 "RPL user flags" saved by Fx75 and restored by Fx85. When backed by a file they
+/// survive across sessions, which games use for high scores.
+pub struct RplFlags {
+    path: Option<PathBuf>,
+    values: [u8; FLAG_COUNT],
+}
+
+impl RplFlags {
+    /// Flags kept in memory only
+    pub fn default() -> Self {
+        RplFlags {
+            path: None,
+            values: [0; FLAG_COUNT],
+        }
+    }
+
+    /// Flags stored in `path`, starting from its contents if it exists
+    pub fn open(path: PathBuf) -> Self {
+        let mut values = [0; FLAG_COUNT];
+        if let Ok(saved) = fs::read(&path) {
+            let count = saved.len().min(FLAG_COUNT);
+            values[..count].copy_from_slice(&saved[..count]);
+        }
+        RplFlags {
+            path: Some(path),
+            values,
+        }
+    }
+
+    /// Flags of the ROM with the given SHA-1, kept in the user data directory
+    pub fn for_rom(rom_sha1: &str) -> Self {
+        match dirs::data_dir() {
+            Some(dir) => RplFlags::open(
+                dir.join("chip8")
+                    .join("flags")
+                    .join(format!("{}.bin", rom_sha1)),
+            ),
+            None => RplFlags::default(),
+        }
+    }
+
+    pub fn values(&self) -> &[u8] {
+        &self.values
+    }
+
+    /// Replaces the first flags with `values` and writes all of them to the file
+    pub fn store(&mut self, values: &[u8]) -> io::Result<()> {
+        let count = values.len().min(FLAG_COUNT);
+        self.values[..count].copy_from_slice(&values[..count]);
+        if let Some(path) = &self.path {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::write(path, self.values)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir()
+            .join("chip8_flags_test")
+            .join("flags.bin");
+        let _ = fs::remove_file(&path);
+        let mut flags = RplFlags::open(path.clone());
+        assert_eq!(flags.values(), [0; FLAG_COUNT]);
+        flags.store(&[1, 2, 3]).unwrap();
+        flags.store(&[9]).unwrap();
+
+        let reopened = RplFlags::open(path.clone());
+        assert_eq!(&reopened.values()[..4], [9, 2, 3, 0]);
+        assert_eq!(fs::read(&path).unwrap().len(), FLAG_COUNT);
+        fs::remove_file(&path).unwrap();
+    }
+}
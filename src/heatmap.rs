@@ -0,0 +1,204 @@
+use crate::analysis::instruction_length;
+use crate::ram::{Access, AccessKind};
+use fltk::{prelude::*, *};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Memory is shown as a square of 64x64 addresses, each drawn as a 6x6 block
+const SIDE: usize = 64;
+const MEMORY_SIZE: usize = SIDE * SIDE;
+const VIEW_SCALE: usize = 6;
+// Share of the heat an address keeps at every window update (30 times per second)
+const DECAY: f32 = 0.93;
+// Heat at which a channel is about two thirds bright
+const BRIGHTNESS: f32 = 4.0;
+
+/// Read, write and execute counts per address, decaying so that recent activity stands out
+pub struct Heatmap {
+    read: Vec<f32>,
+    written: Vec<f32>,
+    executed: Vec<f32>,
+}
+
+fn channel(heat: f32) -> u8 {
+    (255.0 * (1.0 - (-heat / BRIGHTNESS).exp())) as u8
+}
+
+impl Heatmap {
+    pub fn default() -> Self {
+        Heatmap {
+            read: vec![0.0; MEMORY_SIZE],
+            written: vec![0.0; MEMORY_SIZE],
+            executed: vec![0.0; MEMORY_SIZE],
+        }
+    }
+
+    /// Called for every fetched instruction, heats all of its bytes
+    pub fn record_fetch(&mut self, address: u16, opcode: u16) {
+        let start = address as usize;
+        let end = (start + instruction_length(opcode) as usize).min(MEMORY_SIZE);
+        for heat in &mut self.executed[start.min(end)..end] {
+            *heat += 1.0;
+        }
+    }
+
+    pub fn record_accesses(&mut self, accesses: &[Access]) {
+        for access in accesses {
+            let counts = match access.kind {
+                AccessKind::Read => &mut self.read,
+                AccessKind::Write => &mut self.written,
+            };
+            if let Some(heat) = counts.get_mut(access.address) {
+                *heat += 1.0;
+            }
+        }
+    }
+
+    pub fn decay(&mut self, factor: f32) {
+        for counts in [&mut self.read, &mut self.written, &mut self.executed] {
+            for heat in counts.iter_mut() {
+                *heat *= factor;
+            }
+        }
+    }
+
+    /// Heat of every address as RGB: red for writes, green for execution, blue for reads
+    pub fn pixels(&self) -> Vec<u8> {
+        (0..MEMORY_SIZE)
+            .flat_map(|a| {
+                [
+                    channel(self.written[a]),
+                    channel(self.executed[a]),
+                    channel(self.read[a]),
+                ]
+            })
+            .collect()
+    }
+
+    fn describe(&self, address: usize) -> String {
+        format!(
+            "{:#05x}  read {:.1}  written {:.1}  executed {:.1}",
+            address, self.read[address], self.written[address], self.executed[address]
+        )
+    }
+}
+
+/// Window showing the heatmap of the running program, address 0 at the top left and one
+/// row per 64 bytes
+pub struct HeatmapViewer {
+    window: window::Window,
+    view: widget::Widget,
+    status: frame::Frame,
+    // Pixels of the last update and the address under the mouse
+    state: Rc<RefCell<(Vec<u8>, Option<usize>)>>,
+}
+
+impl HeatmapViewer {
+    pub fn new() -> Self {
+        let size = (SIDE * VIEW_SCALE) as i32;
+        let mut window = window::Window::new(760, 100, size + 10, size + 60, "Memory heatmap");
+        let mut view = widget::Widget::new(5, 5, size, size, None);
+        let mut legend = frame::Frame::new(5, size + 8, size, 20, None);
+        legend.set_label("red: write  green: execute  blue: read");
+        legend.set_label_size(12);
+        let mut status = frame::Frame::new(5, size + 32, size, 20, None);
+        status.set_label_size(12);
+        window.end();
+        window.show();
+
+        let state = Rc::new(RefCell::new((vec![0; MEMORY_SIZE * 3], None)));
+        let draw_state = state.clone();
+        view.draw(move |w| {
+            let state = draw_state.borrow();
+            let scaled: Vec<u8> = state
+                .0
+                .chunks(SIDE * 3)
+                .flat_map(|row| {
+                    let line: Vec<u8> = row
+                        .chunks(3)
+                        .flat_map(|rgb| rgb.repeat(VIEW_SCALE))
+                        .collect();
+                    line.repeat(VIEW_SCALE)
+                })
+                .collect();
+            let _ = draw::draw_image(&scaled, w.x(), w.y(), w.w(), w.h(), enums::ColorDepth::Rgb8);
+        });
+
+        let hover_state = state.clone();
+        view.handle(move |w, event| match event {
+            enums::Event::Move | enums::Event::Enter => {
+                let x = (app::event_x() - w.x()).max(0) as usize / VIEW_SCALE;
+                let y = (app::event_y() - w.y()).max(0) as usize / VIEW_SCALE;
+                hover_state.borrow_mut().1 = Some((y * SIDE + x).min(MEMORY_SIZE - 1));
+                true
+            }
+            enums::Event::Leave => {
+                hover_state.borrow_mut().1 = None;
+                true
+            }
+            _ => false,
+        });
+
+        HeatmapViewer {
+            window,
+            view,
+            status,
+            state,
+        }
+    }
+
+    /// Shows the current heat and lets it cool down
+    pub fn update(&mut self, heatmap: &mut Heatmap) {
+        if self.window.shown() {
+            let mut state = self.state.borrow_mut();
+            state.0 = heatmap.pixels();
+            match state.1 {
+                Some(address) => self.status.set_label(&heatmap.describe(address)),
+                None => self.status.set_label(""),
+            }
+            self.view.redraw();
+        }
+        heatmap.decay(DECAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heat() {
+        let mut heatmap = Heatmap::default();
+        heatmap.record_fetch(0x200, 0x00E0);
+        heatmap.record_fetch(0x202, 0xF000);
+        heatmap.record_accesses(&[
+            Access {
+                address: 0x300,
+                kind: AccessKind::Read,
+                value: 0,
+            },
+            Access {
+                address: 0x301,
+                kind: AccessKind::Write,
+                value: 1,
+            },
+        ]);
+        // A fetch at the very end of memory does not run past it
+        heatmap.record_fetch(0xFFF, 0x00E0);
+
+        let pixels = heatmap.pixels();
+        let rgb = |address: usize| &pixels[address * 3..address * 3 + 3];
+        assert_eq!(rgb(0x201), [0, 56, 0]);
+        assert_eq!(rgb(0x205), [0, 56, 0]);
+        assert_eq!(rgb(0x206), [0, 0, 0]);
+        assert_eq!(rgb(0x300), [0, 0, 56]);
+        assert_eq!(rgb(0x301), [56, 0, 0]);
+        assert_eq!(rgb(0xFFF), [0, 56, 0]);
+
+        heatmap.decay(0.5);
+        assert_eq!(
+            heatmap.describe(0x200),
+            "0x200  read 0.0  written 0.0  executed 0.5"
+        );
+    }
+}
@@ -0,0 +1,403 @@
+use crate::chip8x::ColourMap;
+use crate::keyboard::InputState;
+use crate::megachip::MegaChip;
+use crate::register::Reg;
+use crate::stack::Stack;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+
+// Instructions between two checkpoints, about 1.4s at 720 instructions per second
+const CHECKPOINT_INTERVAL: u64 = 1000;
+// Roughly 15 minutes of history at ~7KB per checkpoint
+const MAX_CHECKPOINTS: usize = 600;
+
+/// Complete machine state, enough to resume execution deterministically
+#[derive(Clone)]
+pub struct Snapshot {
+    pub reg: Reg,
+    pub memory: Vec<u8>,
+    pub cart_size: usize,
+    pub stack: Stack,
+    pub pixels: Vec<Vec<bool>>,
+    pub found_key: Option<u8>,
+    pub rng: StdRng,
+    pub input: InputState,
+    pub cycles: u64,
+    pub mega: Option<MegaChip>,
+    // CHIP-8X colour board and sound
+    pub colours: Option<ColourMap>,
+    pub tone_port: u8,
+}
+
+// Saved state files start with this magic and a format version
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 1;
+// Marker for `None` in optional key fields
+const NO_KEY: u8 = 0xFF;
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Cursor over the bytes of a saved state
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl StateReader<'_> {
+    pub fn bytes(&mut self, count: usize) -> io::Result<&[u8]> {
+        if self.pos + count > self.data.len() {
+            return Err(invalid("Saved state is truncated"));
+        }
+        self.pos += count;
+        Ok(&self.data[self.pos - count..self.pos])
+    }
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    pub fn u32(&mut self) -> io::Result<u32> {
+        let mut value = [0; 4];
+        value.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(value))
+    }
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let mut value = [0; 8];
+        value.copy_from_slice(self.bytes(8)?);
+        Ok(u64::from_le_bytes(value))
+    }
+    fn key(&mut self) -> io::Result<Option<u8>> {
+        let key = self.u8()?;
+        Ok(if key == NO_KEY { None } else { Some(key) })
+    }
+}
+
+impl Snapshot {
+    /// Serializes the state to a file. The random number generator is not saved, so a loaded
+    /// state continues with fresh random numbers.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = Vec::new();
+        out.extend_from_slice(STATE_MAGIC);
+        out.push(STATE_VERSION);
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&self.reg.v);
+        out.extend_from_slice(&self.reg.i.to_le_bytes());
+        out.push(self.reg.delay_timer);
+        out.push(self.reg.sound_time);
+        out.extend_from_slice(&self.reg.pc.to_le_bytes());
+        out.push(self.reg.sp);
+        let stack = self.stack.as_slice();
+        out.push(stack.len() as u8);
+        for item in stack {
+            out.extend_from_slice(&item.to_le_bytes());
+        }
+        out.push(self.found_key.unwrap_or(NO_KEY));
+        for pressed in self.input.pressed {
+            out.push(pressed as u8);
+        }
+        out.push(self.input.last_key_down.unwrap_or(NO_KEY));
+        out.push(self.input.last_key_up.unwrap_or(NO_KEY));
+        out.extend_from_slice(&(self.cart_size as u32).to_le_bytes());
+        out.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.memory[..]);
+        out.push(self.pixels[0].len() as u8);
+        out.push(self.pixels.len() as u8);
+        for row in self.pixels.iter() {
+            for byte in row.chunks(8) {
+                out.push(byte.iter().fold(0, |acc, p| (acc << 1) | *p as u8));
+            }
+        }
+        match &self.mega {
+            Some(mega) => {
+                out.push(1);
+                mega.save_state(&mut out);
+            }
+            None => out.push(0),
+        }
+        match &self.colours {
+            Some(colours) => {
+                out.push(1);
+                colours.save_state(&mut out);
+            }
+            None => out.push(0),
+        }
+        out.push(self.tone_port);
+        fs::write(path, out)
+    }
+
+    pub fn load(path: &str) -> io::Result<Snapshot> {
+        let data = fs::read(path)?;
+        let mut reader = StateReader {
+            data: &data,
+            pos: 0,
+        };
+        if reader.bytes(4)? != STATE_MAGIC {
+            return Err(invalid("Not a saved state file"));
+        }
+        if reader.u8()? != STATE_VERSION {
+            return Err(invalid("Unsupported saved state version"));
+        }
+        let cycles = reader.u64()?;
+        let mut reg = Reg::default();
+        reg.v.copy_from_slice(reader.bytes(16)?);
+        reg.i = reader.u32()?;
+        reg.delay_timer = reader.u8()?;
+        reg.sound_time = reader.u8()?;
+        reg.pc = reader.u16()?;
+        reg.sp = reader.u8()?;
+        let mut stack = Stack::default();
+        for _ in 0..reader.u8()? {
+            stack.push(reader.u16()?).map_err(invalid)?;
+        }
+        let found_key = reader.key()?;
+        let mut input = InputState::default();
+        for pressed in input.pressed.iter_mut() {
+            *pressed = reader.u8()? != 0;
+        }
+        input.last_key_down = reader.key()?;
+        input.last_key_up = reader.key()?;
+        let cart_size = reader.u32()? as usize;
+        let memory_size = reader.u32()? as usize;
+        if cart_size > memory_size {
+            return Err(invalid("Saved state program ends past the end of memory"));
+        }
+        let memory = reader.bytes(memory_size)?.to_vec();
+        let (width, height) = (reader.u8()? as usize, reader.u8()? as usize);
+        if width == 0 || height == 0 {
+            return Err(invalid("Saved state has an empty display"));
+        }
+        let mut pixels = vec![vec![false; width]; height];
+        for row in pixels.iter_mut() {
+            for (chunk, byte) in row.chunks_mut(8).zip(reader.bytes(width.div_ceil(8))?) {
+                for (bit, pixel) in chunk.iter_mut().enumerate() {
+                    *pixel = (byte >> (7 - bit)) & 1 == 1;
+                }
+            }
+        }
+        let mega = match reader.u8()? {
+            0 => None,
+            _ => Some(MegaChip::load_state(&mut reader)?),
+        };
+        let colours = match reader.u8()? {
+            0 => None,
+            _ => Some(ColourMap::load_state(&mut reader)?),
+        };
+        let tone_port = reader.u8()?;
+        Ok(Snapshot {
+            reg,
+            memory,
+            cart_size,
+            stack,
+            pixels,
+            found_key,
+            rng: StdRng::from_entropy(),
+            input,
+            cycles,
+            mega,
+            colours,
+            tone_port,
+        })
+    }
+}
+
+/// Periodic checkpoints plus a log of everything coming from outside the CPU (input and timer
+/// ticks), so any earlier instruction can be reached by restoring a checkpoint and re-executing.
+pub struct History {
+    checkpoints: VecDeque<Snapshot>,
+    // Cycle at which the input changed to the given state
+    inputs: Vec<(u64, InputState)>,
+    // Cycles at which the 60Hz timers ticked, a tick at cycle c happens before instruction c
+    ticks: Vec<u64>,
+    // Number of instructions executed so far in the recorded timeline
+    end: u64,
+}
+
+impl History {
+    pub fn default() -> Self {
+        History {
+            checkpoints: VecDeque::new(),
+            inputs: Vec::new(),
+            ticks: Vec::new(),
+            end: 0,
+        }
+    }
+
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn set_end(&mut self, cycles: u64) {
+        self.end = self.end.max(cycles);
+    }
+
+    /// The oldest cycle that can still be reached
+    pub fn start(&self) -> Option<u64> {
+        self.checkpoints.front().map(|c| c.cycles)
+    }
+
+    pub fn needs_checkpoint(&self, cycles: u64) -> bool {
+        match self.checkpoints.back() {
+            Some(last) => cycles >= last.cycles + CHECKPOINT_INTERVAL,
+            None => true,
+        }
+    }
+
+    pub fn add_checkpoint(&mut self, snapshot: Snapshot) {
+        self.checkpoints.push_back(snapshot);
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+            // Logs older than the oldest checkpoint can never be replayed again
+            let start = self.checkpoints[0].cycles;
+            let keep_from = self.inputs.partition_point(|(c, _)| *c <= start);
+            if keep_from > 1 {
+                self.inputs.drain(..keep_from - 1);
+            }
+            let keep_from = self.ticks.partition_point(|c| *c < start);
+            self.ticks.drain(..keep_from);
+        }
+    }
+
+    /// Latest checkpoint taken at or before `cycles`
+    pub fn checkpoint_before(&self, cycles: u64) -> Option<&Snapshot> {
+        self.checkpoints.iter().rev().find(|c| c.cycles <= cycles)
+    }
+
+    pub fn checkpoint_cycles(&self) -> Vec<u64> {
+        self.checkpoints.iter().map(|c| c.cycles).collect()
+    }
+
+    pub fn record_input(&mut self, cycles: u64, input: InputState) {
+        match self.inputs.last() {
+            Some((_, last)) if *last == input => {}
+            _ => self.inputs.push((cycles, input)),
+        }
+    }
+
+    pub fn record_tick(&mut self, cycles: u64) {
+        self.ticks.push(cycles);
+    }
+
+    pub fn input_at(&self, cycles: u64) -> InputState {
+        let index = self.inputs.partition_point(|(c, _)| *c <= cycles);
+        if index == 0 {
+            InputState::default()
+        } else {
+            self.inputs[index - 1].1
+        }
+    }
+
+    pub fn ticks_at(&self, cycles: u64) -> usize {
+        let start = self.ticks.partition_point(|c| *c < cycles);
+        let end = self.ticks.partition_point(|c| *c <= cycles);
+        end - start
+    }
+
+    /// Forgets everything recorded after `cycles`, used when the state was changed by hand and
+    /// the recorded future can no longer be replayed
+    pub fn truncate(&mut self, cycles: u64) {
+        self.checkpoints.retain(|c| c.cycles <= cycles);
+        self.inputs.retain(|(c, _)| *c <= cycles);
+        self.ticks.retain(|c| *c <= cycles);
+        self.end = cycles;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pressed(key: usize) -> InputState {
+        let mut input = InputState::default();
+        input.pressed[key] = true;
+        input
+    }
+
+    #[test]
+    fn test_input_log() {
+        let mut history = History::default();
+        history.record_input(0, InputState::default());
+        history.record_input(5, pressed(1));
+        history.record_input(6, pressed(1));
+        history.record_input(9, InputState::default());
+        assert_eq!(history.inputs.len(), 3);
+        assert_eq!(history.input_at(4), InputState::default());
+        assert_eq!(history.input_at(5), pressed(1));
+        assert_eq!(history.input_at(8), pressed(1));
+        assert_eq!(history.input_at(100), InputState::default());
+    }
+
+    #[test]
+    fn test_ticks() {
+        let mut history = History::default();
+        history.record_tick(3);
+        history.record_tick(3);
+        history.record_tick(10);
+        assert_eq!(history.ticks_at(2), 0);
+        assert_eq!(history.ticks_at(3), 2);
+        assert_eq!(history.ticks_at(10), 1);
+        history.truncate(5);
+        assert_eq!(history.ticks_at(10), 0);
+        assert_eq!(history.end(), 5);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let mut stack = Stack::default();
+        stack.push(0x204).unwrap();
+        stack.push(0x3A2).unwrap();
+        let mut snapshot = Snapshot {
+            reg: Reg {
+                v: [7; 16],
+                i: 0x345,
+                delay_timer: 9,
+                sound_time: 2,
+                pc: 0x3A4,
+                sp: 2,
+            },
+            // A 2K VIP
+            memory: vec![0xAB; 0x800],
+            cart_size: 0x280,
+            stack,
+            // The ETI-660 display
+            pixels: vec![vec![false; 64]; 48],
+            found_key: Some(4),
+            rng: StdRng::from_entropy(),
+            input: pressed(0xF),
+            cycles: 123456,
+            mega: None,
+            colours: None,
+            tone_port: 0,
+        };
+        snapshot.pixels[3][9] = true;
+        snapshot.pixels[47][63] = true;
+        let path = std::env::temp_dir().join("chip8_history_test.c8s");
+        let path = path.to_str().unwrap();
+        snapshot.save(path).unwrap();
+        let loaded = Snapshot::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded.reg, snapshot.reg);
+        assert_eq!(loaded.memory, snapshot.memory);
+        assert_eq!(loaded.cart_size, 0x280);
+        assert_eq!(loaded.stack.as_slice(), &[0x204, 0x3A2]);
+        assert_eq!(loaded.pixels, snapshot.pixels);
+        assert_eq!(loaded.found_key, Some(4));
+        assert_eq!(loaded.input, snapshot.input);
+        assert_eq!(loaded.cycles, 123456);
+
+        // A program filling all of XO-CHIP's 64K
+        snapshot.memory = vec![0; 0x10000];
+        snapshot.cart_size = 0x10000;
+        snapshot.save(path).unwrap();
+        let loaded = Snapshot::load(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(loaded.cart_size, 0x10000);
+    }
+}
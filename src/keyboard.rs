@@ -1,3 +1,13 @@
+/// Keypad state as seen by the CPU for one instruction
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct InputState {
+    pub pressed: [bool; 16],
+    // The second keypad of CHIP-8X
+    pub second: [bool; 16],
+    pub last_key_down: Option<u8>,
+    pub last_key_up: Option<u8>,
+}
+
 pub fn map_modern_to_chip8(modern_key: char) -> Option<u8> {
     match modern_key {
         '1' => Some(0x1),
@@ -19,3 +29,66 @@ pub fn map_modern_to_chip8(modern_key: char) -> Option<u8> {
         _ => None,
     }
 }
+
+/// CHIP-8X's second keypad, laid out like the first one four keyboard columns to the right
+pub fn map_modern_to_second_keypad(modern_key: char) -> Option<u8> {
+    let index = "5678tyuighjkbnm,".find(modern_key.to_ascii_lowercase())?;
+    map_modern_to_chip8("1234qwerasdfzxcv".chars().nth(index)?)
+}
+
+/// Keyboard character for each of the 16 CHIP-8 keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyMap {
+    keys: [char; 16],
+}
+
+impl KeyMap {
+    pub fn default() -> Self {
+        let mut keys = [' '; 16];
+        for modern_key in "1234qwerasdfzxcv".chars() {
+            keys[map_modern_to_chip8(modern_key).unwrap() as usize] = modern_key;
+        }
+        KeyMap { keys }
+    }
+
+    /// Moves a CHIP-8 key to another keyboard character, swapping with the key that used it
+    pub fn remap(&mut self, chip8_key: u8, modern_key: char) {
+        let modern_key = modern_key.to_ascii_lowercase();
+        if let Some(other) = self.chip8_key_for(modern_key) {
+            self.keys[other as usize] = self.keys[chip8_key as usize];
+        }
+        self.keys[chip8_key as usize] = modern_key;
+    }
+
+    pub fn chip8_key_for(&self, modern_key: char) -> Option<u8> {
+        let modern_key = modern_key.to_ascii_lowercase();
+        self.keys
+            .iter()
+            .position(|k| *k == modern_key)
+            .map(|k| k as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keymap() {
+        let mut keymap = KeyMap::default();
+        assert_eq!(keymap.chip8_key_for('w'), Some(0x5));
+        assert_eq!(keymap.chip8_key_for('V'), Some(0xF));
+        // Arrow-style layout: move 5 to 'i', 'i' was unused
+        keymap.remap(0x5, 'i');
+        assert_eq!(keymap.chip8_key_for('i'), Some(0x5));
+        assert_eq!(keymap.chip8_key_for('w'), None);
+        // 'q' already belongs to 4, so 4 takes over the old key of 6
+        keymap.remap(0x6, 'q');
+        assert_eq!(keymap.chip8_key_for('q'), Some(0x6));
+        assert_eq!(keymap.chip8_key_for('e'), Some(0x4));
+
+        assert_eq!(map_modern_to_second_keypad('T'), Some(0x4));
+        assert_eq!(map_modern_to_second_keypad(','), Some(0xF));
+        assert_eq!(map_modern_to_second_keypad('1'), None);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::quirks::Platform;
+use std::collections::BTreeMap;
+
+/// What a 0nnn call to 1802 machine code does, as there is no 1802 to run it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MachineCodeMode {
+    /// Carry on as if the routine returned straight away
+    Ignore,
+    /// Stop the program with an error
+    Halt,
+    /// Run the native routine registered for the address, halt if there is none
+    Native,
+}
+
+impl MachineCodeMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "ignore" => Some(MachineCodeMode::Ignore),
+            "halt" => Some(MachineCodeMode::Halt),
+            "native" => Some(MachineCodeMode::Native),
+            _ => None,
+        }
+    }
+}
+
+/// Rust versions of machine-code routines that CHIP-8 programs call. Only routines whose
+/// effect is known are emulated, a program calling anything else has to be run on the VIP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Routine {
+    /// Clears the display, like 00E0, as the screen clearing routines of hi-res interpreters do
+    Clear,
+    /// Steps the CHIP-8X background colour
+    CycleBackground,
+}
+
+impl Routine {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "clear" => Some(Routine::Clear),
+            "background" => Some(Routine::CycleBackground),
+            _ => None,
+        }
+    }
+}
+
+/// What a 0nnn call does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dispatch {
+    Run(Routine),
+    /// Carry on with the next instruction
+    Skip,
+    /// Stop the program on the call
+    Halt,
+}
+
+// Routines that are part of an interpreter rather than of the programs, which call them with
+// 0nnn. They run whatever the mode.
+fn built_in(platform: Platform) -> &'static [(u16, Routine)] {
+    match platform {
+        // The two-page display interpreter clears its 64x64 screen with 0230
+        Platform::TwoPage => &[(0x230, Routine::Clear)],
+        // The CHIP-8X interpreter changes the background colour with 02A0
+        Platform::Chip8X => &[(0x2A0, Routine::CycleBackground)],
+        _ => &[],
+    }
+}
+
+/// How 0nnn calls are handled and the native routines registered for their addresses
+pub struct MachineCode {
+    pub mode: MachineCodeMode,
+    built_in: BTreeMap<u16, Routine>,
+    routines: BTreeMap<u16, Routine>,
+}
+
+impl MachineCode {
+    pub fn default() -> Self {
+        MachineCode {
+            mode: MachineCodeMode::Native,
+            built_in: BTreeMap::new(),
+            routines: BTreeMap::new(),
+        }
+    }
+
+    /// Uses the helpers of the interpreter for `platform`
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.built_in = built_in(platform).iter().copied().collect();
+    }
+
+    pub fn register(&mut self, address: u16, routine: Routine) {
+        self.routines.insert(address, routine);
+    }
+
+    pub fn dispatch(&self, address: u16) -> Dispatch {
+        if let Some(routine) = self.built_in.get(&address) {
+            return Dispatch::Run(*routine);
+        }
+        match self.mode {
+            MachineCodeMode::Ignore => Dispatch::Skip,
+            MachineCodeMode::Halt => Dispatch::Halt,
+            MachineCodeMode::Native => match self.routines.get(&address) {
+                Some(routine) => Dispatch::Run(*routine),
+                None => Dispatch::Halt,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dispatch() {
+        let mut machine_code = MachineCode::default();
+        assert_eq!(machine_code.dispatch(0x230), Dispatch::Halt);
+        machine_code.register(0x230, Routine::parse("Clear").unwrap());
+        assert_eq!(machine_code.dispatch(0x230), Dispatch::Run(Routine::Clear));
+        assert_eq!(machine_code.dispatch(0x232), Dispatch::Halt);
+
+        machine_code.mode = MachineCodeMode::Halt;
+        assert_eq!(machine_code.dispatch(0x230), Dispatch::Halt);
+        machine_code.mode = MachineCodeMode::parse("ignore").unwrap();
+        assert_eq!(machine_code.dispatch(0x232), Dispatch::Skip);
+        assert_eq!(Routine::parse("invert"), None);
+
+        // Interpreter helpers run even when other calls are ignored
+        machine_code.set_platform(Platform::Chip8X);
+        assert_eq!(
+            machine_code.dispatch(0x2A0),
+            Dispatch::Run(Routine::CycleBackground)
+        );
+        machine_code.set_platform(Platform::TwoPage);
+        assert_eq!(machine_code.dispatch(0x230), Dispatch::Run(Routine::Clear));
+        machine_code.set_platform(Platform::Chip8);
+        assert_eq!(machine_code.dispatch(0x2A0), Dispatch::Skip);
+    }
+}
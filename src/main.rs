@@ -1,54 +1,1051 @@
+mod analysis;
+mod cartridge;
+mod cdp1802;
+mod cfg;
+mod chip8x;
+mod console;
+mod coverage;
 mod cpu;
+mod debugger;
+mod decompile;
+mod disasm;
 mod display;
+mod expr;
+mod flags;
+mod fonts;
+mod heatmap;
+mod history;
 mod keyboard;
+mod machine_code;
+mod megachip;
+mod memview;
+mod octo;
+mod profiler;
+mod quirks;
 mod ram;
 mod register;
+mod romdb;
+mod spriteview;
 mod stack;
-use clap::Parser;
+mod symbols;
+mod trace;
+mod vip;
+use analysis::Guess;
+use cfg::ControlFlowGraph;
+use clap::{Parser, Subcommand};
+use console::Console;
+use coverage::Coverage;
 use cpu::CPU;
+use debugger::{BreakKind, Debugger};
+use decompile::Decompiler;
+use disasm::Disassembly;
 use display::EmuDisplay;
+use flags::RplFlags;
 use fltk::{prelude::*, *};
-use rodio::{source::SineWave, source::Source, OutputStream};
+use fonts::FontSet;
+use heatmap::{Heatmap, HeatmapViewer};
+use history::{History, Snapshot};
+use machine_code::{MachineCodeMode, Routine};
+use megachip::SoundCommand;
+use memview::MemoryViewer;
+use profiler::Profiler;
+use quirks::Platform;
+use rodio::{buffer::SamplesBuffer, source::SineWave, source::Source, OutputStream, Sink};
+use romdb::{RomDatabase, RomInfo};
+use spriteview::{SpriteSheet, SpriteSize, SpriteViewer};
 use std::cell::RefCell;
+use std::path::Path;
 use std::rc::Rc;
 use std::time::Duration;
+use symbols::SymbolMap;
+use vip::Vip;
 /// Chip-8 Emulator
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to the ROM file to load
-    #[arg(short, long)]
-    rom: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to the ROM file to load, an Octo cartridge (.gif) or Octo source (.8o)
+    #[arg(short, long, required = true)]
+    rom: Option<String>,
+    #[command(flatten)]
+    options: RunOptions,
 }
+
+/// Options for running a program
+#[derive(clap::Args, Debug)]
+struct RunOptions {
+    /// Extra ROM database (JSON) used to identify the ROM, can be repeated
+    #[arg(long, value_name = "FILE")]
+    rom_db: Vec<String>,
+    /// Platform whose quirks to use (chip8, chip8x, schip, xochip, megachip, eti660, twopage), overrides the ROM database
+    #[arg(long, value_parser = parse_platform)]
+    platform: Option<Platform>,
+    /// Built-in font (vip, dream6800, eti660, fish, schip, octo), overrides the ROM database
+    #[arg(long, value_parser = parse_font)]
+    font: Option<FontSet>,
+    /// Address the font is loaded at
+    #[arg(long, value_name = "ADDR", value_parser = parse_font_address)]
+    font_address: Option<u16>,
+    /// Address programs are loaded at and start running from, overrides the platform's
+    #[arg(long, value_name = "ADDR", value_parser = parse_address)]
+    load_address: Option<u16>,
+    /// Memory size, 2K, 3.5K, 4K, 64K, 16M or a number of bytes, overrides the platform's
+    #[arg(long, value_name = "SIZE", value_parser = parse_memory_size)]
+    memory_size: Option<usize>,
+    /// Make DXYN wait for the next 60Hz frame like the VIP (true or false), overrides the ROM
+    /// database
+    #[arg(long, value_name = "BOOL")]
+    display_wait: Option<bool>,
+    /// Instructions executed per 60Hz frame, overrides the ROM database
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    ipf: Option<u32>,
+    /// Print an execution profile (hot addresses, subroutines, opcodes, draws) on exit
+    #[arg(long)]
+    profile: bool,
+    /// Write the execution profile as JSON to this file on exit
+    #[arg(long, value_name = "FILE")]
+    profile_json: Option<String>,
+    /// Print code coverage (per subroutine, code that never ran) on exit
+    #[arg(long)]
+    coverage: bool,
+    /// Write code coverage as an LCOV tracefile to this file on exit, with source lines when
+    /// the program has a symbol map
+    #[arg(long, value_name = "FILE")]
+    coverage_lcov: Option<String>,
+    /// Write the executed addresses to this file on exit, for `disasm --coverage`
+    #[arg(long, value_name = "FILE")]
+    coverage_log: Option<String>,
+    /// Start with the debugger console attached to stdin (type `help` for commands) and record history for reverse stepping
+    #[arg(long)]
+    debug: bool,
+    /// Stop before executing this address, label, label+offset or file:line, can be repeated (implies --debug)
+    #[arg(long = "break", value_name = "ADDR")]
+    breakpoints: Vec<String>,
+    /// Load a file into memory at an address before running, as FILE@ADDR, can be repeated
+    #[arg(long = "load", value_name = "FILE@ADDR", value_parser = parse_blob)]
+    blobs: Vec<(String, u16)>,
+    /// Write bytes to memory before running, as ADDR=VALUE [VALUE...], can be repeated
+    #[arg(long = "poke", value_name = "ADDR=VALUE")]
+    pokes: Vec<String>,
+    /// Start running at this address or label instead of the load address
+    #[arg(long, value_name = "ADDR")]
+    pc: Option<String>,
+    /// Symbol map of the ROM, by default the .sym file next to it is used if there is one
+    #[arg(long, value_name = "FILE")]
+    symbols: Option<String>,
+    /// Log every executed instruction to this file
+    #[arg(long, value_name = "FILE")]
+    trace: Option<String>,
+    /// Open a window showing memory, which can be edited while paused
+    #[arg(long)]
+    memory_viewer: bool,
+    /// Open a window rendering memory as sprites, following I by default
+    #[arg(long)]
+    sprite_viewer: bool,
+    /// Open a window showing which addresses are being read, written and executed
+    #[arg(long)]
+    heatmap: bool,
+    /// What 0nnn calls to 1802 machine code do: ignore them, halt, or run the native routine
+    /// registered with --native and halt on others. Helpers of the platform's interpreter,
+    /// like 0230 on twopage and 02A0 on chip8x, always run.
+    #[arg(long, value_name = "MODE", default_value = "native", value_parser = parse_machine_code)]
+    machine_code: MachineCodeMode,
+    /// Run a built-in routine (clear, background) for 0nnn calls to an address, as
+    /// ADDR=ROUTINE, can be repeated
+    #[arg(long = "native", value_name = "ADDR=ROUTINE", value_parser = parse_routine)]
+    routines: Vec<(u16, Routine)>,
+    /// Emulate a COSMAC VIP running this CHIP-8 interpreter image (loaded at 0x0000) instead of
+    /// the built-in interpreter, needs --vip-monitor
+    #[arg(long, value_name = "FILE", requires = "vip_monitor")]
+    vip_interpreter: Option<String>,
+    /// The VIP's 512-byte monitor ROM, used with --vip-interpreter
+    #[arg(long, value_name = "FILE", requires = "vip_interpreter")]
+    vip_monitor: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run a ROM, an Octo cartridge (.gif) or Octo source (.8o), which is compiled first
+    Run {
+        /// Path to the program
+        file: String,
+        #[command(flatten)]
+        options: Box<RunOptions>,
+    },
+    /// Compile Octo source to a ROM, with a symbol map (.sym) next to it for the debugger
+    Compile {
+        /// Path to the Octo source
+        source: String,
+        /// Path of the ROM to write, the source with a .ch8 extension by default
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Print a hex dump of a saved state file
+    Dump {
+        /// Path to the state file, saved with the debugger's `save` command
+        state: String,
+        /// First address to print
+        #[arg(long, default_value = "0", value_parser = parse_address)]
+        start: u16,
+        /// Last address to print
+        #[arg(long, default_value = "0xfff", value_parser = parse_address)]
+        end: u16,
+    },
+    /// Identify a ROM and print the platform and quirks it would run with
+    Info {
+        /// Path to the ROM file
+        rom: String,
+        /// Extra ROM database (JSON) used to identify the ROM, can be repeated
+        #[arg(long, value_name = "FILE")]
+        rom_db: Vec<String>,
+        /// Address the program is loaded at and starts running from
+        #[arg(long, value_name = "ADDR", default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+    },
+    /// Disassemble a ROM to Octo source that compiles back to the same ROM, telling code from
+    /// data by following the program flow
+    Disasm {
+        /// Path to the ROM file
+        rom: String,
+        /// Coverage or trace log (--trace) of a run, its addresses are treated as code too
+        #[arg(long, value_name = "FILE")]
+        coverage: Vec<String>,
+        /// Address the program is loaded at and starts running from
+        #[arg(long, value_name = "ADDR", default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Write the source to this file instead of printing it
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Decompile a ROM to structured pseudocode, one function per subroutine
+    Decompile {
+        /// Path to the ROM file
+        rom: String,
+        /// Address the program is loaded at and starts running from
+        #[arg(long, value_name = "ADDR", default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Write the pseudocode to this file instead of printing it
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Build the control-flow graph of a ROM: basic blocks, subroutines, jumps, calls and skips
+    Cfg {
+        /// Path to the ROM file
+        rom: String,
+        /// Output format, `dot` (Graphviz) or `json`
+        #[arg(long, default_value = "dot")]
+        format: String,
+        /// Address the program is loaded at and starts running from
+        #[arg(long, value_name = "ADDR", default_value = "0x200", value_parser = parse_address)]
+        load_address: u16,
+        /// Write the graph to this file instead of printing it
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Render memory of a ROM or saved state as sprites, as text or as a PNG sprite sheet
+    Sprites {
+        /// Path to a ROM or a saved state file
+        file: String,
+        /// Address of the first sprite
+        #[arg(long, default_value = "0x200", value_parser = parse_address)]
+        start: u16,
+        /// Number of sprites to render
+        #[arg(long, default_value_t = 16)]
+        count: usize,
+        /// Rows per sprite, ignored with --schip
+        #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=15))]
+        height: u8,
+        /// Render 16x16 SCHIP sprites
+        #[arg(long)]
+        schip: bool,
+        /// Sprites per row of the sheet
+        #[arg(long, default_value_t = 8)]
+        columns: usize,
+        /// Write the sheet to this PNG file instead of printing it
+        #[arg(long, value_name = "PNG")]
+        output: Option<String>,
+        /// Size of a sprite pixel in the PNG
+        #[arg(long, default_value_t = 4)]
+        scale: usize,
+    },
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    match expr::parse_number(text) {
+        Some(address) if (0..=0xFFFF).contains(&address) => Ok(address as u16),
+        _ => Err(format!("invalid address '{}'", text)),
+    }
+}
+fn parse_platform(text: &str) -> Result<Platform, String> {
+    Platform::parse(text).ok_or(format!("unknown platform '{}'", text))
+}
+fn parse_machine_code(text: &str) -> Result<MachineCodeMode, String> {
+    MachineCodeMode::parse(text).ok_or(format!("unknown machine code mode '{}'", text))
+}
+fn parse_routine(text: &str) -> Result<(u16, Routine), String> {
+    let (address, name) = text.split_once('=').ok_or("expected ADDR=ROUTINE")?;
+    let routine = Routine::parse(name).ok_or(format!("unknown routine '{}'", name))?;
+    match parse_address(address)? {
+        address if address <= 0xFFF => Ok((address, routine)),
+        _ => Err(format!("0nnn cannot call '{}'", address)),
+    }
+}
+fn parse_blob(text: &str) -> Result<(String, u16), String> {
+    let (path, address) = text.rsplit_once('@').ok_or("expected FILE@ADDR")?;
+    Ok((path.to_string(), parse_address(address)?))
+}
+fn parse_memory_size(text: &str) -> Result<usize, String> {
+    let size = match text.to_ascii_lowercase().as_str() {
+        "2k" => Some(0x800),
+        "3.5k" => Some(0xE00),
+        "4k" => Some(0x1000),
+        "64k" => Some(0x10000),
+        "16m" => Some(0x1000000),
+        _ => expr::parse_number(text).map(|size| size as usize),
+    };
+    match size {
+        Some(size) if (0x200..=ram::MAX_MEMORY_SIZE).contains(&size) => Ok(size),
+        _ => Err(format!("invalid memory size '{}'", text)),
+    }
+}
+fn parse_font(text: &str) -> Result<FontSet, String> {
+    FontSet::parse(text).ok_or(format!("unknown font '{}'", text))
+}
+// The fonts have to fit below the program
+fn parse_font_address(text: &str) -> Result<u16, String> {
+    match parse_address(text)? {
+        address if address as usize + fonts::MAX_FONT_SIZE <= 0x200 => Ok(address),
+        _ => Err(format!("font at '{}' would overlap the program", text)),
+    }
+}
+
+fn dump_state(path: &str, start: u16, end: u16) {
+    match Snapshot::load(path) {
+        Ok(state) => print!(
+            "{}",
+            memview::hex_dump(
+                &state.memory[..],
+                start as usize,
+                end as usize + 1,
+                &state.reg,
+                state.cart_size
+            )
+        ),
+        Err(e) => eprintln!("Could not load {}: {}", path, e),
+    }
+}
+
+fn load_rom_db(paths: &[String]) -> RomDatabase {
+    let mut rom_db = RomDatabase::bundled();
+    for path in paths {
+        match RomDatabase::load(path) {
+            Ok(db) => rom_db.merge(db),
+            Err(e) => eprintln!("Could not load ROM database {}: {}", path, e),
+        }
+    }
+    rom_db
+}
+
+// Settings from the ROM database, or guessed from the code for unknown ROMs
+fn identify_rom(memory: &ram::RAM, rom_db: &RomDatabase) -> (RomInfo, Option<Guess>) {
+    match rom_db.lookup(&memory.rom_sha1) {
+        Some(info) => (info.clone(), None),
+        None => {
+            let start = memory.load_address as u16;
+            let guess = analysis::guess_profile(&memory.cart, start, memory.cart_size);
+            let mut info = RomInfo::unknown(guess.platform);
+            info.quirks = guess.quirks;
+            (info, Some(guess))
+        }
+    }
+}
+
+// A ROM loaded on its own for the analysis commands
+fn load_program(path: &str, load_address: u16) -> Option<ram::RAM> {
+    let mut memory = ram::RAM::new(ram::ADDRESS_SPACE, load_address as usize);
+    match memory.load(path) {
+        Ok(()) => Some(memory),
+        Err(e) => {
+            eprintln!("Could not load {}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn disassemble(path: &str, load_address: u16, coverage: &[String], output: Option<&str>) {
+    let memory = match load_program(path, load_address) {
+        Some(memory) => memory,
+        None => return,
+    };
+    let mut executed = Vec::new();
+    for log in coverage {
+        match std::fs::read_to_string(log) {
+            Ok(text) => executed.extend(disasm::parse_address_log(&text)),
+            Err(e) => eprintln!("Could not load {}: {}", log, e),
+        }
+    }
+    let source =
+        Disassembly::new(&memory.cart, load_address, memory.cart_size, &executed).to_source();
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, source) {
+                eprintln!("Could not write {}: {}", output, e);
+            }
+        }
+        None => print!("{}", source),
+    }
+}
+
+fn decompile(path: &str, load_address: u16, output: Option<&str>) {
+    let memory = match load_program(path, load_address) {
+        Some(memory) => memory,
+        None => return,
+    };
+    let text = Decompiler::new(&memory.cart, load_address, memory.cart_size).pseudocode();
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, text) {
+                eprintln!("Could not write {}: {}", output, e);
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn export_cfg(path: &str, load_address: u16, format: &str, output: Option<&str>) {
+    let memory = match load_program(path, load_address) {
+        Some(memory) => memory,
+        None => return,
+    };
+    let graph = ControlFlowGraph::new(&memory.cart, load_address, memory.cart_size);
+    let text = match format {
+        "dot" => graph.to_dot(&memory.cart),
+        "json" => serde_json::to_string_pretty(&graph.to_json(&memory.cart)).unwrap() + "\n",
+        _ => {
+            eprintln!("Unknown format '{}', expected dot or json", format);
+            return;
+        }
+    };
+    match output {
+        Some(output) => {
+            if let Err(e) = std::fs::write(output, text) {
+                eprintln!("Could not write {}: {}", output, e);
+            }
+        }
+        None => print!("{}", text),
+    }
+}
+
+fn print_info(path: &str, load_address: u16, rom_db_paths: &[String]) {
+    let memory = match load_program(path, load_address) {
+        Some(memory) => memory,
+        None => return,
+    };
+    let (info, guess) = identify_rom(&memory, &load_rom_db(rom_db_paths));
+    println!("File:     {}", path);
+    println!("Size:     {} bytes", memory.program().len());
+    println!("SHA-1:    {}", memory.rom_sha1);
+    match guess {
+        None => {
+            println!("Title:    {}", info.title);
+            if let Some(author) = &info.author {
+                println!("Author:   {}", author);
+            }
+            println!("Platform: {} (from the ROM database)", info.platform.name());
+        }
+        Some(guess) => {
+            println!(
+                "Platform: {} (guessed, {:.0}% confidence)",
+                info.platform.name(),
+                guess.confidence * 100.0
+            );
+            for reason in &guess.reasons {
+                println!("  - {}", reason);
+            }
+        }
+    }
+    println!("Quirks:   {}", info.quirks);
+    println!("IPF:      {}", info.ipf);
+    println!("Font:     {}", info.font.name());
+}
+
+// Compiles an Octo source file, errors are reported as `file:line: message`
+fn compile_file(path: &str) -> Result<octo::Program, String> {
+    let source = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut program =
+        octo::compile(&source).map_err(|e| format!("{}:{}: {}", path, e.line, e.message))?;
+    program.symbols.source = Some(path.to_string());
+    program.symbols.set_text(&source);
+    Ok(program)
+}
+
+fn compile(source: &str, output: Option<&str>) {
+    let program = match compile_file(source) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let rom_path = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(source)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .to_string(),
+    };
+    let symbols_path = Path::new(&rom_path)
+        .with_extension("sym")
+        .to_string_lossy()
+        .to_string();
+    if let Err(e) = std::fs::write(&rom_path, &program.rom) {
+        eprintln!("Could not write {}: {}", rom_path, e);
+    } else if let Err(e) = program.symbols.save(&symbols_path) {
+        eprintln!("Could not write {}: {}", symbols_path, e);
+    } else {
+        println!(
+            "Wrote {} ({} bytes) and {}",
+            rom_path,
+            program.rom.len(),
+            symbols_path
+        );
+    }
+}
+
+// Extra data, memory edits and the entry point given on the command line
+fn prepare_memory(cpu: &mut CPU, args: &RunOptions, symbols: &SymbolMap) -> Result<(), String> {
+    for (path, address) in &args.blobs {
+        let data = std::fs::read(path).map_err(|e| format!("Could not load {}: {}", path, e))?;
+        for (offset, byte) in data.iter().enumerate() {
+            cpu.poke(*address as usize + offset, *byte).map_err(|_| {
+                format!(
+                    "{} ({} bytes) does not fit in memory at {:#05x}",
+                    path,
+                    data.len(),
+                    address
+                )
+            })?;
+        }
+    }
+    for poke in &args.pokes {
+        let edits =
+            memview::parse_edit(poke).map_err(|e| format!("Invalid poke {}: {}", poke, e))?;
+        for (address, value) in edits {
+            cpu.poke(address, value)
+                .map_err(|e| format!("Invalid poke {}: {}", poke, e))?;
+        }
+    }
+    if let Some(pc) = &args.pc {
+        let address = symbols
+            .resolve(pc)
+            .map_err(|e| format!("Invalid entry point {}: {}", pc, e))?;
+        cpu.set_pc(address);
+    }
+    Ok(())
+}
+
+// Symbol map given with --symbols, or the one written next to the ROM by `compile`
+fn load_symbols(rom_path: &str, path: Option<&str>) -> SymbolMap {
+    let beside = Path::new(rom_path).with_extension("sym");
+    let path = match path {
+        Some(path) => path.to_string(),
+        None if beside.exists() => beside.to_string_lossy().to_string(),
+        None => return SymbolMap::default(),
+    };
+    match SymbolMap::load(&path) {
+        Ok(symbols) => symbols,
+        Err(e) => {
+            eprintln!("Could not load symbols {}: {}", path, e);
+            SymbolMap::default()
+        }
+    }
+}
+
+// Memory of a saved state, or of a fresh machine with the ROM loaded
+fn load_memory(path: &str) -> std::io::Result<Vec<u8>> {
+    match Snapshot::load(path) {
+        Ok(state) => Ok(state.memory.to_vec()),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            let mut memory = ram::RAM::new(ram::ADDRESS_SPACE, 0x200);
+            memory.load(path)?;
+            Ok(memory.cart.to_vec())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs the program on an emulated COSMAC VIP, only the memory size option applies
+fn run_vip(rom_path: &str, monitor: &str, interpreter: &str, memory_size: Option<usize>) {
+    let read =
+        |path: &str| std::fs::read(path).map_err(|e| format!("Could not load {}: {}", path, e));
+    let program = if rom_path.to_ascii_lowercase().ends_with(".8o") {
+        compile_file(rom_path).map(|compiled| compiled.rom)
+    } else {
+        read(rom_path)
+    };
+    let vip = program.and_then(|program| {
+        Vip::new(
+            &read(monitor)?,
+            &read(interpreter)?,
+            &program,
+            memory_size.unwrap_or(0x1000),
+        )
+    });
+    let vip = match vip {
+        Ok(vip) => Rc::new(RefCell::new(vip)),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let my_app = app::App::default().with_scheme(app::Scheme::Gleam);
+    let mut wind = window::Window::new(100, 100, 640, 320, "Chip-8 Emu (COSMAC VIP)");
+    let display = EmuDisplay::new("Display");
+    wind.end();
+    wind.show();
+    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+    let run_frame_callback = move |handle| {
+        {
+            let mut vip = vip.borrow_mut();
+            vip.set_keys(display.input_state().pressed);
+            vip.run_frame();
+            *display.pixel_mat.borrow_mut() = vip.pixels().iter().map(|row| row.to_vec()).collect();
+            if vip.beeping() {
+                let source =
+                    SineWave::new(1400.0).take_duration(Duration::from_secs_f32(1.0 / 60.0));
+                stream_handle.play_raw(source.convert_samples()).unwrap();
+            }
+        }
+        wind.redraw();
+        app::repeat_timeout3(1.0 / 60.0, handle);
+    };
+    app::add_timeout3(1.0 / 60.0, run_frame_callback);
+    my_app.run().unwrap();
+}
+
 fn main() {
     let args = Args::parse();
+    match &args.command {
+        Some(Command::Compile { source, output }) => {
+            compile(source, output.as_deref());
+            return;
+        }
+        Some(Command::Dump { state, start, end }) => {
+            dump_state(state, *start, *end);
+            return;
+        }
+        Some(Command::Info {
+            rom,
+            rom_db,
+            load_address,
+        }) => {
+            print_info(rom, *load_address, rom_db);
+            return;
+        }
+        Some(Command::Disasm {
+            rom,
+            coverage,
+            load_address,
+            output,
+        }) => {
+            disassemble(rom, *load_address, coverage, output.as_deref());
+            return;
+        }
+        Some(Command::Decompile {
+            rom,
+            load_address,
+            output,
+        }) => {
+            decompile(rom, *load_address, output.as_deref());
+            return;
+        }
+        Some(Command::Cfg {
+            rom,
+            format,
+            load_address,
+            output,
+        }) => {
+            export_cfg(rom, *load_address, format, output.as_deref());
+            return;
+        }
+        Some(Command::Sprites {
+            file,
+            start,
+            count,
+            height,
+            schip,
+            columns,
+            output,
+            scale,
+        }) => {
+            let memory = match load_memory(file) {
+                Ok(memory) => memory,
+                Err(e) => {
+                    eprintln!("Could not load {}: {}", file, e);
+                    return;
+                }
+            };
+            let size = if *schip {
+                SpriteSize::schip()
+            } else {
+                SpriteSize::chip8(*height as usize)
+            };
+            let sheet = SpriteSheet::new(&memory, *start as usize, *count, size, *columns);
+            match output {
+                Some(path) => {
+                    if let Err(e) = sheet.save_png(path, *scale) {
+                        eprintln!("Could not write {}: {}", path, e);
+                    }
+                }
+                None => print!("{}", sheet.to_text()),
+            }
+            return;
+        }
+        Some(Command::Run { .. }) | None => {}
+    }
+    let (rom_path, args) = match args.command {
+        Some(Command::Run { file, options }) => (file, *options),
+        _ => (args.rom.unwrap(), args.options),
+    };
+    if let (Some(interpreter), Some(monitor)) = (&args.vip_interpreter, &args.vip_monitor) {
+        run_vip(&rom_path, monitor, interpreter, args.memory_size);
+        return;
+    }
     let my_app = app::App::default().with_scheme(app::Scheme::Gleam);
     let mut wind = window::Window::new(100, 100, 640, 320, "Chip-8 Emu");
     let display = EmuDisplay::new("Display");
     wind.end();
     wind.show();
 
+    let mut memory_viewer = if args.memory_viewer {
+        Some(MemoryViewer::new())
+    } else {
+        None
+    };
+
+    let mut sprite_viewer = if args.sprite_viewer {
+        Some(SpriteViewer::new())
+    } else {
+        None
+    };
+
+    let mut heatmap_viewer = if args.heatmap {
+        Some(HeatmapViewer::new())
+    } else {
+        None
+    };
+
     let cpu = Rc::new(RefCell::new(CPU::new(display)));
-    cpu.borrow_mut().load_rom(&args.rom);
+    if args.heatmap {
+        cpu.borrow_mut().heatmap = Some(Heatmap::default());
+    }
+    let rom_path = rom_path.as_str();
+    // Octo cartridges carry their own settings, plain ROMs are looked up or guessed. Either is
+    // first loaded into memory big enough for any platform, the real one is set up below
+    cpu.borrow_mut()
+        .set_memory_layout(ram::MAX_MEMORY_SIZE, 0x200);
+    let symbols;
+    let (mut rom_info, guess) = if rom_path.to_ascii_lowercase().ends_with(".8o") {
+        let compiled = match compile_file(rom_path) {
+            Ok(compiled) => compiled,
+            Err(e) => {
+                eprintln!("{}", e);
+                return;
+            }
+        };
+        symbols = compiled.symbols;
+        if let Err(e) = cpu.borrow_mut().load_program(&compiled.rom) {
+            eprintln!("Could not load {}: {}", rom_path, e);
+            return;
+        }
+        identify_rom(cpu.borrow().memory(), &load_rom_db(&args.rom_db))
+    } else {
+        let loaded = cpu.borrow_mut().load_rom(rom_path);
+        match loaded {
+            Ok(Some((info, cartridge_symbols))) => {
+                symbols = cartridge_symbols;
+                (info, None)
+            }
+            Ok(None) => {
+                symbols = load_symbols(rom_path, args.symbols.as_deref());
+                identify_rom(cpu.borrow().memory(), &load_rom_db(&args.rom_db))
+            }
+            Err(e) => {
+                eprintln!("Could not load {}: {}", rom_path, e);
+                return;
+            }
+        }
+    };
+    match guess {
+        None => {
+            let author = match &rom_info.author {
+                Some(author) => format!(" by {}", author),
+                None => String::new(),
+            };
+            println!(
+                "Identified {}{} ({})",
+                rom_info.title,
+                author,
+                rom_info.platform.name()
+            );
+        }
+        Some(guess) => println!(
+            "Unknown ROM, guessing {} ({:.0}% confidence)",
+            guess.platform.name(),
+            guess.confidence * 100.0
+        ),
+    }
+    if let Some(platform) = args.platform {
+        rom_info.platform = platform;
+        rom_info.quirks = platform.quirks();
+        rom_info.font = platform.font();
+    }
+    if let Some(font) = args.font {
+        rom_info.font = font;
+    }
+    if let Some(display_wait) = args.display_wait {
+        rom_info.quirks.display_wait = display_wait;
+    }
+    let ipf = args.ipf.unwrap_or(rom_info.ipf);
+    let program = cpu.borrow().memory().program().to_vec();
+    let memory_size = args
+        .memory_size
+        .unwrap_or(rom_info.platform.memory_size(program.len()));
+    let load_address = args
+        .load_address
+        .unwrap_or(rom_info.platform.load_address());
+    cpu.borrow_mut()
+        .set_memory_layout(memory_size, load_address);
+    if let Err(e) = cpu.borrow_mut().load_program(&program) {
+        eprintln!("Could not load {}: {}", rom_path, e);
+        return;
+    }
+    cpu.borrow_mut().apply_rom_info(&rom_info);
+    if let Some(address) = args.font_address {
+        cpu.borrow_mut().set_font(rom_info.font, address);
+    }
+    if let Err(e) = prepare_memory(&mut cpu.borrow_mut(), &args, &symbols) {
+        eprintln!("{}", e);
+        return;
+    }
+    cpu.borrow_mut().machine_code.mode = args.machine_code;
+    for (address, routine) in &args.routines {
+        cpu.borrow_mut().machine_code.register(*address, *routine);
+    }
+    let flags = RplFlags::for_rom(&cpu.borrow().memory().rom_sha1);
+    cpu.borrow_mut().flags = flags;
+    if args.profile || args.profile_json.is_some() {
+        cpu.borrow_mut().profiler = Some(Profiler::default());
+    }
+    if args.coverage || args.coverage_lcov.is_some() || args.coverage_log.is_some() {
+        cpu.borrow_mut().coverage = Some(Coverage::default());
+    }
+    let symbols = Rc::new(symbols);
+    let report_symbols = symbols.clone();
+    if let Some(path) = &args.trace {
+        match trace::Trace::create(path, symbols.clone()) {
+            Ok(trace) => cpu.borrow_mut().trace = Some(trace),
+            Err(e) => eprintln!("Could not create trace {}: {}", path, e),
+        }
+    }
+    let console = if args.debug || !args.breakpoints.is_empty() {
+        let mut debugger = Debugger::default();
+        debugger.symbols = symbols.clone();
+        for address in &args.breakpoints {
+            match symbols.resolve(address) {
+                Ok(address) => {
+                    debugger.add_breakpoint(BreakKind::Pc(address), None);
+                }
+                Err(e) => {
+                    eprintln!("Invalid breakpoint {}: {}", address, e);
+                    return;
+                }
+            }
+        }
+        debugger.on_stop(move |reason, reg| {
+            println!("Stopped: {}", reason);
+            if let Some(location) = symbols.location(reg.pc) {
+                println!("at {}", location);
+            }
+            println!("{}", console::format_registers(reg));
+        });
+        cpu.borrow_mut().debugger = Some(debugger);
+        cpu.borrow_mut().history = Some(History::default());
+        Some(Console::spawn())
+    } else {
+        None
+    };
     let cpu_clone = cpu.clone();
-    // run approximately 700 cycle per second
+    let cpu_report = cpu.clone();
+    // run `ipf` instructions per 60Hz frame, the default being ~720 per second
 
     let (_stream, stream_handle) = OutputStream::try_default().unwrap();
+    // MegaChip's digitised sound, which stops when the sink is dropped
+    let mut _sample_sink: Option<Sink> = None;
 
     let screen_update_callback = move |handle| {
         wind.redraw();
-        cpu_clone.borrow_mut().update_timers();
+        if let Some(console) = &console {
+            console.poll(&mut cpu_clone.borrow_mut());
+        }
+        if let Some(viewer) = &mut memory_viewer {
+            let mut cpu = cpu_clone.borrow_mut();
+            if viewer.take_pause_toggle() {
+                let paused = cpu.is_paused();
+                cpu.set_paused(!paused);
+            }
+            for (address, value) in viewer.take_edits() {
+                if let Err(e) = cpu.poke(address, value) {
+                    eprintln!("Could not edit memory: {}", e);
+                }
+            }
+            let memory = cpu.memory();
+            viewer.update(
+                &memory.cart,
+                cpu.registers(),
+                memory.cart_size,
+                cpu.is_paused(),
+            );
+        }
+        if let Some(viewer) = &mut sprite_viewer {
+            let cpu = cpu_clone.borrow();
+            viewer.update(&cpu.memory().cart, cpu.registers());
+        }
+        if let Some(viewer) = &mut heatmap_viewer {
+            if let Some(heatmap) = &mut cpu_clone.borrow_mut().heatmap {
+                viewer.update(heatmap);
+            }
+        }
         if cpu_clone.borrow().should_beep() {
-            let source = SineWave::new(440.0).take_duration(Duration::from_secs_f32(5.0 / 60.0));
+            let source = SineWave::new(cpu_clone.borrow().tone_frequency())
+                .take_duration(Duration::from_secs_f32(5.0 / 60.0));
             stream_handle.play_raw(source.convert_samples()).unwrap();
         }
+        let command = cpu_clone.borrow_mut().take_sound_command();
+        if let Some(command) = command {
+            _sample_sink = None;
+            if let SoundCommand::Play(sound) = command {
+                let source = SamplesBuffer::new(1, sound.rate, sound.to_f32());
+                match Sink::try_new(&stream_handle) {
+                    Ok(sink) => {
+                        if sound.looping {
+                            sink.append(source.repeat_infinite());
+                        } else {
+                            sink.append(source);
+                        }
+                        _sample_sink = Some(sink);
+                    }
+                    Err(e) => eprintln!("Could not play sound: {}", e),
+                }
+            }
+        }
         app::repeat_timeout3(1.0 / 30.0, handle);
     };
     let run_cpu_callback = move |handle| {
-        cpu.borrow_mut().run();
-        app::repeat_timeout3(1.0 / 720.0, handle);
+        {
+            let mut cpu = cpu.borrow_mut();
+            for _ in 0..ipf {
+                cpu.run();
+                if cpu.waiting_for_frame() {
+                    break;
+                }
+            }
+            if !cpu.is_paused() {
+                cpu.update_timers();
+            }
+        }
+        app::repeat_timeout3(1.0 / 60.0, handle);
     };
     app::add_timeout3(1.0 / 30.0, screen_update_callback);
-    app::add_timeout3(1.0 / 720.0, run_cpu_callback);
+    app::add_timeout3(1.0 / 60.0, run_cpu_callback);
     my_app.run().unwrap();
+
+    let mut cpu = cpu_report.borrow_mut();
+    if let Some(trace) = &mut cpu.trace {
+        if let Err(e) = trace.flush() {
+            eprintln!("Could not write trace: {}", e);
+        }
+    }
+    if let Some(profiler) = &cpu.profiler {
+        if args.profile {
+            print!("{}", profiler.text_report());
+        }
+        if let Some(path) = &args.profile_json {
+            let json = serde_json::to_string_pretty(&profiler.json_report()).unwrap();
+            if let Err(e) = std::fs::write(path, json) {
+                eprintln!("Could not write profile to {}: {}", path, e);
+            }
+        }
+    }
+    if let Some(coverage) = &cpu.coverage {
+        let memory = cpu.memory();
+        let start = memory.load_address as u16;
+        if args.coverage {
+            print!(
+                "{}",
+                coverage.text_report(&memory.cart, start, memory.cart_size, &report_symbols)
+            );
+        }
+        if let Some(path) = &args.coverage_lcov {
+            let lcov = coverage.lcov(
+                &memory.cart,
+                start,
+                memory.cart_size,
+                &report_symbols,
+                rom_path,
+            );
+            if let Err(e) = std::fs::write(path, lcov) {
+                eprintln!("Could not write coverage to {}: {}", path, e);
+            }
+        }
+        if let Some(path) = &args.coverage_log {
+            if let Err(e) = std::fs::write(path, coverage.address_log()) {
+                eprintln!("Could not write coverage to {}: {}", path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prepare(arguments: &[&str], symbols: &SymbolMap) -> Result<CPU, String> {
+        let args = Args::parse_from([&["chip8", "--rom", "game.ch8"], arguments].concat());
+        let mut cpu = CPU::default();
+        prepare_memory(&mut cpu, &args.options, symbols)?;
+        Ok(cpu)
+    }
+
+    #[test]
+    fn test_prepare_memory() {
+        let path = std::env::temp_dir().join("chip8_prepare_memory_test.bin");
+        std::fs::write(&path, [0xAB, 0xCD, 0xEF]).unwrap();
+        let blob = format!("{}@0x300", path.to_str().unwrap());
+        let cpu = prepare(
+            &["--load", &blob, "--poke", "0x301=0x12"],
+            &SymbolMap::default(),
+        );
+        let cpu = cpu.unwrap();
+        // Pokes are applied after the blobs
+        assert_eq!(
+            cpu.memory().cart[0x2FF..0x304],
+            [0x00, 0xAB, 0x12, 0xEF, 0x00]
+        );
+
+        // Running past the end of the 4K of memory
+        let blob = format!("{}@0xFFE", path.to_str().unwrap());
+        assert!(prepare(&["--load", &blob], &SymbolMap::default()).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!(prepare(&["--poke", "0x1000=0x12"], &SymbolMap::default()).is_err());
+        assert!(prepare(&["--poke", "0xFFF=0x12 0x34"], &SymbolMap::default()).is_err());
+
+        let mut symbols = SymbolMap::default();
+        symbols.add_label("main_loop", 0x246);
+        let cpu = prepare(&["--pc", "main_loop+2"], &symbols).unwrap();
+        assert_eq!(cpu.registers().pc, 0x248);
+        assert!(prepare(&["--pc", "nowhere"], &symbols).is_err());
+    }
 }
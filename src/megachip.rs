@@ -0,0 +1,308 @@
+use crate::history::{invalid, StateReader};
+use std::io;
+
+// MegaChip8 draws on a 256x192 screen with up to 255 colours
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 192;
+// Digitised sounds start with the sample rate (2 bytes), length (3 bytes) and a reserved byte
+const SOUND_HEADER: usize = 6;
+
+/// How sprite pixels are combined with the screen, set by 080n
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    Normal,
+    Alpha25,
+    Alpha50,
+    Alpha75,
+    Add,
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(BlendMode::Normal),
+            1 => Some(BlendMode::Alpha25),
+            2 => Some(BlendMode::Alpha50),
+            3 => Some(BlendMode::Alpha75),
+            4 => Some(BlendMode::Add),
+            5 => Some(BlendMode::Multiply),
+            _ => None,
+        }
+    }
+
+    fn mix(&self, screen: [u8; 3], colour: [u8; 4]) -> [u8; 3] {
+        // Colours are ARGB, the palette alpha scales the blend mode's own opacity
+        let alpha = colour[0] as u32
+            * match self {
+                BlendMode::Alpha25 => 64,
+                BlendMode::Alpha50 => 128,
+                BlendMode::Alpha75 => 192,
+                _ => 255,
+            }
+            / 255;
+        let mut out = [0; 3];
+        for (c, out) in out.iter_mut().enumerate() {
+            let (s, p) = (screen[c] as u32, colour[c + 1] as u32);
+            *out = match self {
+                BlendMode::Add => (s + p).min(255),
+                BlendMode::Multiply => s * p / 255,
+                _ => (p * alpha + s * (255 - alpha)) / 255,
+            } as u8;
+        }
+        out
+    }
+}
+
+/// A digitised sound played by 060n: unsigned 8-bit mono samples
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sound {
+    pub rate: u32,
+    pub samples: Vec<u8>,
+    pub looping: bool,
+}
+
+impl Sound {
+    /// Reads the sound at `address`, cut short if it runs past the end of memory. A sample
+    /// rate of 0 is not a sound.
+    pub fn parse(memory: &[u8], address: usize, looping: bool) -> Option<Sound> {
+        let header = memory.get(address..address + SOUND_HEADER)?;
+        let rate = u16::from_be_bytes([header[0], header[1]]) as u32;
+        if rate == 0 {
+            return None;
+        }
+        let length = u32::from_be_bytes([0, header[2], header[3], header[4]]) as usize;
+        let start = address + SOUND_HEADER;
+        let end = (start + length).min(memory.len());
+        Some(Sound {
+            rate,
+            samples: memory[start..end].to_vec(),
+            looping,
+        })
+    }
+
+    /// Samples between -1 and 1, for playback
+    pub fn to_f32(&self) -> Vec<f32> {
+        self.samples
+            .iter()
+            .map(|s| (*s as f32 - 128.0) / 128.0)
+            .collect()
+    }
+}
+
+/// Sound changes for the audio output to pick up
+#[derive(Debug, Clone, PartialEq)]
+pub enum SoundCommand {
+    Play(Sound),
+    Stop,
+}
+
+/// State of MegaChip mode: palette, sprite settings and a double-buffered screen. Drawing goes
+/// to the back buffer, which 00E0 shows and then clears.
+#[derive(Clone)]
+pub struct MegaChip {
+    // ARGB, colour 0 is transparent
+    palette: [[u8; 4]; 256],
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: BlendMode,
+    pub collision_colour: u8,
+    screen_alpha: u8,
+    // Palette index of every pixel, for collisions, and the blended colour it shows
+    indices: Vec<u8>,
+    pixels: Vec<[u8; 3]>,
+    // RGB of the frame shown by the last 00E0
+    front: Vec<u8>,
+}
+
+impl MegaChip {
+    pub fn default() -> Self {
+        MegaChip {
+            palette: [[0; 4]; 256],
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: BlendMode::Normal,
+            collision_colour: 0,
+            screen_alpha: 0xFF,
+            indices: vec![0; WIDTH * HEIGHT],
+            pixels: vec![[0; 3]; WIDTH * HEIGHT],
+            front: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    /// 02nn: `count` ARGB colours from `address` become colours 1 to `count`
+    pub fn load_palette(&mut self, memory: &[u8], address: usize, count: u8) {
+        for (index, colour) in self
+            .palette
+            .iter_mut()
+            .skip(1)
+            .take(count as usize)
+            .enumerate()
+        {
+            let start = address + index * 4;
+            if let Some(bytes) = memory.get(start..start + 4) {
+                colour.copy_from_slice(bytes);
+            }
+        }
+    }
+
+    /// 05nn: fades the whole screen, 0 is invisible
+    pub fn set_screen_alpha(&mut self, alpha: u8) {
+        self.screen_alpha = alpha;
+    }
+
+    /// DXYN in MegaChip mode: a sprite of one palette index per byte, 0 being transparent and
+    /// clipped at the edges. Returns whether a pixel of the collision colour was drawn over.
+    pub fn draw_sprite(&mut self, memory: &[u8], address: usize, x: usize, y: usize) -> bool {
+        let mut collision = false;
+        for row in 0..self.sprite_height {
+            for col in 0..self.sprite_width {
+                let index = memory
+                    .get(address + row * self.sprite_width + col)
+                    .copied()
+                    .unwrap_or(0);
+                let (px, py) = (x + col, y + row);
+                if index == 0 || px >= WIDTH || py >= HEIGHT {
+                    continue;
+                }
+                let pixel = py * WIDTH + px;
+                if self.indices[pixel] == self.collision_colour {
+                    collision = true;
+                }
+                self.indices[pixel] = index;
+                self.pixels[pixel] = self
+                    .blend
+                    .mix(self.pixels[pixel], self.palette[index as usize]);
+            }
+        }
+        collision
+    }
+
+    /// 00E0: shows the back buffer and clears it for the next frame
+    pub fn present(&mut self) {
+        let alpha = self.screen_alpha as u32;
+        for (out, pixel) in self.front.chunks_mut(3).zip(&self.pixels) {
+            for (c, channel) in out.iter_mut().enumerate() {
+                *channel = (pixel[c] as u32 * alpha / 255) as u8;
+            }
+        }
+        self.indices.fill(0);
+        self.pixels.fill([0; 3]);
+    }
+
+    /// Moves the back buffer by `dx`, `dy` pixels, uncovering blank pixels
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let indices = self.indices.clone();
+        let pixels = self.pixels.clone();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let (sx, sy) = (x as isize - dx, y as isize - dy);
+                let inside =
+                    (0..WIDTH as isize).contains(&sx) && (0..HEIGHT as isize).contains(&sy);
+                let pixel = y * WIDTH + x;
+                if inside {
+                    let source = sy as usize * WIDTH + sx as usize;
+                    self.indices[pixel] = indices[source];
+                    self.pixels[pixel] = pixels[source];
+                } else {
+                    self.indices[pixel] = 0;
+                    self.pixels[pixel] = [0; 3];
+                }
+            }
+        }
+    }
+
+    /// The frame shown, as RGB rows
+    pub fn frame(&self) -> &[u8] {
+        &self.front
+    }
+
+    /// Appends the palette, sprite settings and both buffers to a saved state
+    pub fn save_state(&self, out: &mut Vec<u8>) {
+        for colour in self.palette.iter() {
+            out.extend_from_slice(colour);
+        }
+        out.extend_from_slice(&(self.sprite_width as u16).to_le_bytes());
+        out.extend_from_slice(&(self.sprite_height as u16).to_le_bytes());
+        out.push(self.blend as u8);
+        out.push(self.collision_colour);
+        out.push(self.screen_alpha);
+        out.extend_from_slice(&self.indices);
+        for pixel in self.pixels.iter() {
+            out.extend_from_slice(pixel);
+        }
+        out.extend_from_slice(&self.front);
+    }
+
+    pub fn load_state(reader: &mut StateReader) -> io::Result<Self> {
+        let mut mega = MegaChip::default();
+        for colour in mega.palette.iter_mut() {
+            colour.copy_from_slice(reader.bytes(4)?);
+        }
+        mega.sprite_width = reader.u16()? as usize;
+        mega.sprite_height = reader.u16()? as usize;
+        mega.blend = BlendMode::from_code(reader.u8()?)
+            .ok_or_else(|| invalid("Saved state has an unknown blend mode"))?;
+        mega.collision_colour = reader.u8()?;
+        mega.screen_alpha = reader.u8()?;
+        mega.indices.copy_from_slice(reader.bytes(WIDTH * HEIGHT)?);
+        for pixel in mega.pixels.iter_mut() {
+            pixel.copy_from_slice(reader.bytes(3)?);
+        }
+        mega.front
+            .copy_from_slice(reader.bytes(WIDTH * HEIGHT * 3)?);
+        Ok(mega)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sprites() {
+        let mut mega = MegaChip::default();
+        // Colour 1 opaque red, colour 2 half transparent blue
+        let mut memory = vec![0xFF, 0xFF, 0x00, 0x00, 0x80, 0x00, 0x00, 0xFF];
+        // A 2x2 sprite with a transparent corner
+        memory.extend([1, 2, 0, 1]);
+        mega.load_palette(&memory, 0, 2);
+        mega.sprite_width = 2;
+        mega.sprite_height = 2;
+        mega.collision_colour = 1;
+
+        assert!(!mega.draw_sprite(&memory, 8, 10, 10));
+        assert!(mega.draw_sprite(&memory, 8, 9, 10));
+        // Clipped at the right edge
+        assert!(!mega.draw_sprite(&memory, 8, WIDTH - 1, 0));
+        mega.scroll(0, 1);
+        mega.present();
+        let rgb = |x: usize, y: usize| &mega.frame()[(y * WIDTH + x) * 3..(y * WIDTH + x) * 3 + 3];
+        assert_eq!(rgb(9, 11), [0xFF, 0, 0]);
+        // Half blue over red, and over black
+        assert_eq!(rgb(10, 11), [0x7F, 0, 0x80]);
+        assert_eq!(rgb(11, 11), [0, 0, 0x80]);
+        assert_eq!(rgb(10, 13), [0, 0, 0]);
+        assert_eq!(rgb(WIDTH - 1, 1), [0xFF, 0, 0]);
+
+        // The back buffer starts empty again
+        mega.set_screen_alpha(0x80);
+        mega.blend = BlendMode::from_code(4).unwrap();
+        mega.draw_sprite(&memory, 8, 0, 0);
+        mega.draw_sprite(&memory, 8, 0, 0);
+        mega.present();
+        assert_eq!(mega.frame()[..3], [0x80, 0, 0]);
+    }
+
+    #[test]
+    fn test_sound() {
+        let memory = [0x1F, 0x40, 0x00, 0x00, 0x03, 0x00, 0x80, 0xFF, 0x00, 0x42];
+        let sound = Sound::parse(&memory, 0, true).unwrap();
+        assert_eq!(sound.rate, 8000);
+        assert_eq!(sound.samples, [0x80, 0xFF, 0x00]);
+        assert_eq!(sound.to_f32(), [0.0, 127.0 / 128.0, -1.0]);
+        let truncated = [0x00, 0x01, 0x00, 0x00, 0x09, 0x00, 0x05];
+        assert_eq!(Sound::parse(&truncated, 0, false).unwrap().samples, [0x05]);
+        assert_eq!(Sound::parse(&memory, 8, false), None);
+    }
+}
@@ -0,0 +1,423 @@
+use crate::expr::parse_number;
+use crate::fonts::{DEFAULT_FONT_ADDRESS, MAX_FONT_SIZE};
+use crate::register::Reg;
+use fltk::{prelude::*, *};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+const MEMORY_SIZE: usize = 4096;
+const BYTES_PER_ROW: usize = 8;
+// Small and big fonts at their default address
+const FONT_START: usize = DEFAULT_FONT_ADDRESS as usize;
+const FONT_END: usize = FONT_START + MAX_FONT_SIZE;
+const PROGRAM_START: usize = 0x200;
+
+// Layout of the viewer rows, in pixels
+const ROW_HEIGHT: i32 = 18;
+const ADDRESS_WIDTH: i32 = 48;
+const CELL_WIDTH: i32 = 24;
+const CHAR_WIDTH: i32 = 9;
+const SPRITE_PIXEL: i32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Font,
+    Program,
+    Free,
+}
+
+impl Region {
+    pub fn of(address: usize, program_end: usize) -> Self {
+        if (FONT_START..FONT_END).contains(&address) {
+            Region::Font
+        } else if (PROGRAM_START..program_end).contains(&address) {
+            Region::Program
+        } else {
+            Region::Free
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Region::Font => "font",
+            Region::Program => "program",
+            Region::Free => "free",
+        }
+    }
+
+    fn color(&self) -> enums::Color {
+        match self {
+            Region::Font => enums::Color::from_rgb(0xE8, 0xF0, 0xFF),
+            Region::Program => enums::Color::from_rgb(0xEC, 0xFF, 0xE8),
+            Region::Free => enums::Color::White,
+        }
+    }
+}
+
+fn printable(byte: u8) -> char {
+    if byte.is_ascii_graphic() || byte == b' ' {
+        byte as char
+    } else {
+        '.'
+    }
+}
+
+// One byte as 4 characters, each showing two pixels with half blocks
+fn sprite_chars(byte: u8) -> String {
+    (0..4)
+        .map(|pair| match (byte >> (6 - pair * 2)) & 0b11 {
+            0b00 => ' ',
+            0b01 => '▐',
+            0b10 => '▌',
+            _ => '█',
+        })
+        .collect()
+}
+
+/// Hex dump of `memory[start..end]` with ASCII and sprite columns. A comment line marks the
+/// start of each region and rows holding PC or I are tagged at the end.
+pub fn hex_dump(memory: &[u8], start: usize, end: usize, reg: &Reg, program_end: usize) -> String {
+    let end = end.min(memory.len());
+    let start = start - start % BYTES_PER_ROW;
+    let mut out = String::new();
+    let mut last_region = None;
+    for row in (start..end).step_by(BYTES_PER_ROW) {
+        let bytes = &memory[row..(row + BYTES_PER_ROW).min(end)];
+        let region = Region::of(row, program_end);
+        if last_region != Some(region) {
+            let region_end = (row..MEMORY_SIZE)
+                .find(|a| Region::of(*a, program_end) != region)
+                .unwrap_or(MEMORY_SIZE);
+            out += &format!("; {} {:#05x}-{:#05x}\n", region.name(), row, region_end - 1);
+            last_region = Some(region);
+        }
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes.iter().map(|b| printable(*b)).collect();
+        let sprite: Vec<String> = bytes.iter().map(|b| sprite_chars(*b)).collect();
+        out += &format!(
+            "{:03x}  {:<23}  {:<8}  |{}|",
+            row,
+            hex.join(" "),
+            ascii,
+            sprite.join("|")
+        );
+        let rows = row..row + bytes.len();
+        if rows.contains(&(reg.pc as usize)) {
+            out += &format!(" <pc {:03x}", reg.pc);
+        }
+        if rows.contains(&(reg.i as usize)) {
+            out += &format!(" <i {:03x}", reg.i);
+        }
+        out += "\n";
+    }
+    out
+}
+
+/// Parses `addr=value [value...]`, writing consecutive bytes starting at `addr`
+pub fn parse_edit(text: &str) -> Result<Vec<(usize, u8)>, String> {
+    let (address, values) = text.split_once('=').ok_or("expected addr=value")?;
+    let address = match parse_number(address.trim()) {
+        Some(address) if (0..MEMORY_SIZE as i64).contains(&address) => address as usize,
+        _ => return Err(format!("invalid address '{}'", address.trim())),
+    };
+    let mut edits = Vec::new();
+    for (offset, value) in values.split_whitespace().enumerate() {
+        let byte = match parse_number(value) {
+            Some(byte) if (0..=0xFF).contains(&byte) => byte as u8,
+            _ => return Err(format!("invalid byte '{}'", value)),
+        };
+        if address + offset >= MEMORY_SIZE {
+            return Err("edit runs past the end of memory".to_string());
+        }
+        edits.push((address + offset, byte));
+    }
+    if edits.is_empty() {
+        return Err("missing value".to_string());
+    }
+    Ok(edits)
+}
+
+struct ViewState {
+    memory: [u8; MEMORY_SIZE],
+    changed: [bool; MEMORY_SIZE],
+    pc: usize,
+    i: usize,
+    program_end: usize,
+    paused: bool,
+    top_row: usize,
+    selected: Option<usize>,
+}
+
+/// Window showing the whole memory, live while running and editable while paused
+pub struct MemoryViewer {
+    window: window::Window,
+    view: widget::Widget,
+    status: frame::Frame,
+    pause_button: button::Button,
+    state: Rc<RefCell<ViewState>>,
+    edits: Rc<RefCell<Vec<(usize, u8)>>>,
+    pause_toggled: Rc<RefCell<bool>>,
+}
+
+impl MemoryViewer {
+    pub fn new() -> Self {
+        let mut window = window::Window::new(760, 100, 420, 520, "Memory");
+        let mut view = widget::Widget::new(5, 5, 410, 450, None);
+        let mut input = input::Input::new(60, 460, 355, 25, "Edit:");
+        input.set_tooltip("addr=value [value...] to edit while paused, or an address to go to");
+        let mut pause_button = button::Button::new(5, 490, 80, 25, "Pause");
+        let status = frame::Frame::new(90, 490, 325, 25, None)
+            .with_align(enums::Align::Left | enums::Align::Inside);
+        window.end();
+        window.show();
+
+        let state = Rc::new(RefCell::new(ViewState {
+            memory: [0; MEMORY_SIZE],
+            changed: [false; MEMORY_SIZE],
+            pc: 0,
+            i: 0,
+            program_end: PROGRAM_START,
+            paused: false,
+            top_row: PROGRAM_START / BYTES_PER_ROW,
+            selected: None,
+        }));
+        let edits = Rc::new(RefCell::new(Vec::new()));
+        let pause_toggled = Rc::new(RefCell::new(false));
+
+        let draw_state = state.clone();
+        view.draw(move |w| {
+            let state = draw_state.borrow();
+            draw::draw_rect_fill(w.x(), w.y(), w.w(), w.h(), enums::Color::White);
+            draw::push_clip(w.x(), w.y(), w.w(), w.h());
+            draw::set_font(enums::Font::Courier, 12);
+            let ascii_x = ADDRESS_WIDTH + CELL_WIDTH * BYTES_PER_ROW as i32 + 4;
+            let sprite_x = ascii_x + CHAR_WIDTH * BYTES_PER_ROW as i32 + 8;
+            for r in 0..w.h() / ROW_HEIGHT {
+                let row = (state.top_row + r as usize) * BYTES_PER_ROW;
+                if row >= MEMORY_SIZE {
+                    break;
+                }
+                let y = w.y() + r * ROW_HEIGHT;
+                let baseline = y + ROW_HEIGHT - 5;
+                let region = Region::of(row, state.program_end);
+                draw::draw_rect_fill(w.x(), y, w.w(), ROW_HEIGHT, region.color());
+                draw::set_draw_color(enums::Color::Dark3);
+                draw::draw_text(&format!("{:03x}", row), w.x() + 4, baseline);
+                for col in 0..BYTES_PER_ROW {
+                    let address = row + col;
+                    let byte = state.memory[address];
+                    let x = w.x() + ADDRESS_WIDTH + col as i32 * CELL_WIDTH;
+                    if state.selected == Some(address) {
+                        draw::draw_rect_fill(
+                            x - 3,
+                            y + 1,
+                            CELL_WIDTH - 2,
+                            ROW_HEIGHT - 2,
+                            enums::Color::Cyan,
+                        );
+                    } else if state.changed[address] {
+                        draw::draw_rect_fill(
+                            x - 3,
+                            y + 1,
+                            CELL_WIDTH - 2,
+                            ROW_HEIGHT - 2,
+                            enums::Color::Yellow,
+                        );
+                    }
+                    if address == state.pc || address == state.pc + 1 {
+                        draw::draw_rect_with_color(
+                            x - 3,
+                            y + 1,
+                            CELL_WIDTH - 2,
+                            ROW_HEIGHT - 2,
+                            enums::Color::Red,
+                        );
+                    }
+                    if address == state.i {
+                        draw::draw_rect_with_color(
+                            x - 2,
+                            y + 2,
+                            CELL_WIDTH - 4,
+                            ROW_HEIGHT - 4,
+                            enums::Color::Blue,
+                        );
+                    }
+                    draw::set_draw_color(enums::Color::Black);
+                    draw::draw_text(&format!("{:02x}", byte), x, baseline);
+                    let ascii = printable(byte).to_string();
+                    draw::draw_text(&ascii, w.x() + ascii_x + col as i32 * CHAR_WIDTH, baseline);
+                    // The row's bytes stacked as 8 lines of a sprite
+                    for bit in 0..8 {
+                        if byte & (0x80 >> bit) != 0 {
+                            draw::draw_rect_fill(
+                                w.x() + sprite_x + bit * SPRITE_PIXEL,
+                                y + 1 + col as i32 * SPRITE_PIXEL,
+                                SPRITE_PIXEL,
+                                SPRITE_PIXEL,
+                                enums::Color::Black,
+                            );
+                        }
+                    }
+                }
+            }
+            draw::pop_clip();
+        });
+
+        let handle_state = state.clone();
+        let mut handle_input = input.clone();
+        view.handle(move |w, ev| match ev {
+            enums::Event::MouseWheel => {
+                let mut state = handle_state.borrow_mut();
+                let rows = MEMORY_SIZE / BYTES_PER_ROW;
+                let step = match app::event_dy() {
+                    app::MouseWheel::Up => -3,
+                    app::MouseWheel::Down => 3,
+                    _ => 0,
+                };
+                state.top_row = (state.top_row as i32 + step).clamp(0, rows as i32 - 1) as usize;
+                w.redraw();
+                true
+            }
+            enums::Event::Push => {
+                let mut state = handle_state.borrow_mut();
+                let row = state.top_row + ((app::event_y() - w.y()) / ROW_HEIGHT) as usize;
+                let col = (app::event_x() - w.x() - ADDRESS_WIDTH + 3) / CELL_WIDTH;
+                if (0..BYTES_PER_ROW as i32).contains(&col) && row * BYTES_PER_ROW < MEMORY_SIZE {
+                    let address = row * BYTES_PER_ROW + col as usize;
+                    state.selected = Some(address);
+                    handle_input
+                        .set_value(&format!("{:#05x}={:#04x}", address, state.memory[address]));
+                    w.redraw();
+                }
+                true
+            }
+            _ => false,
+        });
+
+        let input_state = state.clone();
+        let input_edits = edits.clone();
+        let mut input_status = status.clone();
+        let mut input_view = view.clone();
+        input.set_trigger(enums::CallbackTrigger::EnterKey);
+        input.set_callback(move |i| {
+            let text = i.value();
+            let mut state = input_state.borrow_mut();
+            if text.contains('=') {
+                if !state.paused {
+                    input_status.set_label("Pause before editing memory");
+                    return;
+                }
+                match parse_edit(&text) {
+                    Ok(edits) => {
+                        input_status.set_label(&format!("Wrote {} byte(s)", edits.len()));
+                        input_edits.borrow_mut().extend(edits);
+                    }
+                    Err(e) => input_status.set_label(&e),
+                }
+            } else {
+                match parse_number(text.trim()) {
+                    Some(address) if (0..MEMORY_SIZE as i64).contains(&address) => {
+                        state.top_row = address as usize / BYTES_PER_ROW;
+                        state.selected = Some(address as usize);
+                        input_view.redraw();
+                    }
+                    _ => input_status.set_label("Expected an address or addr=value"),
+                }
+            }
+        });
+
+        let button_toggled = pause_toggled.clone();
+        pause_button.set_callback(move |_| {
+            *button_toggled.borrow_mut() = true;
+        });
+
+        MemoryViewer {
+            window,
+            view,
+            status,
+            pause_button,
+            state,
+            edits,
+            pause_toggled,
+        }
+    }
+
+    /// Refreshes the view with the current memory, highlighting the bytes that changed since
+    /// the previous update
+    pub fn update(&mut self, memory: &[u8], reg: &Reg, program_end: usize, paused: bool) {
+        if !self.window.shown() {
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        let mut any_changed = false;
+        for (address, byte) in memory.iter().enumerate().take(MEMORY_SIZE) {
+            state.changed[address] = state.memory[address] != *byte;
+            any_changed |= state.changed[address];
+            state.memory[address] = *byte;
+        }
+        let moved = state.pc != reg.pc as usize || state.i != reg.i as usize;
+        let paused_changed = state.paused != paused;
+        state.pc = reg.pc as usize;
+        state.i = reg.i as usize;
+        state.program_end = program_end;
+        state.paused = paused;
+        if paused_changed {
+            self.pause_button
+                .set_label(if paused { "Resume" } else { "Pause" });
+            self.status.set_label(if paused {
+                "Paused, click a byte or type addr=value to edit"
+            } else {
+                ""
+            });
+        }
+        if any_changed || moved || paused_changed {
+            self.view.redraw();
+        }
+    }
+
+    /// Edits typed since the last call, only collected while paused
+    pub fn take_edits(&self) -> Vec<(usize, u8)> {
+        self.edits.borrow_mut().drain(..).collect()
+    }
+
+    /// Whether the pause button was pressed since the last call
+    pub fn take_pause_toggle(&self) -> bool {
+        self.pause_toggled.replace(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_dump() {
+        let mut memory = [0u8; MEMORY_SIZE];
+        memory[0x200..0x206].copy_from_slice(&[0x41, 0x42, 0xF0, 0x90, 0x0F, 0x00]);
+        let reg = Reg {
+            pc: 0x202,
+            i: 0x20A,
+            ..Reg::default()
+        };
+        let dump = hex_dump(&memory, 0x1F8, 0x210, &reg, 0x206);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines[0], "; free 0x1f8-0x1ff");
+        assert_eq!(lines[2], "; program 0x200-0x205");
+        assert!(lines[3].starts_with(
+            "200  41 42 f0 90 0f 00 00 00  AB......  |▐  ▐|▐  ▌|██  |▌▐  |  ██|    |"
+        ));
+        assert!(lines[3].ends_with(" <pc 202"));
+        assert_eq!(lines[4], "; free 0x208-0xfff");
+        assert!(lines[5].ends_with(" <i 20a"));
+        assert_eq!(lines.len(), 6);
+    }
+
+    #[test]
+    fn test_parse_edit() {
+        assert_eq!(parse_edit("0x300=0xff"), Ok(vec![(0x300, 0xFF)]));
+        assert_eq!(parse_edit("#300 = 1 2"), Ok(vec![(0x300, 1), (0x301, 2)]));
+        assert!(parse_edit("0x300").is_err());
+        assert!(parse_edit("0x300=256").is_err());
+        assert!(parse_edit("0xfff=1 2").is_err());
+    }
+}
@@ -0,0 +1,979 @@
+use crate::expr::parse_number;
+use crate::symbols::SymbolMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+// Octo source compiled to a ROM that is loaded at 0x200, XO-CHIP programs can fill 64K
+const START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x10000;
+// Stops macros that keep expanding into themselves
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+// Words are separated by whitespace and `#` starts a comment. Braces and parentheses are
+// tokens of their own so that `:calc x {1+2}` needs no extra spaces.
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for word in code.split_whitespace() {
+            let mut text = String::new();
+            for c in word.chars() {
+                if "{}()".contains(c) {
+                    if !text.is_empty() {
+                        tokens.push_back(Token {
+                            text: std::mem::take(&mut text),
+                            line: index + 1,
+                        });
+                    }
+                    tokens.push_back(Token {
+                        text: c.to_string(),
+                        line: index + 1,
+                    });
+                } else {
+                    text.push(c);
+                }
+            }
+            if !text.is_empty() {
+                tokens.push_back(Token {
+                    text,
+                    line: index + 1,
+                });
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_integer(text: &str) -> Option<i64> {
+    match text.strip_prefix('-') {
+        Some(positive) => parse_number(positive).map(|n| -n),
+        None => parse_number(text),
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// How an address is written into the instruction that refers to it
+#[derive(Debug, Clone, Copy)]
+enum Ref {
+    // Low 12 bits of an nnn instruction
+    Jump,
+    // The 16 bit word following F000
+    Long,
+    // High half of an `:unpack`, with the nibble to put in front or None for `:unpack long`
+    UnpackHigh(Option<u8>),
+    UnpackLow,
+}
+
+// Reference to a label that was not defined yet when it was used
+struct Fixup {
+    address: usize,
+    name: String,
+    kind: Ref,
+    line: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+enum Block {
+    // Address of the jump taken when the condition is false, or over the else branch
+    If { jump: usize, has_else: bool },
+    Loop { start: usize, breaks: Vec<usize> },
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+/// A compiled program with the labels and source lines of its instructions
+#[derive(Debug)]
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub symbols: SymbolMap,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    // Set when `main` comes first, so that no jump to it is needed at 0x200
+    main_first: bool,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    blocks: Vec<(Block, usize)>,
+    expansions: usize,
+    symbols: SymbolMap,
+}
+
+impl Compiler {
+    fn error<T>(&self, message: String) -> Result<T, CompileError> {
+        Err(CompileError {
+            line: self.line,
+            message,
+        })
+    }
+
+    fn next(&mut self) -> Result<Token, CompileError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token)
+            }
+            None => self.error("unexpected end of program".to_string()),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|t| t.text.as_str())
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), CompileError> {
+        let token = self.next()?;
+        if token.text != text {
+            return self.error(format!("expected '{}', found '{}'", text, token.text));
+        }
+        Ok(())
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), CompileError> {
+        if self.here >= MEMORY_SIZE {
+            return self.error("program does not fit in memory".to_string());
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit(&mut self, opcode: u16) -> Result<(), CompileError> {
+        self.symbols.add_line(self.here as u16, self.line);
+        self.emit_byte((opcode >> 8) as u8)?;
+        self.emit_byte(opcode as u8)
+    }
+
+    fn as_register(&self, text: &str) -> Option<u8> {
+        if let Some(register) = self.aliases.get(text) {
+            return Some(*register);
+        }
+        let digit = text.strip_prefix(['v', 'V'])?;
+        match digit.len() {
+            1 => u8::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+
+    fn register(&mut self) -> Result<u8, CompileError> {
+        let token = self.next()?;
+        match self.as_register(&token.text) {
+            Some(register) => Ok(register),
+            None => self.error(format!("expected a register, found '{}'", token.text)),
+        }
+    }
+
+    fn defined(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.constants.contains_key(name)
+    }
+
+    // Value of a number, constant or label that is already known
+    fn constant(&self, token: &Token) -> Result<f64, CompileError> {
+        if let Some(number) = parse_integer(&token.text) {
+            return Ok(number as f64);
+        }
+        if let Some(value) = self.constants.get(&token.text) {
+            return Ok(*value);
+        }
+        match self.labels.get(&token.text) {
+            Some(address) => Ok(*address as f64),
+            None => self.error(format!("undefined name '{}'", token.text)),
+        }
+    }
+
+    fn integer(&mut self, min: i64, max: i64) -> Result<i64, CompileError> {
+        let token = self.next()?;
+        let value = self.constant(&token)? as i64;
+        if value < min || value > max {
+            return self.error(format!(
+                "'{}' is out of range ({} to {})",
+                token.text, min, max
+            ));
+        }
+        Ok(value)
+    }
+
+    fn byte(&mut self) -> Result<u8, CompileError> {
+        Ok(self.integer(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u16, CompileError> {
+        Ok(self.integer(0, 15)? as u16)
+    }
+
+    fn operand(&mut self) -> Result<Operand, CompileError> {
+        match self.peek().and_then(|text| self.as_register(text)) {
+            Some(register) => {
+                self.next()?;
+                Ok(Operand::Register(register))
+            }
+            None => Ok(Operand::Byte(self.byte()?)),
+        }
+    }
+
+    fn patch(&mut self, address: usize, kind: Ref, value: u16) -> Result<(), CompileError> {
+        match kind {
+            Ref::Jump => {
+                if value > 0xFFF {
+                    return self.error(format!(
+                        "address 0x{:x} is out of reach, use 'i := long'",
+                        value
+                    ));
+                }
+                self.memory[address] = (self.memory[address] & 0xF0) | (value >> 8) as u8;
+                self.memory[address + 1] = value as u8;
+            }
+            Ref::Long => {
+                self.memory[address] = (value >> 8) as u8;
+                self.memory[address + 1] = value as u8;
+            }
+            Ref::UnpackHigh(None) => self.memory[address + 1] = (value >> 8) as u8,
+            Ref::UnpackHigh(Some(nibble)) => {
+                self.memory[address + 1] = (nibble << 4) | ((value >> 8) & 0xF) as u8
+            }
+            Ref::UnpackLow => self.memory[address + 1] = value as u8,
+        }
+        Ok(())
+    }
+
+    // Writes the address named by `token` into the instruction at `address`, later if it is
+    // a label that is not defined yet
+    fn reference(&mut self, token: Token, address: usize, kind: Ref) -> Result<(), CompileError> {
+        if parse_integer(&token.text).is_some() || self.defined(&token.text) {
+            let value = self.constant(&token)? as i64;
+            if !(0..=0xFFFF).contains(&value) {
+                return self.error(format!("'{}' is not an address", token.text));
+            }
+            return self.patch(address, kind, value as u16);
+        }
+        if !is_identifier(&token.text) || self.as_register(&token.text).is_some() {
+            return self.error(format!("expected an address, found '{}'", token.text));
+        }
+        self.fixups.push(Fixup {
+            address,
+            name: token.text,
+            kind,
+            line: token.line,
+        });
+        Ok(())
+    }
+
+    // Emits an instruction with an address operand
+    fn emit_with_address(&mut self, opcode: u16) -> Result<(), CompileError> {
+        let token = self.next()?;
+        let address = self.here;
+        self.emit(opcode)?;
+        self.reference(token, address, Ref::Jump)
+    }
+
+    fn emit_jump_placeholder(&mut self) -> Result<usize, CompileError> {
+        let address = self.here;
+        self.emit(0x1000)?;
+        Ok(address)
+    }
+
+    fn define_name(&mut self) -> Result<String, CompileError> {
+        let token = self.next()?;
+        if !is_identifier(&token.text) || self.as_register(&token.text).is_some() {
+            return self.error(format!("'{}' is not a valid name", token.text));
+        }
+        if self.defined(&token.text) {
+            return self.error(format!("'{}' is already defined", token.text));
+        }
+        Ok(token.text)
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), CompileError> {
+        if name == "main" && self.here == START + 2 && self.end == START + 2 {
+            self.main_first = true;
+            self.here = START;
+            self.end = START;
+            self.symbols.add_label(&name, START as u16);
+            self.labels.insert(name, START as u16);
+            return Ok(());
+        }
+        self.symbols.add_label(&name, address as u16);
+        self.labels.insert(name, address as u16);
+        Ok(())
+    }
+
+    // `:calc` expressions have no precedence and are evaluated right to left, as in Octo
+    fn calc(&mut self) -> Result<f64, CompileError> {
+        self.expect("{")?;
+        let value = self.calc_expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    fn calc_expr(&mut self) -> Result<f64, CompileError> {
+        let left = self.calc_term()?;
+        if matches!(self.peek(), Some("}") | Some(")") | None) {
+            return Ok(left);
+        }
+        let op = self.next()?;
+        let right = self.calc_expr()?;
+        let bool = |b: bool| if b { 1.0 } else { 0.0 };
+        Ok(match op.text.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "&" => ((left as i64) & (right as i64)) as f64,
+            "|" => ((left as i64) | (right as i64)) as f64,
+            "^" => ((left as i64) ^ (right as i64)) as f64,
+            "<<" => ((left as i64) << (right as i64).clamp(0, 63)) as f64,
+            ">>" => ((left as i64) >> (right as i64).clamp(0, 63)) as f64,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "<" => bool(left < right),
+            "<=" => bool(left <= right),
+            ">" => bool(left > right),
+            ">=" => bool(left >= right),
+            "==" => bool(left == right),
+            "!=" => bool(left != right),
+            _ => return self.error(format!("unknown operator '{}'", op.text)),
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, CompileError> {
+        let token = self.next()?;
+        let value = match token.text.as_str() {
+            "(" => {
+                let value = self.calc_expr()?;
+                self.expect(")")?;
+                value
+            }
+            "-" => -self.calc_term()?,
+            "~" => !(self.calc_term()? as i64) as f64,
+            "!" => (self.calc_term()? == 0.0) as i64 as f64,
+            "sin" => self.calc_term()?.sin(),
+            "cos" => self.calc_term()?.cos(),
+            "tan" => self.calc_term()?.tan(),
+            "exp" => self.calc_term()?.exp(),
+            "log" => self.calc_term()?.ln(),
+            "abs" => self.calc_term()?.abs(),
+            "sqrt" => self.calc_term()?.sqrt(),
+            "sign" => self.calc_term()?.signum(),
+            "ceil" => self.calc_term()?.ceil(),
+            "floor" => self.calc_term()?.floor(),
+            "@" => {
+                let address = self.calc_term()? as usize;
+                self.memory.get(address).copied().unwrap_or(0) as f64
+            }
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            _ => match token.text.parse::<f64>() {
+                Ok(value) if parse_integer(&token.text).is_none() => value,
+                _ => self.constant(&token)?,
+            },
+        };
+        Ok(value)
+    }
+
+    // Emits code that skips the next instruction when the condition evaluates to `skip_when`
+    fn condition(&mut self, skip_when: bool) -> Result<(), CompileError> {
+        let x = self.register()? as u16;
+        let op = self.next()?;
+        match op.text.as_str() {
+            "key" | "-key" => {
+                let skip_pressed = (op.text == "key") == skip_when;
+                self.emit(if skip_pressed { 0xE09E } else { 0xE0A1 } | (x << 8))
+            }
+            "==" | "!=" => {
+                let skip_equal = (op.text == "==") == skip_when;
+                match self.operand()? {
+                    Operand::Register(y) => {
+                        let opcode = if skip_equal { 0x5000 } else { 0x9000 };
+                        self.emit(opcode | (x << 8) | ((y as u16) << 4))
+                    }
+                    Operand::Byte(n) => {
+                        let opcode = if skip_equal { 0x3000 } else { 0x4000 };
+                        self.emit(opcode | (x << 8) | n as u16)
+                    }
+                }
+            }
+            "<" | ">=" | ">" | "<=" => {
+                // VF becomes the no-borrow flag of left - right, set when left >= right
+                let other = self.operand()?;
+                let (left, right) = match op.text.as_str() {
+                    "<" | ">=" => (Operand::Register(x as u8), other),
+                    _ => (other, Operand::Register(x as u8)),
+                };
+                match (left, right) {
+                    (left, Operand::Register(right)) => {
+                        match left {
+                            Operand::Register(left) => self.emit(0x8F00 | ((left as u16) << 4))?,
+                            Operand::Byte(n) => self.emit(0x6F00 | n as u16)?,
+                        }
+                        self.emit(0x8F05 | ((right as u16) << 4))?;
+                    }
+                    (Operand::Register(left), Operand::Byte(n)) => {
+                        self.emit(0x6F00 | n as u16)?;
+                        self.emit(0x8F07 | ((left as u16) << 4))?;
+                    }
+                    (Operand::Byte(_), Operand::Byte(_)) => unreachable!(),
+                }
+                let true_flag = matches!(op.text.as_str(), ">=" | "<=") as u16;
+                let skip_flag = if skip_when { true_flag } else { 1 - true_flag };
+                self.emit(0x3F00 | skip_flag)
+            }
+            _ => self.error(format!("unknown comparison '{}'", op.text)),
+        }
+    }
+
+    fn expand_macro(&mut self, name: &str) -> Result<(), CompileError> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return self.error(format!("macro '{}' expands too many times", name));
+        }
+        let params = self.macros[name].params.clone();
+        let mut args = HashMap::new();
+        for param in params {
+            args.insert(param, self.next()?.text);
+        }
+        let line = self.line;
+        let expansion = self.macros.get_mut(name).unwrap();
+        let calls = expansion.calls.to_string();
+        expansion.calls += 1;
+        for token in expansion.body.iter().rev() {
+            let text = match token.text.as_str() {
+                "CALLS" => calls.clone(),
+                text => args.get(text).cloned().unwrap_or(token.text.clone()),
+            };
+            self.tokens.push_front(Token { text, line });
+        }
+        Ok(())
+    }
+
+    fn define_macro(&mut self) -> Result<(), CompileError> {
+        let name = self.define_name()?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.next()?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(
+            name,
+            Macro {
+                params,
+                body,
+                calls: 0,
+            },
+        );
+        Ok(())
+    }
+
+    fn register_statement(&mut self, x: u16) -> Result<(), CompileError> {
+        let op = self.next()?;
+        let alu = |op: &str| match op {
+            "|=" => Some(0x1),
+            "&=" => Some(0x2),
+            "^=" => Some(0x3),
+            "=-" => Some(0x7),
+            ">>=" => Some(0x6),
+            "<<=" => Some(0xE),
+            _ => None,
+        };
+        match op.text.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let mask = self.byte()? as u16;
+                    self.emit(0xC000 | (x << 8) | mask)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.emit(0xF007 | (x << 8))
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.emit(0xF00A | (x << 8))
+                }
+                _ => match self.operand()? {
+                    Operand::Register(y) => self.emit(0x8000 | (x << 8) | ((y as u16) << 4)),
+                    Operand::Byte(n) => self.emit(0x6000 | (x << 8) | n as u16),
+                },
+            },
+            "+=" => match self.operand()? {
+                Operand::Register(y) => self.emit(0x8004 | (x << 8) | ((y as u16) << 4)),
+                Operand::Byte(n) => self.emit(0x7000 | (x << 8) | n as u16),
+            },
+            "-=" => match self.operand()? {
+                Operand::Register(y) => self.emit(0x8005 | (x << 8) | ((y as u16) << 4)),
+                Operand::Byte(n) => self.emit(0x7000 | (x << 8) | n.wrapping_neg() as u16),
+            },
+            op => match alu(op) {
+                Some(n) => {
+                    let y = self.register()? as u16;
+                    self.emit(0x8000 | (x << 8) | (y << 4) | n)
+                }
+                None => self.error(format!("unknown operator '{}'", op)),
+            },
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), CompileError> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            ":" => {
+                let name = self.define_name()?;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // Not added to the symbol map, it points into the middle of an instruction
+                let name = self.define_name()?;
+                self.labels.insert(name, self.here as u16 + 1);
+            }
+            ":alias" => {
+                let name = self.define_name()?;
+                if self.peek() == Some("{") {
+                    let value = self.calc()?;
+                    self.constants.insert(name, value);
+                } else {
+                    let register = self.register()?;
+                    self.aliases.insert(name, register);
+                }
+            }
+            ":const" => {
+                let name = self.define_name()?;
+                let value = self.next()?;
+                let value = self.constant(&value)?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.define_name()?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            }
+            ":byte" => {
+                let byte = if self.peek() == Some("{") {
+                    self.calc()? as i64 as u8
+                } else {
+                    self.byte()?
+                };
+                self.emit_byte(byte)?;
+            }
+            ":org" => self.here = self.integer(0, MEMORY_SIZE as i64 - 1)? as usize,
+            ":unpack" => {
+                let high = if self.peek() == Some("long") {
+                    self.next()?;
+                    None
+                } else {
+                    Some(self.nibble()? as u8)
+                };
+                let token = self.next()?;
+                let address = self.here;
+                self.emit(0x6000)?;
+                self.emit(0x6100)?;
+                self.reference(token.clone(), address, Ref::UnpackHigh(high))?;
+                self.reference(token, address + 2, Ref::UnpackLow)?;
+            }
+            ":call" => self.emit_with_address(0x2000)?,
+            ":macro" => self.define_macro()?,
+            ":breakpoint" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            ";" | "return" => self.emit(0x00EE)?,
+            "clear" => self.emit(0x00E0)?,
+            "exit" => self.emit(0x00FD)?,
+            "lores" => self.emit(0x00FE)?,
+            "hires" => self.emit(0x00FF)?,
+            "scroll-right" => self.emit(0x00FB)?,
+            "scroll-left" => self.emit(0x00FC)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00C0 | n)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00D0 | n)?;
+            }
+            "audio" => self.emit(0xF002)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xF001 | (n << 8))?;
+            }
+            "bcd" => {
+                let x = self.register()? as u16;
+                self.emit(0xF033 | (x << 8))?;
+            }
+            "saveflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF075 | (x << 8))?;
+            }
+            "loadflags" => {
+                let x = self.register()? as u16;
+                self.emit(0xF085 | (x << 8))?;
+            }
+            "save" | "load" => {
+                let x = self.register()? as u16;
+                let store = token.text == "save";
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.register()? as u16;
+                    let opcode = if store { 0x5002 } else { 0x5003 };
+                    self.emit(opcode | (x << 8) | (y << 4))?;
+                } else {
+                    self.emit(if store { 0xF055 } else { 0xF065 } | (x << 8))?;
+                }
+            }
+            "sprite" => {
+                let x = self.register()? as u16;
+                let y = self.register()? as u16;
+                let n = self.nibble()?;
+                self.emit(0xD000 | (x << 8) | (y << 4) | n)?;
+            }
+            "jump" => self.emit_with_address(0x1000)?,
+            "jump0" => self.emit_with_address(0xB000)?,
+            "native" => self.emit_with_address(0x0000)?,
+            "i" => {
+                let op = self.next()?;
+                match (op.text.as_str(), self.peek()) {
+                    (":=", Some("hex")) => {
+                        self.next()?;
+                        let x = self.register()? as u16;
+                        self.emit(0xF029 | (x << 8))?;
+                    }
+                    (":=", Some("bighex")) => {
+                        self.next()?;
+                        let x = self.register()? as u16;
+                        self.emit(0xF030 | (x << 8))?;
+                    }
+                    (":=", Some("long")) => {
+                        self.next()?;
+                        let token = self.next()?;
+                        self.emit(0xF000)?;
+                        let address = self.here;
+                        self.emit(0x0000)?;
+                        self.reference(token, address, Ref::Long)?;
+                    }
+                    (":=", _) => self.emit_with_address(0xA000)?,
+                    ("+=", _) => {
+                        let x = self.register()? as u16;
+                        self.emit(0xF01E | (x << 8))?;
+                    }
+                    (op, _) => return self.error(format!("unknown operator '{}' for i", op)),
+                }
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()? as u16;
+                let opcode = match token.text.as_str() {
+                    "delay" => 0xF015,
+                    "buzzer" => 0xF018,
+                    _ => 0xF03A,
+                };
+                self.emit(opcode | (x << 8))?;
+            }
+            "if" => {
+                // Find out how the body is attached before compiling the condition
+                let attach = self
+                    .tokens
+                    .iter()
+                    .take(4)
+                    .find_map(|t| match t.text.as_str() {
+                        "then" | "begin" => Some(t.text.clone()),
+                        _ => None,
+                    });
+                match attach.as_deref() {
+                    Some("then") => {
+                        self.condition(false)?;
+                        self.expect("then")?;
+                        self.statement()?;
+                    }
+                    Some(_) => {
+                        self.condition(true)?;
+                        self.expect("begin")?;
+                        let jump = self.emit_jump_placeholder()?;
+                        self.blocks.push((
+                            Block::If {
+                                jump,
+                                has_else: false,
+                            },
+                            token.line,
+                        ));
+                    }
+                    None => return self.error("'if' without 'then' or 'begin'".to_string()),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((
+                    Block::If {
+                        jump,
+                        has_else: false,
+                    },
+                    line,
+                )) => {
+                    let skip_else = self.emit_jump_placeholder()?;
+                    self.patch(jump, Ref::Jump, self.here as u16)?;
+                    self.blocks.push((
+                        Block::If {
+                            jump: skip_else,
+                            has_else: true,
+                        },
+                        line,
+                    ));
+                }
+                _ => return self.error("'else' without 'if ... begin'".to_string()),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::If { jump, .. }, _)) => {
+                    self.patch(jump, Ref::Jump, self.here as u16)?
+                }
+                _ => return self.error("'end' without 'if ... begin'".to_string()),
+            },
+            "loop" => self.blocks.push((
+                Block::Loop {
+                    start: self.here,
+                    breaks: Vec::new(),
+                },
+                token.line,
+            )),
+            "while" => {
+                self.condition(true)?;
+                let jump = self.emit_jump_placeholder()?;
+                match self.blocks.last_mut() {
+                    Some((Block::Loop { breaks, .. }, _)) => breaks.push(jump),
+                    _ => return self.error("'while' outside of a loop".to_string()),
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, breaks }, _)) => {
+                    self.emit(0x1000)?;
+                    self.patch(self.here - 2, Ref::Jump, start as u16)?;
+                    for jump in breaks {
+                        self.patch(jump, Ref::Jump, self.here as u16)?;
+                    }
+                }
+                _ => return self.error("'again' without 'loop'".to_string()),
+            },
+            text => {
+                if let Some(x) = self.as_register(text) {
+                    self.register_statement(x as u16)?;
+                } else if self.macros.contains_key(text) {
+                    self.expand_macro(text)?;
+                } else if parse_integer(text).is_some() {
+                    let value = self.constant(&token)? as i64;
+                    if !(-128..=255).contains(&value) {
+                        return self.error(format!("'{}' does not fit in a byte", text));
+                    }
+                    self.emit_byte(value as u8)?;
+                } else if is_identifier(text) && !self.constants.contains_key(text) {
+                    // A bare name calls the subroutine with that label
+                    self.tokens.push_front(token);
+                    self.emit_with_address(0x2000)?;
+                } else {
+                    return self.error(format!("unknown instruction '{}'", text));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Program, CompileError> {
+        if let Some((block, line)) = self.blocks.pop() {
+            self.line = line;
+            return self.error(match block {
+                Block::If { .. } => "'begin' without 'end'".to_string(),
+                Block::Loop { .. } => "'loop' without 'again'".to_string(),
+            });
+        }
+        for fixup in std::mem::take(&mut self.fixups) {
+            self.line = fixup.line;
+            match self.labels.get(&fixup.name) {
+                Some(address) => self.patch(fixup.address, fixup.kind, *address)?,
+                None => return self.error(format!("undefined name '{}'", fixup.name)),
+            }
+        }
+        match self.labels.get("main") {
+            Some(_) if self.main_first => {}
+            Some(main) => self.patch(START, Ref::Jump, *main)?,
+            None => return self.error("program has no 'main' label".to_string()),
+        }
+        Ok(Program {
+            rom: self.memory[START..self.end].to_vec(),
+            symbols: self.symbols,
+        })
+    }
+}
+
+/// Compiles Octo assembly language to a ROM to load at 0x200. Execution starts at the
+/// `main` label, jumped to from 0x200 unless it is the first thing in the program.
+pub fn compile(source: &str) -> Result<Program, CompileError> {
+    let mut memory = vec![0; MEMORY_SIZE];
+    memory[START] = 0x10;
+    let mut compiler = Compiler {
+        tokens: tokenize(source),
+        line: 1,
+        memory,
+        here: START + 2,
+        end: START + 2,
+        main_first: false,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        expansions: 0,
+        symbols: SymbolMap::default(),
+    };
+    while !compiler.tokens.is_empty() {
+        compiler.statement()?;
+    }
+    compiler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(rom: &[u8]) -> Vec<u16> {
+        rom.chunks(2)
+            .map(|w| ((w[0] as u16) << 8) | w[1] as u16)
+            .collect()
+    }
+
+    #[test]
+    fn test_instructions() {
+        let rom = compile(
+            ": main
+               clear v3 := 0x12 v3 += v4 v3 -= 1 i := sprites sprite v1 v2 5
+               i := long sprites save v1 - v2 :byte { 2 * 3 + 1 } :byte -1
+             : sprites",
+        )
+        .unwrap()
+        .rom;
+        // :calc has no precedence, 2 * 3 + 1 is 2 * (3 + 1)
+        assert_eq!(
+            words(&rom),
+            vec![0x00E0, 0x6312, 0x8344, 0x73FF, 0xA214, 0xD125, 0xF000, 0x0214, 0x5122, 0x08FF]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let program = compile(
+            "jump main
+             : sub return
+             : main
+               loop
+                 while v0 != 3
+                 if v0 < v1 then sub
+                 if v0 key begin v1 := 1 else v1 := 2 end
+               again",
+        )
+        .unwrap();
+        assert_eq!(
+            words(&program.rom),
+            vec![
+                0x1206, 0x1206, 0x00EE, // jump to main, jump main, sub
+                0x4003, 0x121E, // while
+                0x8F00, 0x8F15, 0x3F01, 0x2204, // if v0 < v1
+                0xE09E, 0x121A, 0x6101, 0x121C, 0x6102, // if key begin else end
+                0x1206,
+            ]
+        );
+        assert_eq!(program.symbols.resolve("sub"), Ok(0x204));
+        assert_eq!(
+            program.symbols.symbolize(0x20E).as_deref(),
+            Some("main+0x8")
+        );
+        assert_eq!(
+            program.symbols.source_line(0x212).as_deref(),
+            Some("source:7")
+        );
+    }
+
+    #[test]
+    fn test_definitions() {
+        let rom = compile(
+            ":alias x v5
+             :const SIZE 4
+             :calc AREA { SIZE * SIZE }
+             :macro grow reg { reg += AREA }
+             : main grow x grow v1 :unpack 0xA data
+             : data",
+        )
+        .unwrap()
+        .rom;
+        assert_eq!(words(&rom), vec![0x7510, 0x7110, 0x60A2, 0x6108]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = compile(": main\n  v0 := 1\n  v0 := 300\n").unwrap_err();
+        assert_eq!(error.line, 3);
+        assert_eq!(compile(": main\n  jump nowhere").unwrap_err().line, 2);
+        assert!(compile("clear").unwrap_err().message.contains("main"));
+        assert_eq!(compile(": main\nloop\n clear").unwrap_err().line, 2);
+        assert_eq!(
+            compile(": main\n  frobnicate :=").unwrap_err().to_string(),
+            "line 2: unknown instruction ':='"
+        );
+    }
+}
@@ -0,0 +1,321 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// Number of rows printed for each table of the text report
+const REPORT_ROWS: usize = 20;
+
+#[derive(Default, Debug, Clone)]
+pub struct SubroutineStats {
+    pub calls: u64,
+    // Instructions executed between the call and the matching return, callees included
+    pub instructions: u64,
+    pub time: Duration,
+}
+
+struct ActiveCall {
+    address: u16,
+    entered: Instant,
+    instructions: u64,
+}
+
+pub struct Profiler {
+    started: Instant,
+    instructions: u64,
+    address_counts: HashMap<u16, u64>,
+    opcode_counts: HashMap<&'static str, u64>,
+    subroutines: HashMap<u16, SubroutineStats>,
+    call_stack: Vec<ActiveCall>,
+    draws_per_frame: Vec<u32>,
+    current_frame_draws: u32,
+}
+
+impl Profiler {
+    pub fn default() -> Self {
+        Profiler {
+            started: Instant::now(),
+            instructions: 0,
+            address_counts: HashMap::new(),
+            opcode_counts: HashMap::new(),
+            subroutines: HashMap::new(),
+            call_stack: Vec::new(),
+            draws_per_frame: Vec::new(),
+            current_frame_draws: 0,
+        }
+    }
+
+    /// Called for every fetched instruction, before it is executed
+    pub fn record_instruction(&mut self, address: u16, opcode: u16) {
+        self.instructions += 1;
+        *self.address_counts.entry(address).or_insert(0) += 1;
+        *self.opcode_counts.entry(opcode_class(opcode)).or_insert(0) += 1;
+    }
+
+    pub fn enter_subroutine(&mut self, address: u16) {
+        self.call_stack.push(ActiveCall {
+            address,
+            entered: Instant::now(),
+            instructions: self.instructions,
+        });
+    }
+
+    pub fn leave_subroutine(&mut self) {
+        // A return without a matching call (e.g. the ROM manipulated the stack) is ignored
+        if let Some(call) = self.call_stack.pop() {
+            let stats = self.subroutines.entry(call.address).or_default();
+            stats.calls += 1;
+            stats.instructions += self.instructions - call.instructions;
+            stats.time += call.entered.elapsed();
+        }
+    }
+
+    pub fn record_draw(&mut self) {
+        self.current_frame_draws += 1;
+    }
+
+    pub fn end_frame(&mut self) {
+        self.draws_per_frame.push(self.current_frame_draws);
+        self.current_frame_draws = 0;
+    }
+
+    fn hot_addresses(&self) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = self.address_counts.iter().map(|(a, c)| (*a, *c)).collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot
+    }
+
+    fn opcode_histogram(&self) -> Vec<(&'static str, u64)> {
+        let mut histogram: Vec<(&'static str, u64)> =
+            self.opcode_counts.iter().map(|(o, c)| (*o, *c)).collect();
+        histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        histogram
+    }
+
+    fn subroutines_by_time(&self) -> Vec<(u16, &SubroutineStats)> {
+        let mut subs: Vec<(u16, &SubroutineStats)> =
+            self.subroutines.iter().map(|(a, s)| (*a, s)).collect();
+        subs.sort_by(|a, b| b.1.time.cmp(&a.1.time).then(a.0.cmp(&b.0)));
+        subs
+    }
+
+    fn draw_stats(&self) -> (usize, f64, u32) {
+        let frames = self.draws_per_frame.len();
+        let total: u64 = self.draws_per_frame.iter().map(|d| *d as u64).sum();
+        let max = self.draws_per_frame.iter().copied().max().unwrap_or(0);
+        let average = if frames > 0 {
+            total as f64 / frames as f64
+        } else {
+            0.0
+        };
+        (frames, average, max)
+    }
+
+    pub fn text_report(&self) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let ips = if elapsed > 0.0 {
+            self.instructions as f64 / elapsed
+        } else {
+            0.0
+        };
+        let mut out = String::new();
+        writeln!(out, "=== Profile ===").unwrap();
+        writeln!(
+            out,
+            "{} instructions in {:.2}s ({:.0} IPS)",
+            self.instructions, elapsed, ips
+        )
+        .unwrap();
+
+        writeln!(out, "\nHottest addresses:").unwrap();
+        for (address, count) in self.hot_addresses().iter().take(REPORT_ROWS) {
+            writeln!(
+                out,
+                "  {:#05x}  {:>10}  {:>6.2}%",
+                address,
+                count,
+                percent(*count, self.instructions)
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nSubroutines (inclusive):").unwrap();
+        for (address, stats) in self.subroutines_by_time().iter().take(REPORT_ROWS) {
+            writeln!(
+                out,
+                "  {:#05x}  calls {:>8}  instructions {:>10}  time {:>9.3}ms",
+                address,
+                stats.calls,
+                stats.instructions,
+                stats.time.as_secs_f64() * 1000.0
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\nOpcodes:").unwrap();
+        for (class, count) in self.opcode_histogram() {
+            writeln!(
+                out,
+                "  {}  {:>10}  {:>6.2}%",
+                class,
+                count,
+                percent(count, self.instructions)
+            )
+            .unwrap();
+        }
+
+        let (frames, average, max) = self.draw_stats();
+        writeln!(
+            out,
+            "\nDraw calls: {} frames, {:.2} per frame on average, {} max",
+            frames, average, max
+        )
+        .unwrap();
+        out
+    }
+
+    pub fn json_report(&self) -> Value {
+        let (frames, average, max) = self.draw_stats();
+        json!({
+            "instructions": self.instructions,
+            "elapsed_seconds": self.started.elapsed().as_secs_f64(),
+            "addresses": self.hot_addresses().iter().map(|(address, count)| json!({
+                "address": address,
+                "count": count,
+            })).collect::<Vec<Value>>(),
+            "subroutines": self.subroutines_by_time().iter().map(|(address, stats)| json!({
+                "address": address,
+                "calls": stats.calls,
+                "instructions": stats.instructions,
+                "seconds": stats.time.as_secs_f64(),
+            })).collect::<Vec<Value>>(),
+            "opcodes": self.opcode_histogram().iter().map(|(class, count)| json!({
+                "opcode": class,
+                "count": count,
+            })).collect::<Vec<Value>>(),
+            "draws": {
+                "frames": frames,
+                "average_per_frame": average,
+                "max_per_frame": max,
+                "per_frame": self.draws_per_frame,
+            },
+        })
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+/// Groups an opcode with the others that share its instruction pattern, e.g. 0x6A05 -> "6XNN"
+pub fn opcode_class(opcode: u16) -> &'static str {
+    match (opcode & 0xF000) >> 12 {
+        0x0 => match opcode {
+            0x00E0 => "00E0",
+            0x00EE => "00EE",
+            _ => "0NNN",
+        },
+        0x1 => "1NNN",
+        0x2 => "2NNN",
+        0x3 => "3XNN",
+        0x4 => "4XNN",
+        0x5 => "5XY0",
+        0x6 => "6XNN",
+        0x7 => "7XNN",
+        0x8 => match opcode & 0x000F {
+            0x0 => "8XY0",
+            0x1 => "8XY1",
+            0x2 => "8XY2",
+            0x3 => "8XY3",
+            0x4 => "8XY4",
+            0x5 => "8XY5",
+            0x6 => "8XY6",
+            0x7 => "8XY7",
+            0xE => "8XYE",
+            _ => "8XY?",
+        },
+        0x9 => "9XY0",
+        0xA => "ANNN",
+        0xB => "BNNN",
+        0xC => "CXNN",
+        0xD => "DXYN",
+        0xE => match opcode & 0x00FF {
+            0x9E => "EX9E",
+            0xA1 => "EXA1",
+            _ => "EX??",
+        },
+        _ => match opcode & 0x00FF {
+            0x07 => "FX07",
+            0x0A => "FX0A",
+            0x15 => "FX15",
+            0x18 => "FX18",
+            0x1E => "FX1E",
+            0x29 => "FX29",
+            0x33 => "FX33",
+            0x55 => "FX55",
+            0x65 => "FX65",
+            _ => "FX??",
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_instruction() {
+        let mut profiler = Profiler::default();
+        profiler.record_instruction(0x200, 0x6A05);
+        profiler.record_instruction(0x202, 0x7A01);
+        profiler.record_instruction(0x200, 0x6A05);
+        assert_eq!(profiler.instructions, 3);
+        assert_eq!(profiler.address_counts[&0x200], 2);
+        assert_eq!(profiler.address_counts[&0x202], 1);
+        assert_eq!(profiler.opcode_histogram()[0], ("6XNN", 2));
+    }
+
+    #[test]
+    fn test_subroutine_stats() {
+        let mut profiler = Profiler::default();
+        profiler.enter_subroutine(0x300);
+        profiler.record_instruction(0x300, 0x2400);
+        profiler.enter_subroutine(0x400);
+        profiler.record_instruction(0x400, 0x00EE);
+        profiler.leave_subroutine();
+        profiler.record_instruction(0x302, 0x00EE);
+        profiler.leave_subroutine();
+        // Unbalanced returns are ignored
+        profiler.leave_subroutine();
+
+        assert_eq!(profiler.subroutines[&0x300].calls, 1);
+        assert_eq!(profiler.subroutines[&0x300].instructions, 3);
+        assert_eq!(profiler.subroutines[&0x400].instructions, 1);
+    }
+
+    #[test]
+    fn test_draw_stats() {
+        let mut profiler = Profiler::default();
+        profiler.record_draw();
+        profiler.record_draw();
+        profiler.end_frame();
+        profiler.end_frame();
+        profiler.record_draw();
+        profiler.end_frame();
+        assert_eq!(profiler.draw_stats(), (3, 1.0, 2));
+        let report = profiler.json_report();
+        assert_eq!(report["draws"]["per_frame"], json!([2, 0, 1]));
+    }
+
+    #[test]
+    fn test_opcode_class() {
+        assert_eq!(opcode_class(0x00E0), "00E0");
+        assert_eq!(opcode_class(0x0123), "0NNN");
+        assert_eq!(opcode_class(0x812E), "8XYE");
+        assert_eq!(opcode_class(0xF133), "FX33");
+    }
+}
@@ -0,0 +1,225 @@
+use crate::fonts::FontSet;
+use serde_json::Value;
+use std::fmt;
+
+/// Behaviours that differ between CHIP-8 interpreters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quirks {
+    // 8xy1/8xy2/8xy3 reset VF to 0
+    pub vf_reset: bool,
+    // Fx55/Fx65 leave I pointing after the last register
+    pub memory: bool,
+    // 8xy6/8xyE shift Vx in place instead of copying Vy first
+    pub shifting: bool,
+    // Bnnn jumps to xnn + Vx instead of nnn + V0
+    pub jumping: bool,
+    // Sprites are cut off at the screen edges instead of wrapping around
+    pub clipping: bool,
+    // Dxyn waits for the next 60Hz frame, as the VIP drew during vertical blank. Off on every
+    // platform, ROMs that need it turn it on in the ROM database or their Octo options.
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    /// Parses the quirk overrides of a `{"shifting": true, ...}` object on top of `self`
+    pub fn with_overrides(mut self, overrides: &Value) -> Result<Self, String> {
+        let object = overrides.as_object().ok_or("quirks must be an object")?;
+        for (name, value) in object {
+            let value = value
+                .as_bool()
+                .ok_or(format!("quirk '{}' must be true or false", name))?;
+            match name.as_str() {
+                "vf_reset" => self.vf_reset = value,
+                "memory" => self.memory = value,
+                "shifting" => self.shifting = value,
+                "jumping" => self.jumping = value,
+                "clipping" => self.clipping = value,
+                "display_wait" => self.display_wait = value,
+                _ => return Err(format!("unknown quirk '{}'", name)),
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            ("vf_reset", self.vf_reset),
+            ("memory", self.memory),
+            ("shifting", self.shifting),
+            ("jumping", self.jumping),
+            ("clipping", self.clipping),
+            ("display_wait", self.display_wait),
+        ];
+        let flags: Vec<String> = flags
+            .iter()
+            .map(|(name, on)| format!("{}={}", name, if *on { "on" } else { "off" }))
+            .collect();
+        write!(f, "{}", flags.join(" "))
+    }
+}
+
+/// Interpreter a ROM was written for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Platform {
+    Chip8,
+    Chip8X,
+    SuperChip,
+    XoChip,
+    MegaChip,
+    Eti660,
+    TwoPage,
+}
+
+impl Platform {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "chip8" | "chip-8" | "vip" => Some(Platform::Chip8),
+            "chip8x" | "chip-8x" => Some(Platform::Chip8X),
+            "schip" | "superchip" | "super-chip" => Some(Platform::SuperChip),
+            "xochip" | "xo-chip" => Some(Platform::XoChip),
+            "megachip" | "megachip8" | "mega-chip" => Some(Platform::MegaChip),
+            "eti660" | "eti-660" => Some(Platform::Eti660),
+            "twopage" | "two-page" | "chip8-64x64" => Some(Platform::TwoPage),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::Chip8X => "chip8x",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+            Platform::MegaChip => "megachip",
+            Platform::Eti660 => "eti660",
+            Platform::TwoPage => "twopage",
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match self {
+            Platform::Chip8 | Platform::Chip8X | Platform::Eti660 | Platform::TwoPage => Quirks {
+                vf_reset: true,
+                memory: true,
+                shifting: false,
+                jumping: false,
+                clipping: true,
+                display_wait: false,
+            },
+            // MegaChip is built on SUPER-CHIP
+            Platform::SuperChip | Platform::MegaChip => Quirks {
+                vf_reset: false,
+                memory: false,
+                shifting: true,
+                jumping: true,
+                clipping: true,
+                display_wait: false,
+            },
+            Platform::XoChip => Quirks {
+                vf_reset: false,
+                memory: true,
+                shifting: false,
+                jumping: false,
+                clipping: false,
+                display_wait: false,
+            },
+        }
+    }
+
+    /// Font the interpreter comes with
+    pub fn font(&self) -> FontSet {
+        match self {
+            Platform::Chip8 | Platform::XoChip => FontSet::Octo,
+            Platform::Chip8X | Platform::TwoPage => FontSet::Vip,
+            Platform::Eti660 => FontSet::Eti660,
+            Platform::SuperChip | Platform::MegaChip => FontSet::SuperChip,
+        }
+    }
+
+    /// Address programs are loaded at and start running from
+    pub fn load_address(&self) -> u16 {
+        match self {
+            // The CHIP-8X interpreter takes up 0x300 bytes
+            Platform::Chip8X => 0x300,
+            // The ETI-660 monitor and interpreter use the first 0x600 bytes
+            Platform::Eti660 => 0x600,
+            _ => 0x200,
+        }
+    }
+
+    /// Bytes of memory the interpreter has for a program of `program_size` bytes. MegaChip could
+    /// address 16M but only gets enough for the program, so snapshots don't copy empty memory
+    pub fn memory_size(&self, program_size: usize) -> usize {
+        match self {
+            Platform::Chip8
+            | Platform::Chip8X
+            | Platform::SuperChip
+            | Platform::Eti660
+            | Platform::TwoPage => 0x1000,
+            Platform::XoChip => 0x10000,
+            Platform::MegaChip => (self.load_address() as usize + program_size)
+                .next_power_of_two()
+                .clamp(0x10000, 0x1000000),
+        }
+    }
+
+    /// Number of RPL user flags Fx75/Fx85 can save and restore
+    pub fn flag_registers(&self) -> usize {
+        match self {
+            Platform::XoChip => 16,
+            _ => 8,
+        }
+    }
+
+    /// Instructions per 60Hz frame
+    pub fn ipf(&self) -> u32 {
+        match self {
+            Platform::Chip8 | Platform::Chip8X | Platform::Eti660 | Platform::TwoPage => 12,
+            Platform::SuperChip => 30,
+            Platform::XoChip | Platform::MegaChip => 1000,
+        }
+    }
+
+    /// Width and height of the display in pixels
+    pub fn resolution(&self) -> (usize, usize) {
+        match self {
+            Platform::Eti660 => (64, 48),
+            // 0230 clears both pages of the two-page display
+            Platform::TwoPage => (64, 64),
+            _ => (64, 32),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_overrides() {
+        let quirks = Platform::Chip8
+            .quirks()
+            .with_overrides(&json!({"shifting": true, "vf_reset": false}))
+            .unwrap();
+        assert!(quirks.shifting);
+        assert!(!quirks.vf_reset);
+        assert!(quirks.memory);
+        assert!(Quirks::with_overrides(quirks, &json!({"wrapping": true})).is_err());
+        assert!(Quirks::with_overrides(quirks, &json!({"memory": 1})).is_err());
+        assert_eq!(
+            quirks.to_string(),
+            "vf_reset=off memory=on shifting=on jumping=off clipping=on display_wait=off"
+        );
+    }
+
+    #[test]
+    fn test_memory_size() {
+        assert_eq!(Platform::Chip8.memory_size(0x800), 0x1000);
+        assert_eq!(Platform::MegaChip.memory_size(0x800), 0x10000);
+        assert_eq!(Platform::MegaChip.memory_size(0x20000), 0x40000);
+        assert_eq!(Platform::MegaChip.memory_size(0x2000000), 0x1000000);
+    }
+}
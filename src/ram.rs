@@ -1,38 +1,112 @@
-use std::fs::File;
-use std::io::{self, Read};
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Access {
+    pub address: usize,
+    pub kind: AccessKind,
+    pub value: u8,
+}
+
+// Largest memory of any platform, MegaChip's 16M
+pub const MAX_MEMORY_SIZE: usize = 0x1000000;
+// Memory reachable with 16 bit addresses, all that analysis of a program looks at
+pub const ADDRESS_SPACE: usize = 0x10000;
 
 pub struct RAM {
-    pub cart: [u8; 4096],
+    pub cart: Vec<u8>,
+    // End of the loaded program
     pub cart_size: usize,
+    // Where programs are loaded and start running
+    pub load_address: usize,
+    // SHA-1 of the loaded ROM file, used to look it up in the ROM database
+    pub rom_sha1: String,
+    // When enabled, every read/write goes into `accesses` until the CPU drains it
+    pub trace_accesses: bool,
+    accesses: RefCell<Vec<Access>>,
 }
 
 impl RAM {
     pub fn default() -> Self {
-        return RAM {
-            cart: [0; 4096],
-            cart_size: 0,
-        };
+        RAM::new(4096, 0x200)
+    }
+    pub fn new(size: usize, load_address: usize) -> Self {
+        RAM {
+            cart: vec![0; size],
+            cart_size: load_address,
+            load_address,
+            rom_sha1: String::new(),
+            trace_accesses: false,
+            accesses: RefCell::new(Vec::new()),
+        }
     }
     pub fn load(&mut self, path: &str) -> io::Result<()> {
-        let mut file = File::open(path)?;
-
-        // buffer is a maximum of 4096 which is the same as my cartridge
-        let mut buffer = vec![0; 4096];
-        let bytes_read = file.read(&mut buffer)?;
-
-        self.cart[0x200..(0x200 + bytes_read)].copy_from_slice(&buffer[..bytes_read]);
-
-        self.cart_size = 0x200 + bytes_read;
+        let program = fs::read(path)?;
+        self.load_bytes(&program)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+    /// Loads a program that is already in memory, like one compiled from source
+    pub fn load_bytes(&mut self, program: &[u8]) -> Result<(), String> {
+        let space = self.cart.len().saturating_sub(self.load_address);
+        if program.len() > space {
+            return Err(format!(
+                "ROM is {} bytes but only {} fit between {:#05x} and the end of memory ({} bytes)",
+                program.len(),
+                space,
+                self.load_address,
+                self.cart.len()
+            ));
+        }
+        let start = self.load_address;
+        self.cart[start..(start + program.len())].copy_from_slice(program);
 
+        self.cart_size = start + program.len();
+        self.rom_sha1 = sha1_smol::Sha1::from(program).digest().to_string();
         Ok(())
     }
+    /// The loaded program
+    pub fn program(&self) -> &[u8] {
+        &self.cart[self.load_address..self.cart_size]
+    }
     pub fn read(&self, address: usize) -> Result<u8, &'static str> {
-        Ok(self.cart[address])
+        let value = *self.cart.get(address).ok_or("Read out of bounds")?;
+        if self.trace_accesses {
+            self.accesses.borrow_mut().push(Access {
+                address,
+                kind: AccessKind::Read,
+                value,
+            });
+        }
+        Ok(value)
     }
     pub fn write(&mut self, address: usize, value: u8) -> Result<(), &'static str> {
-        self.cart[address] = value;
+        *self.cart.get_mut(address).ok_or("Write out of bounds")? = value;
+        if self.trace_accesses {
+            self.accesses.borrow_mut().push(Access {
+                address,
+                kind: AccessKind::Write,
+                value,
+            });
+        }
         Ok(())
     }
+    // Instruction fetches are not data accesses, so they bypass the access trace
+    pub fn fetch(&self, address: usize) -> Result<u16, &'static str> {
+        if address + 1 >= self.cart.len() {
+            return Err("Fetch out of bounds");
+        }
+        Ok(((self.cart[address] as u16) << 8) | self.cart[address + 1] as u16)
+    }
+    pub fn take_accesses(&self) -> Vec<Access> {
+        std::mem::take(&mut *self.accesses.borrow_mut())
+    }
 }
 
 #[cfg(test)]
@@ -48,4 +122,55 @@ mod tests {
         assert_eq!(ram.cart_size, 2907);
         assert_eq!(ram.cart[0x200], 0x1c);
     }
+
+    #[test]
+    fn rom_hash_test() {
+        let path = std::env::temp_dir().join("chip8_ram_hash_test.ch8");
+        std::fs::write(&path, b"abc").unwrap();
+        let mut ram = RAM::default();
+        ram.load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(ram.rom_sha1, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn layout_test() {
+        let mut ram = RAM::new(0x800, 0x600);
+        ram.load_bytes(&[0xAB; 0x200]).unwrap();
+        assert_eq!((ram.cart[0x600], ram.cart[0x7FF]), (0xAB, 0xAB));
+        assert_eq!(ram.cart_size, 0x800);
+        assert_eq!(ram.program().len(), 0x200);
+
+        let error = ram.load_bytes(&[0; 0x201]).unwrap_err();
+        assert!(error.starts_with("ROM is 513 bytes but only 512 fit"));
+        assert_eq!(ram.read(0x800), Err("Read out of bounds"));
+    }
+
+    #[test]
+    fn access_trace_test() {
+        let mut ram = RAM::default();
+        ram.write(0x300, 1).unwrap();
+        assert!(ram.take_accesses().is_empty());
+
+        ram.trace_accesses = true;
+        ram.write(0x300, 2).unwrap();
+        ram.read(0x301).unwrap();
+        ram.fetch(0x300).unwrap();
+        assert_eq!(
+            ram.take_accesses(),
+            vec![
+                Access {
+                    address: 0x300,
+                    kind: AccessKind::Write,
+                    value: 2
+                },
+                Access {
+                    address: 0x301,
+                    kind: AccessKind::Read,
+                    value: 0
+                },
+            ]
+        );
+        assert!(ram.take_accesses().is_empty());
+    }
 }
@@ -1,9 +1,63 @@
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Reg {
     pub v: [u8; 16],
-    pub i: u16,
+    // 16 bits except on MegaChip, which addresses 16MB
+    pub i: u32,
     pub delay_timer: u8,
     pub sound_time: u8,
     pub pc: u16,
     pub sp: u8,
 }
+
+/// A single register of `Reg`, as named in debugger commands and expressions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegName {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl RegName {
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "i" => Some(RegName::I),
+            "pc" => Some(RegName::Pc),
+            "sp" => Some(RegName::Sp),
+            "dt" => Some(RegName::Dt),
+            "st" => Some(RegName::St),
+            _ => {
+                let index = name.strip_prefix('v')?;
+                if index.len() != 1 {
+                    return None;
+                }
+                u8::from_str_radix(index, 16).ok().map(RegName::V)
+            }
+        }
+    }
+
+    pub fn read(&self, reg: &Reg) -> u32 {
+        match self {
+            RegName::V(x) => reg.v[*x as usize] as u32,
+            RegName::I => reg.i,
+            RegName::Pc => reg.pc as u32,
+            RegName::Sp => reg.sp as u32,
+            RegName::Dt => reg.delay_timer as u32,
+            RegName::St => reg.sound_time as u32,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            RegName::V(x) => format!("v{:x}", x),
+            RegName::I => "i".to_string(),
+            RegName::Pc => "pc".to_string(),
+            RegName::Sp => "sp".to_string(),
+            RegName::Dt => "dt".to_string(),
+            RegName::St => "st".to_string(),
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::display::Palette;
+use crate::fonts::FontSet;
+use crate::keyboard::KeyMap;
+use crate::quirks::{Platform, Quirks};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+
+// Database shipped with the emulator, entries from `--rom-db` files are added on top
+const BUNDLED: &str = include_str!("../data/roms.json");
+
+/// Everything needed to run a ROM the way it was meant to be played
+#[derive(Debug, Clone, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub author: Option<String>,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    // Instructions per 60Hz frame
+    pub ipf: u32,
+    pub keymap: KeyMap,
+    pub palette: Palette,
+    pub font: FontSet,
+}
+
+impl RomInfo {
+    /// Settings for a ROM that is not in any database
+    pub fn unknown(platform: Platform) -> Self {
+        RomInfo {
+            title: "Unknown ROM".to_string(),
+            author: None,
+            platform,
+            quirks: platform.quirks(),
+            ipf: platform.ipf(),
+            keymap: KeyMap::default(),
+            palette: Palette::default(),
+            font: platform.font(),
+        }
+    }
+
+    // Reads one database entry, only `title` is required and everything else falls back to
+    // the defaults of the platform
+    fn parse(entry: &Value) -> Result<Self, String> {
+        let title = entry["title"].as_str().ok_or("missing title")?;
+        let platform = match entry["platform"].as_str() {
+            Some(name) => Platform::parse(name).ok_or(format!("unknown platform '{}'", name))?,
+            None => Platform::Chip8,
+        };
+        let mut info = RomInfo::unknown(platform);
+        info.title = title.to_string();
+        info.author = entry["author"].as_str().map(|a| a.to_string());
+        if !entry["quirks"].is_null() {
+            info.quirks = info.quirks.with_overrides(&entry["quirks"])?;
+        }
+        if let Some(ipf) = entry["ipf"].as_u64() {
+            info.ipf = ipf.clamp(1, 100_000) as u32;
+        }
+        if let Some(name) = entry["font"].as_str() {
+            info.font = FontSet::parse(name).ok_or(format!("unknown font '{}'", name))?;
+        }
+        if let Some(keys) = entry["keymap"].as_object() {
+            for (chip8_key, modern_key) in keys {
+                let chip8_key = u8::from_str_radix(chip8_key, 16)
+                    .ok()
+                    .filter(|k| *k < 16)
+                    .ok_or(format!("invalid CHIP-8 key '{}'", chip8_key))?;
+                let mut modern_key = modern_key.as_str().unwrap_or("").chars();
+                match (modern_key.next(), modern_key.next()) {
+                    (Some(c), None) => info.keymap.remap(chip8_key, c),
+                    _ => return Err("keymap values must be single characters".to_string()),
+                }
+            }
+        }
+        if let Some(colors) = entry["palette"].as_array() {
+            let color = |index: usize| {
+                colors
+                    .get(index)
+                    .and_then(|c| c.as_str())
+                    .and_then(Palette::parse_color)
+                    .ok_or("palette needs two #rrggbb colours")
+            };
+            info.palette = Palette {
+                background: color(0)?,
+                foreground: color(1)?,
+            };
+        }
+        Ok(info)
+    }
+}
+
+/// ROM settings indexed by the SHA-1 of the ROM file. The JSON format is
+///
+/// `{"roms": [{"sha1": "...", "title": "...", "author": "...", "platform": "schip",
+///   "quirks": {"shifting": true}, "ipf": 30, "keymap": {"5": "i"},
+///   "palette": ["#000000", "#ffcc00"], "font": "vip"}]}`
+pub struct RomDatabase {
+    roms: HashMap<String, RomInfo>,
+}
+
+impl RomDatabase {
+    pub fn bundled() -> Self {
+        RomDatabase::parse(BUNDLED).expect("Bundled ROM database is invalid")
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let entries = json["roms"].as_array().ok_or("missing 'roms' list")?;
+        let mut roms = HashMap::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let hash = entry["sha1"]
+                .as_str()
+                .ok_or(format!("rom #{}: missing sha1", index))?;
+            let info = RomInfo::parse(entry).map_err(|e| format!("rom #{}: {}", index, e))?;
+            roms.insert(hash.to_ascii_lowercase(), info);
+        }
+        Ok(RomDatabase { roms })
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        RomDatabase::parse(&text)
+    }
+
+    /// Adds the entries of `other`, replacing existing ones for the same ROM
+    pub fn merge(&mut self, other: RomDatabase) {
+        self.roms.extend(other.roms);
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<&RomInfo> {
+        self.roms.get(&sha1.to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bundled() {
+        let logo = [
+            0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x0C, 0x61, 0x08, 0xD0, 0x1F, 0x70, 0x09, 0xA2, 0x39,
+            0xD0, 0x1F, 0xA2, 0x48, 0x70, 0x08, 0xD0, 0x1F, 0x70, 0x04, 0xA2, 0x57, 0xD0, 0x1F,
+            0x70, 0x08, 0xA2, 0x66, 0xD0, 0x1F, 0x70, 0x08, 0xA2, 0x75, 0xD0, 0x1F, 0x12, 0x28,
+            0xFF, 0x00, 0xFF, 0x00, 0x3C, 0x00, 0x3C, 0x00, 0x3C, 0x00, 0x3C, 0x00, 0xFF, 0x00,
+            0xFF, 0xFF, 0x00, 0xFF, 0x00, 0x38, 0x00, 0x3F, 0x00, 0x3F, 0x00, 0x38, 0x00, 0xFF,
+            0x00, 0xFF, 0x80, 0x00, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00, 0x80, 0x00, 0xE0, 0x00,
+            0xE0, 0x00, 0x80, 0xF8, 0x00, 0xFC, 0x00, 0x3E, 0x00, 0x3F, 0x00, 0x3B, 0x00, 0x39,
+            0x00, 0xF8, 0x00, 0xF8, 0x03, 0x00, 0x07, 0x00, 0x0F, 0x00, 0xBF, 0x00, 0xFB, 0x00,
+            0xF3, 0x00, 0xE3, 0x00, 0x43, 0xE0, 0x00, 0xE0, 0x00, 0x80, 0x00, 0x80, 0x00, 0x80,
+            0x00, 0x80, 0x00, 0xE0, 0x00, 0xE0,
+        ];
+        let sha1 = sha1_smol::Sha1::from(&logo[..]).digest().to_string();
+        let db = RomDatabase::bundled();
+        let info = db.lookup(&sha1).unwrap();
+        assert_eq!(info.title, "IBM Logo");
+        assert_eq!(info.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut db = RomDatabase::parse(
+            r##"{"roms": [
+                {"sha1": "AA11", "title": "Game", "author": "Someone", "platform": "schip",
+                 "quirks": {"clipping": false}, "ipf": 20, "keymap": {"5": "i"},
+                 "palette": ["#102030", "#ffcc00"]},
+                {"sha1": "bb22", "title": "Other"}
+            ]}"##,
+        )
+        .unwrap();
+        let game = db.lookup("aa11").unwrap();
+        assert_eq!(game.author.as_deref(), Some("Someone"));
+        assert_eq!(game.platform, Platform::SuperChip);
+        assert!(game.quirks.shifting);
+        assert!(!game.quirks.clipping);
+        assert_eq!(game.ipf, 20);
+        assert_eq!(game.keymap.chip8_key_for('i'), Some(0x5));
+        assert_eq!(game.palette.background, (0x10, 0x20, 0x30));
+
+        let other = db.lookup("BB22").unwrap();
+        assert_eq!(other.quirks, Platform::Chip8.quirks());
+        assert_eq!(other.ipf, Platform::Chip8.ipf());
+        assert!(db.lookup("cc33").is_none());
+
+        db.merge(RomDatabase::parse(r#"{"roms": [{"sha1": "bb22", "title": "New"}]}"#).unwrap());
+        assert_eq!(db.lookup("bb22").unwrap().title, "New");
+
+        assert!(RomDatabase::parse(
+            r#"{"roms": [{"sha1": "1", "title": "x", "platform": "nes"}]}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,320 @@
+use crate::register::Reg;
+use fltk::{prelude::*, *};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::rc::Rc;
+
+// Gray levels used for sprite sheets
+const OFF: u8 = 0x00;
+const ON: u8 = 0xFF;
+const GRID: u8 = 0x40;
+
+// Layout of the viewer, sprites are drawn at 4x
+const VIEW_SCALE: usize = 4;
+const VIEW_COLUMNS: usize = 8;
+const VIEW_ROWS: usize = 4;
+
+/// Pixels of one sprite row, most significant bit first, as `DRW` draws them
+pub fn row_pixels(byte: u8) -> [bool; 8] {
+    let mut pixels = [false; 8];
+    for (bit, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = byte & (0x80 >> bit) != 0;
+    }
+    pixels
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpriteSize {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl SpriteSize {
+    /// 8 pixels wide sprite as drawn by `DXYN`
+    pub fn chip8(height: usize) -> Self {
+        SpriteSize { width: 8, height }
+    }
+
+    /// 16x16 sprite as drawn by SCHIP's `DXY0`, two bytes per row
+    pub fn schip() -> Self {
+        SpriteSize {
+            width: 16,
+            height: 16,
+        }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.width / 8 * self.height
+    }
+}
+
+/// Grid of consecutive sprites as grayscale pixels, with a one pixel border around each sprite
+pub struct SpriteSheet {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl SpriteSheet {
+    /// Decodes `count` sprites starting at `start`, laid out `columns` per row. Bytes past the
+    /// end of memory are drawn blank.
+    pub fn new(
+        memory: &[u8],
+        start: usize,
+        count: usize,
+        size: SpriteSize,
+        columns: usize,
+    ) -> Self {
+        let columns = columns.clamp(1, count.max(1));
+        let rows = count.div_ceil(columns);
+        let width = columns * (size.width + 1) + 1;
+        let height = rows * (size.height + 1) + 1;
+        let mut pixels = vec![GRID; width * height];
+        for sprite in 0..count {
+            let left = sprite % columns * (size.width + 1) + 1;
+            let top = sprite / columns * (size.height + 1) + 1;
+            let address = start + sprite * size.bytes();
+            for y in 0..size.height {
+                for half in 0..size.width / 8 {
+                    let byte = *memory
+                        .get(address + y * size.width / 8 + half)
+                        .unwrap_or(&0);
+                    for (x, pixel) in row_pixels(byte).iter().enumerate() {
+                        pixels[(top + y) * width + left + half * 8 + x] =
+                            if *pixel { ON } else { OFF };
+                    }
+                }
+            }
+        }
+        SpriteSheet {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Pixels enlarged `scale` times in both directions
+    pub fn scaled(&self, scale: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * scale * scale);
+        for row in self.pixels.chunks(self.width) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|p| std::iter::repeat_n(*p, scale))
+                .collect();
+            for _ in 0..scale {
+                out.extend_from_slice(&line);
+            }
+        }
+        out
+    }
+
+    pub fn save_png(&self, path: &str, scale: usize) -> io::Result<()> {
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(
+            file,
+            (self.width * scale) as u32,
+            (self.height * scale) as u32,
+        );
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.scaled(scale))?;
+        Ok(())
+    }
+
+    /// The sheet as text, `#` for set pixels and `.` for clear ones
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for row in self.pixels.chunks(self.width) {
+            for pixel in row {
+                out.push(match *pixel {
+                    ON => '#',
+                    OFF => '.',
+                    _ => ' ',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+struct ViewState {
+    memory: Vec<u8>,
+    start: usize,
+    size: SpriteSize,
+    follow_i: bool,
+}
+
+impl ViewState {
+    fn sheet(&self) -> SpriteSheet {
+        SpriteSheet::new(
+            &self.memory,
+            self.start,
+            VIEW_COLUMNS * VIEW_ROWS,
+            self.size,
+            VIEW_COLUMNS,
+        )
+    }
+}
+
+/// Window rendering memory as sprites, following I while it runs
+pub struct SpriteViewer {
+    window: window::Window,
+    view: widget::Widget,
+    address: input::Input,
+    state: Rc<RefCell<ViewState>>,
+}
+
+impl SpriteViewer {
+    pub fn new() -> Self {
+        let sheet_width = (VIEW_COLUMNS * 17 + 1) * VIEW_SCALE;
+        let sheet_height = (VIEW_ROWS * 17 + 1) * VIEW_SCALE;
+        let mut window = window::Window::new(
+            100,
+            460,
+            sheet_width as i32 + 10,
+            sheet_height as i32 + 75,
+            "Sprites",
+        );
+        let mut view = widget::Widget::new(5, 5, sheet_width as i32, sheet_height as i32, None);
+        let bottom = sheet_height as i32 + 10;
+        let mut address = input::Input::new(65, bottom, 70, 25, "Address:");
+        let mut follow_i = button::CheckButton::new(145, bottom, 90, 25, "Follow I");
+        follow_i.set_checked(true);
+        let mut height = input::IntInput::new(295, bottom, 40, 25, "Height:");
+        height.set_value("8");
+        let mut schip = button::CheckButton::new(345, bottom, 80, 25, "16x16");
+        let mut export = button::Button::new(5, bottom + 35, 100, 25, "Export PNG");
+        window.end();
+        window.show();
+
+        let state = Rc::new(RefCell::new(ViewState {
+            memory: Vec::new(),
+            start: 0x200,
+            size: SpriteSize::chip8(8),
+            follow_i: true,
+        }));
+
+        let draw_state = state.clone();
+        view.draw(move |w| {
+            let sheet = draw_state.borrow().sheet();
+            draw::draw_rect_fill(w.x(), w.y(), w.w(), w.h(), enums::Color::Black);
+            let _ = draw::draw_image(
+                &sheet.scaled(VIEW_SCALE),
+                w.x(),
+                w.y(),
+                (sheet.width * VIEW_SCALE) as i32,
+                (sheet.height * VIEW_SCALE) as i32,
+                enums::ColorDepth::L8,
+            );
+        });
+
+        let address_state = state.clone();
+        let mut address_view = view.clone();
+        let address_follow = follow_i.clone();
+        address.set_trigger(enums::CallbackTrigger::EnterKey);
+        address.set_callback(move |i| {
+            if let Some(start) = crate::expr::parse_number(i.value().trim()) {
+                let mut state = address_state.borrow_mut();
+                state.start = start.clamp(0, 0xFFF) as usize;
+                state.follow_i = false;
+                address_follow.set_checked(false);
+                address_view.redraw();
+            }
+        });
+
+        let follow_state = state.clone();
+        follow_i.set_callback(move |b| {
+            follow_state.borrow_mut().follow_i = b.is_checked();
+        });
+
+        // Rereads both size controls whenever one of them changes
+        let size_controls = (height.clone(), schip.clone());
+        let size_state = state.clone();
+        let mut size_view = view.clone();
+        let mut update_size = move || {
+            let (height, schip) = &size_controls;
+            let size = if schip.is_checked() {
+                SpriteSize::schip()
+            } else {
+                let rows = height.value().parse().unwrap_or(8);
+                SpriteSize::chip8(rows.clamp(1, 15))
+            };
+            size_state.borrow_mut().size = size;
+            size_view.redraw();
+        };
+        let mut update_size_clone = update_size.clone();
+        height.set_trigger(enums::CallbackTrigger::Changed);
+        height.set_callback(move |_| update_size());
+        schip.set_callback(move |_| update_size_clone());
+
+        let export_state = state.clone();
+        export.set_callback(move |_| {
+            if let Some(path) = dialog::file_chooser("Export sprite sheet", "*.png", ".", false) {
+                let sheet = export_state.borrow().sheet();
+                if let Err(e) = sheet.save_png(&path, VIEW_SCALE) {
+                    dialog::alert_default(&format!("Could not write {}: {}", path, e));
+                }
+            }
+        });
+
+        SpriteViewer {
+            window,
+            view,
+            address,
+            state,
+        }
+    }
+
+    pub fn update(&mut self, memory: &[u8], reg: &Reg) {
+        if !self.window.shown() {
+            return;
+        }
+        let mut state = self.state.borrow_mut();
+        let mut changed = state.memory[..] != memory[..];
+        if changed {
+            state.memory = memory.to_vec();
+        }
+        if state.follow_i && state.start != reg.i as usize {
+            state.start = reg.i as usize;
+            self.address.set_value(&format!("{:#05x}", reg.i));
+            changed = true;
+        }
+        if changed {
+            self.view.redraw();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_pixels() {
+        assert_eq!(
+            row_pixels(0b1010_0001),
+            [true, false, true, false, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_sprite_sheet() {
+        // The "0" and "1" font characters
+        let memory = [0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70];
+        let sheet = SpriteSheet::new(&memory, 0, 2, SpriteSize::chip8(5), 8);
+        assert_eq!((sheet.width, sheet.height), (19, 7));
+        let text = sheet.to_text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[1], " ####.... ..#..... ");
+        assert_eq!(lines[2], " #..#.... .##..... ");
+        assert_eq!(lines[5], " ####.... .###.... ");
+        assert_eq!(sheet.scaled(2).len(), 19 * 7 * 4);
+
+        let big = SpriteSheet::new(&[0xFF, 0x01], 0, 1, SpriteSize::schip(), 4);
+        assert_eq!((big.width, big.height), (18, 18));
+        assert_eq!(big.to_text().lines().nth(1).unwrap(), " ########.......# ");
+    }
+}
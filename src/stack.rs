@@ -1,3 +1,4 @@
+#[derive(Clone)]
 pub struct Stack {
     items: [u16; 16], // Array of 16 16-bit values
     top: usize,       // Index of the top element
@@ -37,6 +38,10 @@ impl Stack {
     pub fn size(&self) -> usize {
         self.top // Return the current size of the stack
     }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.items[..self.top] // Bottom of the stack first
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,231 @@
+use crate::expr::parse_number;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Labels and source lines of a compiled program, saved next to the ROM as JSON:
+///
+/// `{"source": "game.8o", "labels": [["main", 512], ...], "lines": [[512, 3], ...]}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SymbolMap {
+    // Path of the source file the program was compiled from
+    pub source: Option<String>,
+    // In definition order, the first label at an address is the one shown for it
+    labels: Vec<(String, u16)>,
+    by_address: BTreeMap<u16, String>,
+    // Source line of each instruction
+    lines: BTreeMap<u16, usize>,
+    // Source text, when available, to show the line itself
+    text: Vec<String>,
+}
+
+impl SymbolMap {
+    pub fn default() -> Self {
+        SymbolMap {
+            source: None,
+            labels: Vec::new(),
+            by_address: BTreeMap::new(),
+            lines: BTreeMap::new(),
+            text: Vec::new(),
+        }
+    }
+
+    pub fn add_label(&mut self, name: &str, address: u16) {
+        self.labels.push((name.to_string(), address));
+        self.by_address
+            .entry(address)
+            .or_insert_with(|| name.to_string());
+    }
+
+    pub fn add_line(&mut self, address: u16, line: usize) {
+        self.lines.insert(address, line);
+    }
+
+    pub fn set_text(&mut self, text: &str) {
+        self.text = text.lines().map(|l| l.trim().to_string()).collect();
+    }
+
+    /// `address` relative to the closest label before it, e.g. `main_loop+0x4`
+    pub fn symbolize(&self, address: u16) -> Option<String> {
+        let (label_address, name) = self.by_address.range(..=address).next_back()?;
+        Some(match address - label_address {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+
+    fn file_name(&self) -> &str {
+        match &self.source {
+            Some(source) => source,
+            None => "source",
+        }
+    }
+
+    /// Source line of the instruction at `address`
+    pub fn line(&self, address: u16) -> Option<usize> {
+        self.lines.get(&address).copied()
+    }
+
+    /// `file:line: text` of the instruction at `address`
+    pub fn source_line(&self, address: u16) -> Option<String> {
+        let line = self.line(address)?;
+        let mut location = format!("{}:{}", self.file_name(), line);
+        if let Some(text) = line.checked_sub(1).and_then(|i| self.text.get(i)) {
+            location += &format!(": {}", text);
+        }
+        Some(location)
+    }
+
+    /// Everything known about `address`, or None if the map has nothing on it
+    pub fn location(&self, address: u16) -> Option<String> {
+        match (self.symbolize(address), self.source_line(address)) {
+            (Some(symbol), Some(line)) => Some(format!("{} {}", symbol, line)),
+            (symbol, line) => symbol.or(line),
+        }
+    }
+
+    /// Address followed by its location, e.g. `0x204 main_loop+0x4 game.8o:12: v0 += 1`
+    pub fn describe(&self, address: u16) -> String {
+        match self.location(address) {
+            Some(location) => format!("{:#05x} {}", address, location),
+            None => format!("{:#05x}", address),
+        }
+    }
+
+    /// Reads an address given as a number, `label`, `label+offset` or `file:line`
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        if let Some(address) = parse_number(text) {
+            return u16::try_from(address).map_err(|_| format!("invalid address '{}'", text));
+        }
+        if let Some((file, line)) = text.rsplit_once(':') {
+            let line: usize = line
+                .parse()
+                .map_err(|_| format!("invalid line '{}'", line))?;
+            let source = self.source.as_deref().ok_or("no source file is known")?;
+            if Path::new(source).file_name() != Path::new(file).file_name() {
+                return Err(format!("'{}' is not the source file ({})", file, source));
+            }
+            // Lines without code resolve to the next line that has some
+            return self
+                .lines
+                .iter()
+                .filter(|(_, l)| **l >= line)
+                .min_by_key(|(address, l)| (**l, **address))
+                .map(|(address, _)| *address)
+                .ok_or(format!("no code at or after {}", text));
+        }
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => {
+                let offset = parse_number(offset).ok_or(format!("invalid offset '{}'", offset))?;
+                (name, offset)
+            }
+            None => (text, 0),
+        };
+        let (_, address) = self
+            .labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .ok_or(format!("unknown label '{}'", name))?;
+        u16::try_from(*address as i64 + offset).map_err(|_| format!("invalid address '{}'", text))
+    }
+
+    pub fn to_json(&self) -> Value {
+        let labels: Vec<Value> = self.labels.iter().map(|(n, a)| json!([n, a])).collect();
+        let lines: Vec<Value> = self.lines.iter().map(|(a, l)| json!([a, l])).collect();
+        json!({"source": self.source, "labels": labels, "lines": lines})
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, serde_json::to_string(&self.to_json())?)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let json: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let mut map = SymbolMap::default();
+        map.source = json["source"].as_str().map(|s| s.to_string());
+        let pairs = |key: &str| json[key].as_array().cloned().unwrap_or_default();
+        for pair in pairs("labels") {
+            match (pair[0].as_str(), pair[1].as_u64()) {
+                (Some(name), Some(address)) => map.add_label(name, address as u16),
+                _ => return Err(format!("invalid label {}", pair)),
+            }
+        }
+        for pair in pairs("lines") {
+            match (pair[0].as_u64(), pair[1].as_u64()) {
+                // Lines count from 1
+                (Some(address), Some(line)) if line > 0 => {
+                    map.add_line(address as u16, line as usize)
+                }
+                _ => return Err(format!("invalid line {}", pair)),
+            }
+        }
+        Ok(map)
+    }
+
+    /// Loads a symbol map and the source it points to, looked for next to the map if the
+    /// path does not work from the current directory
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut map = SymbolMap::parse(&fs::read_to_string(path).map_err(|e| e.to_string())?)?;
+        if let Some(source) = &map.source {
+            let beside = Path::new(path).with_file_name(source);
+            let text = fs::read_to_string(source).or_else(|_| fs::read_to_string(beside));
+            if let Ok(text) = text {
+                map.set_text(&text);
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> SymbolMap {
+        let mut map = SymbolMap::default();
+        map.source = Some("games/pong.8o".to_string());
+        map.set_text(": main\n  clear\n\n: main_loop\n  v0 += 1\n  jump main_loop");
+        map.add_label("main", 0x200);
+        map.add_label("start", 0x200);
+        map.add_label("main_loop", 0x202);
+        map.add_line(0x200, 2);
+        map.add_line(0x202, 5);
+        map.add_line(0x204, 6);
+        map
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let map = map();
+        assert_eq!(map.symbolize(0x200).as_deref(), Some("main"));
+        assert_eq!(map.symbolize(0x204).as_deref(), Some("main_loop+0x2"));
+        assert_eq!(map.symbolize(0x100), None);
+        assert_eq!(
+            map.describe(0x202),
+            "0x202 main_loop games/pong.8o:5: v0 += 1"
+        );
+        assert_eq!(SymbolMap::default().describe(0x202), "0x202");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let map = map();
+        assert_eq!(map.resolve("0x300"), Ok(0x300));
+        assert_eq!(map.resolve("start"), Ok(0x200));
+        assert_eq!(map.resolve("main_loop+2"), Ok(0x204));
+        assert_eq!(map.resolve("pong.8o:5"), Ok(0x202));
+        // Line 3 is empty and line 4 only has a label
+        assert_eq!(map.resolve("games/pong.8o:3"), Ok(0x202));
+        assert!(map.resolve("pong.8o:7").is_err());
+        assert!(map.resolve("other.8o:2").is_err());
+        assert!(map.resolve("nowhere").is_err());
+
+        let mut loaded = SymbolMap::parse(&map.to_json().to_string()).unwrap();
+        assert!(loaded.text.is_empty());
+        loaded.text = map.text.clone();
+        assert_eq!(loaded, map);
+        assert!(SymbolMap::parse(r#"{"lines": [[512, 0]]}"#).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+use crate::symbols::SymbolMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::rc::Rc;
+
+/// Log of every executed instruction, with labels and source lines when they are known
+pub struct Trace {
+    out: BufWriter<File>,
+    symbols: Rc<SymbolMap>,
+}
+
+impl Trace {
+    pub fn create(path: &str, symbols: Rc<SymbolMap>) -> io::Result<Self> {
+        Ok(Trace {
+            out: BufWriter::new(File::create(path)?),
+            symbols,
+        })
+    }
+
+    /// Called for every fetched instruction, before it is executed
+    pub fn record(&mut self, cycle: u64, address: u16, opcode: u16) -> io::Result<()> {
+        write!(self.out, "{:>10}  {:03x}  {:04x}", cycle, address, opcode)?;
+        if let Some(location) = self.symbols.location(address) {
+            write!(self.out, "  {}", location)?;
+        }
+        writeln!(self.out)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
@@ -0,0 +1,241 @@
+use crate::cdp1802::{Bus, Cdp1802};
+
+// The monitor ROM is decoded at 0x8000-0xFFFF, RAM below it
+const ROM_START: u16 = 0x8000;
+pub const MONITOR_SIZE: usize = 0x200;
+// The CHIP-8 interpreter sits at the bottom of RAM, programs right after it
+pub const PROGRAM_START: usize = 0x200;
+pub const MIN_MEMORY_SIZE: usize = 0x800;
+pub const MAX_MEMORY_SIZE: usize = 0x8000;
+
+// The 1861 scans 262 lines of 14 machine cycles per frame, 60 frames per second. Each of the
+// 128 display lines takes 8 of those cycles for DMA, leaving 6 to the CPU.
+const LINES: usize = 262;
+const LINE_CYCLES: i32 = 14;
+const LINE_BYTES: usize = 8;
+const DISPLAY_START: usize = 80;
+const DISPLAY_LINES: usize = 128;
+// INT is raised for 2 lines before the display starts, EF1 for 4 lines before it starts and
+// before it ends
+const INTERRUPT_LINES: usize = 2;
+const EF1_LINES: usize = 4;
+
+struct VipBus {
+    ram: Vec<u8>,
+    rom: Vec<u8>,
+    // After a reset the ROM also appears at 0x0000 until the first access above 0x8000
+    rom_at_zero: bool,
+    display_on: bool,
+    ef1: bool,
+    // Keypad key selected by OUT 2, whose state is read on EF3
+    key_latch: u8,
+    keys: [bool; 16],
+}
+
+impl Bus for VipBus {
+    fn read(&mut self, address: u16) -> u8 {
+        if address >= ROM_START {
+            self.rom_at_zero = false;
+        }
+        if address >= ROM_START || self.rom_at_zero {
+            self.rom[address as usize % MONITOR_SIZE]
+        } else {
+            // Unused address lines are not decoded, so RAM repeats up to 0x8000
+            self.ram[address as usize % self.ram.len()]
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address < ROM_START {
+            let size = self.ram.len();
+            self.ram[address as usize % size] = value;
+        }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.key_latch = value & 0xF,
+            _ => {}
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn flag(&mut self, flag: u8) -> bool {
+        match flag {
+            1 => self.ef1,
+            3 => self.keys[self.key_latch as usize],
+            _ => false,
+        }
+    }
+}
+
+/// A COSMAC VIP: 1802 CPU, RAM, monitor ROM, 1861 video chip and hex keypad. CHIP-8 programs
+/// run on the original interpreter, so timing and quirks are those of the real machine.
+pub struct Vip {
+    cpu: Cdp1802,
+    bus: VipBus,
+    // Bytes fetched by DMA for every display line of the last frame
+    lines: Vec<[u8; LINE_BYTES]>,
+    // Machine cycles left over from the previous line, negative when an instruction ran over
+    cycles: i32,
+}
+
+impl Vip {
+    /// A VIP with `memory_size` bytes of RAM holding the interpreter at 0x0000 and the program
+    /// at 0x200, that starts in the monitor like after pressing reset
+    pub fn new(
+        monitor: &[u8],
+        interpreter: &[u8],
+        program: &[u8],
+        memory_size: usize,
+    ) -> Result<Self, String> {
+        if monitor.len() != MONITOR_SIZE {
+            return Err(format!(
+                "The VIP monitor ROM is {} bytes, expected {}",
+                monitor.len(),
+                MONITOR_SIZE
+            ));
+        }
+        if interpreter.len() > PROGRAM_START {
+            return Err(format!(
+                "The interpreter is {} bytes but only {} fit before the program",
+                interpreter.len(),
+                PROGRAM_START
+            ));
+        }
+        if !(MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&memory_size) {
+            return Err(format!(
+                "The VIP has between {} and {} bytes of RAM, not {}",
+                MIN_MEMORY_SIZE, MAX_MEMORY_SIZE, memory_size
+            ));
+        }
+        // The interpreter keeps its stack, variables and display in the top 352 bytes
+        let space = memory_size - PROGRAM_START - 0x160;
+        if program.len() > space {
+            return Err(format!(
+                "ROM is {} bytes but only {} fit in {} bytes of VIP memory",
+                program.len(),
+                space,
+                memory_size
+            ));
+        }
+        let mut ram = vec![0; memory_size];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        ram[PROGRAM_START..PROGRAM_START + program.len()].copy_from_slice(program);
+        Ok(Vip {
+            cpu: Cdp1802::default(),
+            bus: VipBus {
+                ram,
+                rom: monitor.to_vec(),
+                rom_at_zero: true,
+                display_on: false,
+                ef1: false,
+                key_latch: 0,
+                keys: [false; 16],
+            },
+            lines: vec![[0; LINE_BYTES]; DISPLAY_LINES],
+            cycles: 0,
+        })
+    }
+
+    pub fn set_keys(&mut self, keys: [bool; 16]) {
+        self.bus.keys = keys;
+    }
+
+    /// The speaker beeps while Q is set
+    pub fn beeping(&self) -> bool {
+        self.cpu.q
+    }
+
+    /// Runs the machine for one 60Hz frame of the 1861
+    pub fn run_frame(&mut self) {
+        let display_end = DISPLAY_START + DISPLAY_LINES;
+        for line in 0..LINES {
+            let display_on = self.bus.display_on;
+            self.bus.ef1 = display_on
+                && ((DISPLAY_START - EF1_LINES..DISPLAY_START).contains(&line)
+                    || (display_end - EF1_LINES..display_end).contains(&line));
+            let interrupt =
+                display_on && (DISPLAY_START - INTERRUPT_LINES..DISPLAY_START).contains(&line);
+            self.cycles += LINE_CYCLES;
+            if (DISPLAY_START..display_end).contains(&line) {
+                let bytes = &mut self.lines[line - DISPLAY_START];
+                if display_on {
+                    for byte in bytes.iter_mut() {
+                        *byte = self.cpu.dma_out(&mut self.bus);
+                    }
+                    self.cycles -= LINE_BYTES as i32;
+                } else {
+                    *bytes = [0; LINE_BYTES];
+                }
+            }
+            while self.cycles > 0 {
+                if interrupt {
+                    self.cycles -= self.cpu.interrupt() as i32;
+                }
+                self.cycles -= self.cpu.step(&mut self.bus) as i32;
+            }
+        }
+    }
+
+    /// The last frame as 64x32 pixels. CHIP-8 shows every row on four display lines, a pixel
+    /// is set if it was on any of them.
+    pub fn pixels(&self) -> [[bool; 64]; 32] {
+        let mut pixels = [[false; 64]; 32];
+        for (line, bytes) in self.lines.iter().enumerate() {
+            for (x, pixel) in pixels[line / 4].iter_mut().enumerate() {
+                *pixel |= bytes[x / 8] & (0x80 >> (x % 8)) != 0;
+            }
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        // The "monitor" jumps into ROM, which maps RAM back at 0x0000, then to 0x0010
+        let mut monitor = vec![0xC0, 0x80, 0x03, 0xC0, 0x00, 0x10];
+        monitor.resize(MONITOR_SIZE, 0);
+        let mut interpreter = vec![0; 0x10];
+        // R1 = interrupt routine, R2 = stack, R3 = 0x20, SEP 3
+        interpreter.extend([0xF8, 0x32, 0xA1, 0xF8, 0xFF, 0xA2, 0xF8, 0x20, 0xA3, 0xD3]);
+        interpreter.resize(0x20, 0);
+        // Display on, Q on, then loop
+        interpreter.extend([0xE2, 0x69, 0x7B, 0x30, 0x23]);
+        interpreter.resize(0x30, 0);
+        // Interrupt routine: save T and D, point R0 at 0x500 for the DMA, wait for EF1 to go
+        // away and come back at the end of the display, restore and return to 0x0032 for the
+        // next time
+        interpreter.extend([0x72, 0x70, 0x22, 0x78, 0x22, 0x52]);
+        interpreter.extend([0xF8, 0x05, 0xB0, 0xF8, 0x00, 0xA0]);
+        interpreter.extend([0x34, 0x3C, 0x3C, 0x3E, 0x30, 0x30]);
+
+        let mut vip = Vip::new(&monitor, &interpreter, &[0x12, 0x00], 0x1000).unwrap();
+        // Display memory at 0x500: rows of 4 lines by 8 bytes, a pixel at (0,0) and (63,31)
+        vip.bus.ram[0x500] = 0x80;
+        vip.bus.ram[0x500 + 0x3FF] = 0x01;
+        vip.run_frame();
+        assert!(vip.beeping());
+        vip.run_frame();
+        let pixels = vip.pixels();
+        assert!(pixels[0][0]);
+        assert!(!pixels[0][1]);
+        assert!(pixels[31][63]);
+        assert_eq!(pixels.iter().flatten().filter(|p| **p).count(), 2);
+        // The program is loaded after the interpreter
+        assert_eq!(vip.bus.ram[0x200..0x202], [0x12, 0x00]);
+        assert!(Vip::new(&monitor, &interpreter, &[0; 0xD00], 0x1000).is_err());
+        assert!(Vip::new(&monitor[..0x100], &interpreter, &[], 0x1000).is_err());
+    }
+}